@@ -0,0 +1,732 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::{
+    error::{Error, Result},
+    tag::{PropertyMap, Tag},
+    utils::read_block,
+};
+
+// Maps the APE item keys which differ from the property keys.
+const KEY_CONVERSIONS: &[(&str, &str)] = &[
+    ("TRACK", "TRACKNUMBER"),
+    ("YEAR", "DATE"),
+    ("ALBUM ARTIST", "ALBUMARTIST"),
+    ("DISC", "DISCNUMBER"),
+    ("REMIXER", "MIXARTIST"),
+    ("RELEASESTATUS", "MUSICBRAINZ_ALBUMSTATUS"),
+    ("RELEASETYPE", "MUSICBRAINZ_ALBUMTYPE"),
+];
+
+// The header and the footer of an APE tag share this layout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApeFooter {
+    version: u32,
+    tag_size: u32,
+    item_count: u32,
+    header_present: bool,
+    footer_present: bool,
+    is_header: bool,
+}
+
+impl ApeFooter {
+    pub const SIZE: usize = 32;
+
+    // parses a 32 byte header or footer
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::SIZE || &data[0..8] != b"APETAGEX" {
+            return Err(Error::NoTagFound);
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let flags = u32_at(20);
+
+        Ok(Self {
+            version: u32_at(8),
+            tag_size: u32_at(12),
+            item_count: u32_at(16),
+            header_present: flags & 0x8000_0000 != 0,
+            footer_present: flags & 0x4000_0000 == 0,
+            is_header: flags & 0x2000_0000 != 0,
+        })
+    }
+
+    // returns 1000 for APEv1 and 2000 for APEv2
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // returns the size of the items and the footer, without the header
+    pub fn tag_size(&self) -> u32 {
+        self.tag_size
+    }
+
+    pub fn item_count(&self) -> u32 {
+        self.item_count
+    }
+
+    pub fn header_present(&self) -> bool {
+        self.header_present
+    }
+
+    pub fn footer_present(&self) -> bool {
+        self.footer_present
+    }
+
+    pub fn is_header(&self) -> bool {
+        self.is_header
+    }
+
+    // returns the size of the whole tag, including the header
+    pub fn complete_tag_size(&self) -> u32 {
+        let header_size = if self.header_present { Self::SIZE } else { 0 };
+
+        self.tag_size + header_size as u32
+    }
+
+    fn render(&self, is_header: bool) -> Vec<u8> {
+        let mut flags = 0u32;
+        if self.header_present {
+            flags |= 0x8000_0000;
+        }
+        if !self.footer_present {
+            flags |= 0x4000_0000;
+        }
+        if is_header {
+            flags |= 0x2000_0000;
+        }
+
+        let mut data = b"APETAGEX".to_vec();
+        for value in [self.version, self.tag_size, self.item_count, flags] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([0u8; 8]);
+
+        data
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum ApeItemType {
+    // UTF-8 text, possibly several values separated by NUL bytes
+    Text = 0,
+    Binary = 1,
+    // a link to external data, like a URL
+    Locator = 2,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApeItemValue {
+    Text(Vec<String>),
+    Binary(Vec<u8>),
+    Locator(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApeItem {
+    key: String,
+    value: ApeItemValue,
+    read_only: bool,
+}
+
+impl ApeItem {
+    pub fn new(key: &str, value: ApeItemValue) -> Self {
+        Self {
+            key: String::from(key),
+            value,
+            read_only: false,
+        }
+    }
+
+    // creates a text item with the given values
+    pub fn text(key: &str, values: Vec<String>) -> Self {
+        Self::new(key, ApeItemValue::Text(values))
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &ApeItemValue {
+        &self.value
+    }
+
+    pub fn item_type(&self) -> ApeItemType {
+        match self.value {
+            ApeItemValue::Text(_) => ApeItemType::Text,
+            ApeItemValue::Binary(_) => ApeItemType::Binary,
+            ApeItemValue::Locator(_) => ApeItemType::Locator,
+        }
+    }
+
+    // returns the values of a text item, or an empty slice for other types
+    pub fn values(&self) -> &[String] {
+        match &self.value {
+            ApeItemValue::Text(values) => values,
+            _ => &[],
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    // Parses the item at the start of `data` and returns it together with its
+    // size.
+    fn parse(data: &[u8]) -> Result<(Self, usize)> {
+        if data.len() < 11 {
+            return Err(Error::TruncatedTag);
+        }
+
+        let value_size = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        let flags = u32::from_le_bytes(data[4..8].try_into().unwrap());
+
+        let key_end = data[8..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(Error::TruncatedTag)?
+            + 8;
+        let key = String::from_utf8_lossy(&data[8..key_end]).into_owned();
+
+        let value_start = key_end + 1;
+        let value = data
+            .get(value_start..value_start + value_size)
+            .ok_or(Error::TruncatedTag)?;
+
+        let item_type = ApeItemType::try_from((flags >> 1) & 3).unwrap_or(ApeItemType::Binary);
+        let value = match item_type {
+            ApeItemType::Text => ApeItemValue::Text(
+                String::from_utf8_lossy(value)
+                    .split('\0')
+                    .map(String::from)
+                    .collect(),
+            ),
+            ApeItemType::Binary => ApeItemValue::Binary(value.to_vec()),
+            ApeItemType::Locator => {
+                ApeItemValue::Locator(String::from_utf8_lossy(value).into_owned())
+            }
+        };
+
+        let item = Self {
+            key,
+            value,
+            read_only: flags & 1 != 0,
+        };
+
+        Ok((item, value_start + value_size))
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let value = match &self.value {
+            ApeItemValue::Text(values) => values.join("\0").into_bytes(),
+            ApeItemValue::Binary(data) => data.clone(),
+            ApeItemValue::Locator(locator) => locator.clone().into_bytes(),
+        };
+
+        let item_type: u32 = self.item_type().into();
+        let flags = (item_type << 1) | self.read_only as u32;
+
+        let mut data = Vec::with_capacity(9 + self.key.len() + value.len());
+        data.extend((value.len() as u32).to_le_bytes());
+        data.extend(flags.to_le_bytes());
+        data.extend(self.key.as_bytes());
+        data.push(0);
+        data.extend(value);
+
+        data
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ApeTagPrivate {
+    footer: ApeFooter,
+    items: Vec<ApeItem>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    comment: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+    track: Option<u32>,
+}
+
+pub struct ApeTag {
+    d: ApeTagPrivate,
+}
+
+impl ApeTag {
+    // reads the complete tag of `size` bytes at `offset`
+    pub fn new<R: Read + Seek>(file: &mut R, offset: u64, size: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+        let data = read_block(file, size as usize)?;
+
+        Self::parse(&data)
+    }
+
+    // creates an empty APEv2 tag
+    pub fn empty() -> Self {
+        Self::from_parts(
+            ApeFooter {
+                version: 2000,
+                tag_size: ApeFooter::SIZE as u32,
+                item_count: 0,
+                header_present: true,
+                footer_present: true,
+                is_header: false,
+            },
+            vec![],
+        )
+    }
+
+    // Parses a complete tag, which ends with a footer and may start with a
+    // header.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < ApeFooter::SIZE {
+            return Err(Error::NoTagFound);
+        }
+
+        let footer = ApeFooter::parse(&data[data.len() - ApeFooter::SIZE..])?;
+        if footer.complete_tag_size() as usize != data.len() {
+            return Err(Error::TruncatedTag);
+        }
+
+        let items_start = if footer.header_present {
+            ApeFooter::SIZE
+        } else {
+            0
+        };
+        let mut items_data = &data[items_start..data.len() - ApeFooter::SIZE];
+
+        // Reading stops at the first damaged item, keeping the ones before it.
+        let mut items: Vec<ApeItem> = vec![];
+        for _ in 0..footer.item_count {
+            let Ok((item, size)) = ApeItem::parse(items_data) else {
+                break;
+            };
+            items_data = &items_data[size..];
+
+            // Keys are case-insensitive; the last item wins.
+            items.retain(|i| !i.key.eq_ignore_ascii_case(&item.key));
+            items.push(item);
+        }
+
+        Ok(Self::from_parts(footer, items))
+    }
+
+    fn from_parts(footer: ApeFooter, items: Vec<ApeItem>) -> Self {
+        let mut tag = Self {
+            d: ApeTagPrivate {
+                footer,
+                items,
+                title: None,
+                artist: None,
+                album: None,
+                comment: None,
+                genre: None,
+                year: None,
+                track: None,
+            },
+        };
+        tag.update_fields();
+
+        tag
+    }
+
+    // Renders the tag as APEv2 with both header and footer.
+    pub fn render(&mut self) -> Vec<u8> {
+        let items: Vec<u8> = self.d.items.iter().flat_map(|item| item.render()).collect();
+
+        self.d.footer = ApeFooter {
+            version: 2000,
+            tag_size: (items.len() + ApeFooter::SIZE) as u32,
+            item_count: self.d.items.len() as u32,
+            header_present: true,
+            footer_present: true,
+            is_header: false,
+        };
+
+        let mut data = self.d.footer.render(true);
+        data.extend(items);
+        data.extend(self.d.footer.render(false));
+
+        data
+    }
+
+    pub fn footer(&self) -> &ApeFooter {
+        &self.d.footer
+    }
+
+    pub fn items(&self) -> &[ApeItem] {
+        &self.d.items
+    }
+
+    // returns the item with the given key, ignoring case
+    pub fn item(&self, key: &str) -> Option<&ApeItem> {
+        self.d
+            .items
+            .iter()
+            .find(|item| item.key.eq_ignore_ascii_case(key))
+    }
+
+    // Adds `item`, replacing any item with the same key.  Returns false, and
+    // leaves the tag unchanged, if the key of `item` is invalid.
+    pub fn set_item(&mut self, item: ApeItem) -> bool {
+        if !is_valid_key(&item.key) {
+            return false;
+        }
+
+        match self
+            .d
+            .items
+            .iter_mut()
+            .find(|i| i.key.eq_ignore_ascii_case(&item.key))
+        {
+            Some(existing) => *existing = item,
+            None => self.d.items.push(item),
+        }
+
+        self.update_fields();
+
+        true
+    }
+
+    // removes the item with the given key, ignoring case
+    pub fn remove_item(&mut self, key: &str) {
+        self.d
+            .items
+            .retain(|item| !item.key.eq_ignore_ascii_case(key));
+        self.update_fields();
+    }
+
+    fn text(&self, key: &str) -> Option<String> {
+        self.item(key)
+            .and_then(|item| item.values().first())
+            .filter(|value| !value.is_empty())
+            .cloned()
+    }
+
+    fn set_text(&mut self, key: &str, value: Option<String>) {
+        match value.filter(|value| !value.is_empty()) {
+            Some(value) => {
+                self.set_item(ApeItem::text(key, vec![value]));
+            }
+            None => self.remove_item(key),
+        }
+    }
+
+    fn update_fields(&mut self) {
+        let number = |s: Option<String>| {
+            let s = s?;
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            s[..end].parse::<u32>().ok().filter(|&n| n > 0)
+        };
+
+        self.d.title = self.text("Title");
+        self.d.artist = self.text("Artist");
+        self.d.album = self.text("Album");
+        self.d.comment = self.text("Comment");
+        self.d.genre = self.text("Genre");
+        self.d.year = number(self.text("Year"));
+        self.d.track = number(self.text("Track"));
+    }
+}
+
+impl Tag for ApeTag {
+    // Text items are exposed as properties.  Binary and locator items, like
+    // cover art, are not.
+    fn properties(&self) -> PropertyMap {
+        let mut properties = PropertyMap::new();
+
+        for item in &self.d.items {
+            if let ApeItemValue::Text(values) = &item.value {
+                properties.insert(property_key(&item.key), values.clone());
+            }
+        }
+
+        properties
+    }
+
+    fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
+        for key in properties {
+            self.d
+                .items
+                .retain(|item| property_key(&item.key) != key.to_uppercase());
+        }
+
+        self.update_fields();
+    }
+
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        let mut unsupported = PropertyMap::new();
+
+        // Text items which are not in the map are removed, others are kept.
+        self.d.items.retain(|item| {
+            item.item_type() != ApeItemType::Text
+                || properties.contains_key(&property_key(&item.key))
+        });
+
+        for (key, values) in properties {
+            let item_key = item_key(&key);
+
+            if !is_valid_key(&item_key) {
+                unsupported.insert(key, values);
+                continue;
+            }
+
+            if values.is_empty() {
+                self.d
+                    .items
+                    .retain(|item| !item.key.eq_ignore_ascii_case(&item_key));
+            } else if self.item(&item_key).map(ApeItem::values) != Some(&values[..]) {
+                // Keep the spelling of an existing key.
+                let item_key = self.item(&item_key).map_or(item_key, |i| i.key.clone());
+                self.d
+                    .items
+                    .retain(|item| !item.key.eq_ignore_ascii_case(&item_key));
+                self.d.items.push(ApeItem::text(&item_key, values));
+            }
+        }
+
+        self.update_fields();
+
+        unsupported
+    }
+
+    fn title(&self) -> &Option<String> {
+        &self.d.title
+    }
+
+    fn artist(&self) -> &Option<String> {
+        &self.d.artist
+    }
+
+    fn album(&self) -> &Option<String> {
+        &self.d.album
+    }
+
+    fn comment(&self) -> &Option<String> {
+        &self.d.comment
+    }
+
+    fn genre(&self) -> &Option<String> {
+        &self.d.genre
+    }
+
+    fn year(&self) -> &Option<u32> {
+        &self.d.year
+    }
+
+    fn track(&self) -> &Option<u32> {
+        &self.d.track
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.set_text("Title", title);
+    }
+
+    fn set_artist(&mut self, artist: Option<String>) {
+        self.set_text("Artist", artist);
+    }
+
+    fn set_album(&mut self, album: Option<String>) {
+        self.set_text("Album", album);
+    }
+
+    fn set_comment(&mut self, comment: Option<String>) {
+        self.set_text("Comment", comment);
+    }
+
+    fn set_genre(&mut self, genre: Option<String>) {
+        self.set_text("Genre", genre);
+    }
+
+    fn set_year(&mut self, year: Option<u32>) {
+        self.set_text("Year", year.map(|year| year.to_string()));
+    }
+
+    fn set_track(&mut self, track: Option<u32>) {
+        self.set_text("Track", track.map(|track| track.to_string()));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.d.items.is_empty()
+    }
+}
+
+// returns the property key for an item key
+fn property_key(item_key: &str) -> String {
+    let key = item_key.to_uppercase();
+
+    match KEY_CONVERSIONS.iter().find(|(k, _)| *k == key) {
+        Some((_, property)) => String::from(*property),
+        None => key,
+    }
+}
+
+// returns the item key for a property key
+fn item_key(property_key: &str) -> String {
+    let key = property_key.to_uppercase();
+
+    match KEY_CONVERSIONS.iter().find(|(_, p)| *p == key) {
+        Some((item, _)) => String::from(*item),
+        None => key,
+    }
+}
+
+// Keys are 2 to 255 printable ASCII characters, and may not be one of the
+// identifiers of other tag formats.
+fn is_valid_key(key: &str) -> bool {
+    const INVALID_KEYS: &[&str] = &["ID3", "TAG", "OGGS", "MP+"];
+
+    (2..=255).contains(&key.len())
+        && key.bytes().all(|b| (0x20..=0x7e).contains(&b))
+        && !INVALID_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key))
+}
+
+// Looks for an APE tag footer ending at `end` and returns the offset and the
+// complete size of the tag, including the optional header.
+pub(crate) fn find<R: Read + Seek>(file: &mut R, end: u64) -> Result<Option<(u64, u64)>> {
+    if end < ApeFooter::SIZE as u64 {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(end - ApeFooter::SIZE as u64))?;
+    let footer = match ApeFooter::parse(&read_block(file, ApeFooter::SIZE)?) {
+        Ok(footer) => footer,
+        Err(_) => return Ok(None),
+    };
+
+    let complete_size = footer.complete_tag_size() as u64;
+    if complete_size < ApeFooter::SIZE as u64 || complete_size > end {
+        return Ok(None);
+    }
+
+    Ok(Some((end - complete_size, complete_size)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn item_data(key: &str, flags: u32, value: &[u8]) -> Vec<u8> {
+        let mut data = (value.len() as u32).to_le_bytes().to_vec();
+        data.extend(flags.to_le_bytes());
+        data.extend(key.as_bytes());
+        data.push(0);
+        data.extend(value);
+        data
+    }
+
+    // an APEv1 tag, which has no header
+    fn v1_tag(items: &[Vec<u8>]) -> Vec<u8> {
+        let items: Vec<u8> = items.concat();
+        let mut data = items.clone();
+        data.extend(b"APETAGEX");
+        for value in [1000u32, items.len() as u32 + 32, 3, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([0u8; 8]);
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = v1_tag(&[
+            item_data("Title", 0, b"Title"),
+            item_data("Artist", 0, b"One\0Two"),
+            item_data("Cover Art (Front)", 2, b"cover.jpg\0\xff\xd8"),
+        ]);
+        let tag = ApeTag::parse(&data).unwrap();
+
+        assert_eq!(tag.footer().version(), 1000);
+        assert!(!tag.footer().header_present());
+        assert_eq!(tag.items().len(), 3);
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.artist().as_deref(), Some("One"));
+        assert_eq!(tag.item("artist").unwrap().values(), ["One", "Two"]);
+        assert_eq!(
+            tag.item("COVER ART (FRONT)").unwrap().item_type(),
+            ApeItemType::Binary
+        );
+
+        let properties = tag.properties();
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties["ARTIST"], ["One", "Two"]);
+
+        assert!(matches!(
+            ApeTag::parse(&data[1..]),
+            Err(Error::TruncatedTag)
+        ));
+
+        // fewer items than announced, and a damaged one
+        let mut damaged = item_data("Artist", 0, b"Artist");
+        damaged[0] = 0xff;
+        let data = v1_tag(&[item_data("Title", 0, b"Title"), damaged]);
+        let tag = ApeTag::parse(&data).unwrap();
+        assert_eq!(tag.items().len(), 1);
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut tag = ApeTag::empty();
+        tag.set_title(Some(String::from("Title")));
+        tag.set_track(Some(5));
+        assert!(tag.set_item(ApeItem::new(
+            "Website",
+            ApeItemValue::Locator(String::from("http://example.com")),
+        )));
+        assert!(!tag.set_item(ApeItem::text("ID3", vec![])));
+
+        let data = tag.render();
+        assert_eq!(tag.footer().complete_tag_size() as usize, data.len());
+        assert_eq!(&data[..8], b"APETAGEX");
+
+        let tag = ApeTag::parse(&data).unwrap();
+        assert!(tag.footer().header_present());
+        assert_eq!(tag.footer().version(), 2000);
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(*tag.track(), Some(5));
+        assert_eq!(
+            tag.item("website").unwrap().value(),
+            &ApeItemValue::Locator(String::from("http://example.com"))
+        );
+
+        let mut cursor = Cursor::new(data.clone());
+        let end = data.len() as u64;
+        assert_eq!(find(&mut cursor, end).unwrap(), Some((0, end)));
+        assert_eq!(find(&mut cursor, end - 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let data = v1_tag(&[
+            item_data("Title", 0, b"Title"),
+            item_data("Year", 0, b"2001"),
+            item_data("Cover Art (Front)", 2, b"cover.jpg\0"),
+        ]);
+        let mut tag = ApeTag::parse(&data).unwrap();
+
+        let mut properties = PropertyMap::new();
+        properties.insert(String::from("TITLE"), vec![String::from("Title")]);
+        properties.insert(String::from("TRACKNUMBER"), vec![String::from("3")]);
+        properties.insert(String::from("X"), vec![String::from("Too short")]);
+        let unsupported = tag.set_properties(properties);
+
+        assert_eq!(unsupported.len(), 1);
+        assert!(unsupported.contains_key("X"));
+        assert!(tag.year().is_none());
+        assert_eq!(*tag.track(), Some(3));
+        assert_eq!(tag.item("title").unwrap().key(), "Title");
+        assert_eq!(tag.item("track").unwrap().values(), ["3"]);
+        assert!(tag.item("Cover Art (Front)").is_some());
+    }
+}
@@ -0,0 +1,1259 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    tag::{PropertyMap, Tag},
+    utils::{insert_block, read_block, take, Truncate},
+    AudioFile,
+};
+
+// Objects are identified by GUIDs, stored with their first three fields in
+// little endian order.
+type Guid = [u8; 16];
+
+pub(crate) const HEADER_GUID: Guid = [
+    0x30, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+const FILE_PROPERTIES_GUID: Guid = [
+    0xa1, 0xdc, 0xab, 0x8c, 0x47, 0xa9, 0xcf, 0x11, 0x8e, 0xe4, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+const STREAM_PROPERTIES_GUID: Guid = [
+    0x91, 0x07, 0xdc, 0xb7, 0xb7, 0xa9, 0xcf, 0x11, 0x8e, 0xe6, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+const CONTENT_DESCRIPTION_GUID: Guid = [
+    0x33, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+const EXTENDED_CONTENT_DESCRIPTION_GUID: Guid = [
+    0x40, 0xa4, 0xd0, 0xd2, 0x07, 0xe3, 0xd2, 0x11, 0x97, 0xf0, 0x00, 0xa0, 0xc9, 0x5e, 0xa8, 0x50,
+];
+const HEADER_EXTENSION_GUID: Guid = [
+    0xb5, 0x03, 0xbf, 0x5f, 0x2e, 0xa9, 0xcf, 0x11, 0x8e, 0xe3, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+const METADATA_GUID: Guid = [
+    0xea, 0xcb, 0xf8, 0xc5, 0xaf, 0x5b, 0x77, 0x48, 0x84, 0x67, 0xaa, 0x8c, 0x44, 0xfa, 0x4c, 0xca,
+];
+const METADATA_LIBRARY_GUID: Guid = [
+    0x94, 0x1c, 0x23, 0x44, 0x98, 0x94, 0xd1, 0x49, 0xa1, 0x41, 0x1d, 0x13, 0x4e, 0x45, 0x70, 0x54,
+];
+const CONTENT_ENCRYPTION_GUID: Guid = [
+    0xfb, 0xb3, 0x11, 0x22, 0x23, 0xbd, 0xd2, 0x11, 0xb4, 0xb7, 0x00, 0xa0, 0xc9, 0x55, 0xfc, 0x6e,
+];
+const AUDIO_MEDIA_GUID: Guid = [
+    0x40, 0x9e, 0x69, 0xf8, 0x4d, 0x5b, 0xcf, 0x11, 0xa8, 0xfd, 0x00, 0x80, 0x5f, 0x5c, 0x44, 0x2b,
+];
+// the first reserved field of the header extension object
+const RESERVED_1_GUID: Guid = [
+    0x11, 0xd2, 0xd3, 0xab, 0xba, 0xa9, 0xcf, 0x11, 0x8e, 0xe6, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+
+// the size of the GUID and the 64-bit size in front of every object
+const OBJECT_HEADER_SIZE: usize = 24;
+
+// the size of the header object without its children
+const HEADER_SIZE: usize = 30;
+
+// the largest value the Extended Content Description and Metadata objects
+// can store
+const MAX_VALUE_SIZE: usize = 0xffff;
+
+const PICTURE_ATTRIBUTE: &str = "WM/Picture";
+
+// Maps attribute names to property keys.
+const KEY_MAP: &[(&str, &str)] = &[
+    ("WM/AlbumTitle", "ALBUM"),
+    ("WM/AlbumArtist", "ALBUMARTIST"),
+    ("WM/Composer", "COMPOSER"),
+    ("WM/Writer", "LYRICIST"),
+    ("WM/Conductor", "CONDUCTOR"),
+    ("WM/ModifiedBy", "REMIXER"),
+    ("WM/Year", "DATE"),
+    ("WM/OriginalReleaseYear", "ORIGINALDATE"),
+    ("WM/Producer", "PRODUCER"),
+    ("WM/ContentGroupDescription", "GROUPING"),
+    ("WM/SubTitle", "SUBTITLE"),
+    ("WM/SetSubTitle", "DISCSUBTITLE"),
+    ("WM/TrackNumber", "TRACKNUMBER"),
+    ("WM/PartOfSet", "DISCNUMBER"),
+    ("WM/Genre", "GENRE"),
+    ("WM/BeatsPerMinute", "BPM"),
+    ("WM/Mood", "MOOD"),
+    ("WM/ISRC", "ISRC"),
+    ("WM/Lyrics", "LYRICS"),
+    ("WM/Media", "MEDIA"),
+    ("WM/Publisher", "LABEL"),
+    ("WM/CatalogNo", "CATALOGNUMBER"),
+    ("WM/Barcode", "BARCODE"),
+    ("WM/EncodedBy", "ENCODEDBY"),
+    ("WM/AlbumSortOrder", "ALBUMSORT"),
+    ("WM/AlbumArtistSortOrder", "ALBUMARTISTSORT"),
+    ("WM/ArtistSortOrder", "ARTISTSORT"),
+    ("WM/TitleSortOrder", "TITLESORT"),
+    ("WM/Script", "SCRIPT"),
+    ("WM/Language", "LANGUAGE"),
+    ("MusicBrainz/Track Id", "MUSICBRAINZ_TRACKID"),
+    ("MusicBrainz/Artist Id", "MUSICBRAINZ_ARTISTID"),
+    ("MusicBrainz/Album Id", "MUSICBRAINZ_ALBUMID"),
+    ("MusicBrainz/Album Artist Id", "MUSICBRAINZ_ALBUMARTISTID"),
+    ("MusicBrainz/Release Group Id", "MUSICBRAINZ_RELEASEGROUPID"),
+    ("MusicBrainz/Work Id", "MUSICBRAINZ_WORKID"),
+    ("Acoustid/Id", "ACOUSTID_ID"),
+    ("Acoustid/Fingerprint", "ACOUSTID_FINGERPRINT"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsfAttributeValue {
+    Unicode(String),
+    Bytes(Vec<u8>),
+    Bool(bool),
+    DWord(u32),
+    QWord(u64),
+    Word(u16),
+    Guid(Guid),
+}
+
+impl AsfAttributeValue {
+    fn parse(value_type: u16, data: &[u8]) -> Result<Self> {
+        let number = |size: usize| -> Result<u64> {
+            let bytes = data.get(..size).ok_or(Error::TruncatedTag)?;
+            Ok(bytes.iter().rev().fold(0u64, |n, &b| (n << 8) | b as u64))
+        };
+
+        Ok(match value_type {
+            0 => Self::Unicode(read_utf16(data)),
+            1 => Self::Bytes(data.to_vec()),
+            // Booleans are four bytes long in the Extended Content
+            // Description object, and two bytes long elsewhere.
+            2 => Self::Bool(data.iter().any(|&b| b != 0)),
+            3 => Self::DWord(number(4)? as u32),
+            4 => Self::QWord(number(8)?),
+            5 => Self::Word(number(2)? as u16),
+            6 => Self::Guid(data.try_into().map_err(|_| Error::TruncatedTag)?),
+            _ => return Err(Error::InvalidHeader),
+        })
+    }
+
+    fn value_type(&self) -> u16 {
+        match self {
+            Self::Unicode(_) => 0,
+            Self::Bytes(_) => 1,
+            Self::Bool(_) => 2,
+            Self::DWord(_) => 3,
+            Self::QWord(_) => 4,
+            Self::Word(_) => 5,
+            Self::Guid(_) => 6,
+        }
+    }
+
+    fn render(&self, bool_size: usize) -> Vec<u8> {
+        match self {
+            Self::Unicode(text) => render_utf16(text),
+            Self::Bytes(data) => data.clone(),
+            Self::Bool(value) => {
+                let mut data = vec![0; bool_size];
+                data[0] = *value as u8;
+                data
+            }
+            Self::DWord(value) => value.to_le_bytes().to_vec(),
+            Self::QWord(value) => value.to_le_bytes().to_vec(),
+            Self::Word(value) => value.to_le_bytes().to_vec(),
+            Self::Guid(guid) => guid.to_vec(),
+        }
+    }
+
+    // returns the value as text, or None for binary values
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            Self::Unicode(text) => Some(text.clone()),
+            Self::Bool(value) => Some(String::from(if *value { "1" } else { "0" })),
+            Self::DWord(value) => Some(value.to_string()),
+            Self::QWord(value) => Some(value.to_string()),
+            Self::Word(value) => Some(value.to_string()),
+            Self::Bytes(_) | Self::Guid(_) => None,
+        }
+    }
+}
+
+// A value of an attribute.  Values for a single stream are stored in the
+// Metadata object, and values in a language other than the default one in
+// the Metadata Library object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsfAttribute {
+    pub value: AsfAttributeValue,
+    // the index into the Language List object, 0 for the default language
+    pub language: u16,
+    // the stream the value applies to, 0 for the whole file
+    pub stream: u16,
+}
+
+impl AsfAttribute {
+    pub fn new(value: AsfAttributeValue) -> Self {
+        Self {
+            value,
+            language: 0,
+            stream: 0,
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Self::new(AsfAttributeValue::Unicode(String::from(text)))
+    }
+}
+
+// A picture stored in a WM/Picture attribute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsfPicture {
+    // the ID3v2 APIC picture type, e.g. 3 for the front cover
+    pub picture_type: u8,
+    pub mime_type: String,
+    pub description: String,
+    pub data: Vec<u8>,
+}
+
+impl AsfPicture {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let picture_type = take(data, &mut pos, 1)?[0];
+        let size = u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap()) as usize;
+        let mime_type = read_utf16_terminated(data, &mut pos)?;
+        let description = read_utf16_terminated(data, &mut pos)?;
+        let data = take(data, &mut pos, size)?.to_vec();
+
+        Ok(Self {
+            picture_type,
+            mime_type,
+            description,
+            data,
+        })
+    }
+
+    pub fn render(&self) -> Vec<u8> {
+        let mut data = vec![self.picture_type];
+        data.extend((self.data.len() as u32).to_le_bytes());
+        data.extend(render_utf16(&self.mime_type));
+        data.extend(render_utf16(&self.description));
+        data.extend(&self.data);
+        data
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct AsfTagPrivate {
+    // the Content Description object
+    title: Option<String>,
+    artist: Option<String>,
+    copyright: Option<String>,
+    comment: Option<String>,
+    rating: Option<String>,
+    // the attributes of the other objects by name, in the order they were
+    // read or added
+    attributes: Vec<(String, Vec<AsfAttribute>)>,
+    album: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+    track: Option<u32>,
+}
+
+// The tag of an ASF file, spread over the Content Description object and the
+// attributes of the Extended Content Description, Metadata and Metadata
+// Library objects.
+#[derive(Clone, Default)]
+pub struct AsfTag {
+    d: AsfTagPrivate,
+}
+
+impl AsfTag {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    pub fn copyright(&self) -> &Option<String> {
+        &self.d.copyright
+    }
+
+    pub fn set_copyright(&mut self, copyright: Option<String>) {
+        self.d.copyright = copyright.filter(|s| !s.is_empty());
+    }
+
+    pub fn rating(&self) -> &Option<String> {
+        &self.d.rating
+    }
+
+    pub fn set_rating(&mut self, rating: Option<String>) {
+        self.d.rating = rating.filter(|s| !s.is_empty());
+    }
+
+    pub fn attributes(&self) -> &[(String, Vec<AsfAttribute>)] {
+        &self.d.attributes
+    }
+
+    // returns the values of the attribute `name`, which is case sensitive
+    pub fn attribute(&self, name: &str) -> Option<&[AsfAttribute]> {
+        self.d
+            .attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, values)| &values[..])
+    }
+
+    // replaces the values of the attribute `name`, removing it if `values` is
+    // empty
+    pub fn set_attribute(&mut self, name: &str, values: Vec<AsfAttribute>) {
+        if values.is_empty() {
+            self.d.attributes.retain(|(n, _)| n != name);
+        } else {
+            match self.d.attributes.iter_mut().find(|(n, _)| n == name) {
+                Some((_, v)) => *v = values,
+                None => self.d.attributes.push((String::from(name), values)),
+            }
+        }
+
+        self.update_fields();
+    }
+
+    pub fn add_attribute(&mut self, name: &str, value: AsfAttribute) {
+        match self.d.attributes.iter_mut().find(|(n, _)| n == name) {
+            Some((_, values)) => values.push(value),
+            None => self.d.attributes.push((String::from(name), vec![value])),
+        }
+
+        self.update_fields();
+    }
+
+    pub fn remove_attribute(&mut self, name: &str) {
+        self.set_attribute(name, vec![]);
+    }
+
+    // returns the WM/Picture attributes which could be parsed
+    pub fn pictures(&self) -> Vec<AsfPicture> {
+        self.attribute(PICTURE_ATTRIBUTE)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|attribute| match &attribute.value {
+                AsfAttributeValue::Bytes(data) => AsfPicture::parse(data).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn add_picture(&mut self, picture: AsfPicture) {
+        let value = AsfAttributeValue::Bytes(picture.render());
+        self.add_attribute(PICTURE_ATTRIBUTE, AsfAttribute::new(value));
+    }
+
+    pub fn remove_pictures(&mut self) {
+        self.remove_attribute(PICTURE_ATTRIBUTE);
+    }
+
+    // returns the first value of `name` as text
+    fn text(&self, name: &str) -> Option<String> {
+        self.attribute(name)?
+            .first()?
+            .value
+            .to_text()
+            .filter(|s| !s.is_empty())
+    }
+
+    fn set_text(&mut self, name: &str, value: Option<String>) {
+        let values = value
+            .filter(|value| !value.is_empty())
+            .map(|value| AsfAttribute::text(&value))
+            .into_iter()
+            .collect();
+        self.set_attribute(name, values);
+    }
+
+    fn update_fields(&mut self) {
+        let leading_number = |text: String| {
+            let end = text
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(text.len());
+            text[..end].parse::<u32>().ok().filter(|&n| n > 0)
+        };
+
+        self.d.album = self.text("WM/AlbumTitle");
+        self.d.genre = self.text("WM/Genre");
+        self.d.year = self.text("WM/Year").and_then(leading_number);
+        // WM/Track is the older, zero-based track number.
+        self.d.track = self
+            .text("WM/TrackNumber")
+            .and_then(leading_number)
+            .or_else(|| {
+                let track = self.text("WM/Track")?.trim().parse::<u32>().ok()?;
+                Some(track + 1)
+            });
+    }
+
+    // Parses the Content Description object.
+    fn parse_content_description(&mut self, data: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        let mut lengths = [0; 5];
+        for length in lengths.iter_mut() {
+            *length = u16::from_le_bytes(take(data, &mut pos, 2)?.try_into().unwrap()) as usize;
+        }
+
+        let mut fields = [None, None, None, None, None];
+        for (field, length) in fields.iter_mut().zip(lengths) {
+            let text = read_utf16(take(data, &mut pos, length)?);
+            *field = Some(text).filter(|s| !s.is_empty());
+        }
+
+        let [title, artist, copyright, comment, rating] = fields;
+        self.d.title = title;
+        self.d.artist = artist;
+        self.d.copyright = copyright;
+        self.d.comment = comment;
+        self.d.rating = rating;
+
+        Ok(())
+    }
+
+    // Parses the Extended Content Description object.
+    fn parse_extended_content_description(&mut self, data: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        let count = u16::from_le_bytes(take(data, &mut pos, 2)?.try_into().unwrap());
+
+        for _ in 0..count {
+            let u16_at = |pos: &mut usize| -> Result<usize> {
+                Ok(u16::from_le_bytes(take(data, pos, 2)?.try_into().unwrap()) as usize)
+            };
+
+            let name_length = u16_at(&mut pos)?;
+            let name = read_utf16(take(data, &mut pos, name_length)?);
+            let value_type = u16_at(&mut pos)? as u16;
+            let value_length = u16_at(&mut pos)?;
+            let value = AsfAttributeValue::parse(value_type, take(data, &mut pos, value_length)?)?;
+
+            self.push_attribute(name, AsfAttribute::new(value));
+        }
+
+        Ok(())
+    }
+
+    // Parses a Metadata or Metadata Library object.  Both store the stream
+    // and language of every value.
+    fn parse_metadata(&mut self, data: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        let count = u16::from_le_bytes(take(data, &mut pos, 2)?.try_into().unwrap());
+
+        for _ in 0..count {
+            let u16_at = |pos: &mut usize| -> Result<u16> {
+                Ok(u16::from_le_bytes(take(data, pos, 2)?.try_into().unwrap()))
+            };
+
+            let language = u16_at(&mut pos)?;
+            let stream = u16_at(&mut pos)?;
+            let name_length = u16_at(&mut pos)? as usize;
+            let value_type = u16_at(&mut pos)?;
+            let value_length =
+                u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap()) as usize;
+            let name = read_utf16(take(data, &mut pos, name_length)?);
+            let value = AsfAttributeValue::parse(value_type, take(data, &mut pos, value_length)?)?;
+
+            let attribute = AsfAttribute {
+                value,
+                language,
+                stream,
+            };
+            self.push_attribute(name, attribute);
+        }
+
+        Ok(())
+    }
+
+    // adds a value read from any of the objects, without updating the fields
+    fn push_attribute(&mut self, name: String, attribute: AsfAttribute) {
+        match self.d.attributes.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => values.push(attribute),
+            None => self.d.attributes.push((name, vec![attribute])),
+        }
+    }
+
+    // renders the Content Description object, or None if it would be empty
+    fn render_content_description(&self) -> Option<Vec<u8>> {
+        let fields = [
+            &self.d.title,
+            &self.d.artist,
+            &self.d.copyright,
+            &self.d.comment,
+            &self.d.rating,
+        ];
+        if fields.iter().all(|field| field.is_none()) {
+            return None;
+        }
+
+        let fields: Vec<Vec<u8>> = fields
+            .iter()
+            .map(|field| render_utf16(field.as_deref().unwrap_or_default()))
+            .collect();
+
+        let mut data = vec![];
+        for field in &fields {
+            data.extend((field.len() as u16).to_le_bytes());
+        }
+        data.extend(fields.concat());
+
+        Some(render_object(&CONTENT_DESCRIPTION_GUID, &data))
+    }
+
+    // Renders the Extended Content Description, Metadata and Metadata Library
+    // objects, each as None if it would be empty.  Values go to the first
+    // object able to store them.
+    fn render_attributes(&self) -> [Option<Vec<u8>>; 3] {
+        let mut extended = vec![];
+        let mut metadata = vec![];
+        let mut library = vec![];
+
+        for (name, values) in &self.d.attributes {
+            let name_data = render_utf16(name);
+
+            for attribute in values {
+                let value = &attribute.value;
+                let is_small = value.render(2).len() <= MAX_VALUE_SIZE
+                    && name_data.len() <= MAX_VALUE_SIZE
+                    && !matches!(value, AsfAttributeValue::Guid(_));
+
+                if is_small && attribute.language == 0 && attribute.stream == 0 {
+                    let data = value.render(4);
+                    let mut record = (name_data.len() as u16).to_le_bytes().to_vec();
+                    record.extend(&name_data);
+                    record.extend(value.value_type().to_le_bytes());
+                    record.extend((data.len() as u16).to_le_bytes());
+                    record.extend(data);
+                    extended.push(record);
+                } else {
+                    let data = value.render(2);
+                    let mut record = attribute.language.to_le_bytes().to_vec();
+                    record.extend(attribute.stream.to_le_bytes());
+                    record.extend((name_data.len() as u16).to_le_bytes());
+                    record.extend(value.value_type().to_le_bytes());
+                    record.extend((data.len() as u32).to_le_bytes());
+                    record.extend(&name_data);
+                    record.extend(data);
+
+                    if is_small && attribute.language == 0 {
+                        metadata.push(record);
+                    } else {
+                        library.push(record);
+                    }
+                }
+            }
+        }
+
+        let render = |guid: &Guid, records: Vec<Vec<u8>>| {
+            if records.is_empty() {
+                return None;
+            }
+            let mut data = (records.len() as u16).to_le_bytes().to_vec();
+            data.extend(records.concat());
+            Some(render_object(guid, &data))
+        };
+
+        [
+            render(&EXTENDED_CONTENT_DESCRIPTION_GUID, extended),
+            render(&METADATA_GUID, metadata),
+            render(&METADATA_LIBRARY_GUID, library),
+        ]
+    }
+}
+
+impl Tag for AsfTag {
+    // Attributes without a property key, and binary values, are not
+    // properties.
+    fn properties(&self) -> PropertyMap {
+        let mut properties = PropertyMap::new();
+
+        let fields = [
+            ("TITLE", &self.d.title),
+            ("ARTIST", &self.d.artist),
+            ("COPYRIGHT", &self.d.copyright),
+            ("COMMENT", &self.d.comment),
+        ];
+        for (key, field) in fields {
+            if let Some(value) = field {
+                properties.insert(String::from(key), vec![value.clone()]);
+            }
+        }
+
+        for (name, values) in &self.d.attributes {
+            let Some(&(_, key)) = KEY_MAP.iter().find(|(n, _)| n == name) else {
+                continue;
+            };
+
+            let values: Vec<String> = values
+                .iter()
+                .filter_map(|attribute| attribute.value.to_text())
+                .collect();
+            if !values.is_empty() {
+                properties.insert(String::from(key), values);
+            }
+        }
+
+        properties
+    }
+
+    fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
+        for name in properties {
+            self.remove_attribute(&name);
+        }
+    }
+
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        let mut unsupported = PropertyMap::new();
+
+        // Properties which are not in the map are removed.
+        let current = self.properties();
+        for key in current.keys().filter(|key| !properties.contains_key(*key)) {
+            match key.as_str() {
+                "TITLE" => self.d.title = None,
+                "ARTIST" => self.d.artist = None,
+                "COPYRIGHT" => self.d.copyright = None,
+                "COMMENT" => self.d.comment = None,
+                _ => {
+                    if let Some(&(name, _)) = KEY_MAP.iter().find(|(_, k)| k == key) {
+                        self.d.attributes.retain(|(n, _)| n != name);
+                    }
+                }
+            }
+        }
+
+        for (key, values) in properties {
+            let first = values.first().cloned().filter(|s| !s.is_empty());
+            match key.to_uppercase().as_str() {
+                "TITLE" => self.d.title = first,
+                "ARTIST" => self.d.artist = first,
+                "COPYRIGHT" => self.d.copyright = first,
+                "COMMENT" => self.d.comment = first,
+                upper => match KEY_MAP.iter().find(|(_, k)| *k == upper) {
+                    // Unchanged values keep their type, stream and language.
+                    Some(_) if current.get(upper) == Some(&values) => (),
+                    Some(&(name, _)) => {
+                        let values = values.iter().map(|v| AsfAttribute::text(v)).collect();
+                        self.set_attribute(name, values);
+                    }
+                    None => {
+                        unsupported.insert(key, values);
+                    }
+                },
+            }
+        }
+
+        self.update_fields();
+
+        unsupported
+    }
+
+    fn title(&self) -> &Option<String> {
+        &self.d.title
+    }
+
+    fn artist(&self) -> &Option<String> {
+        &self.d.artist
+    }
+
+    fn album(&self) -> &Option<String> {
+        &self.d.album
+    }
+
+    fn comment(&self) -> &Option<String> {
+        &self.d.comment
+    }
+
+    fn genre(&self) -> &Option<String> {
+        &self.d.genre
+    }
+
+    fn year(&self) -> &Option<u32> {
+        &self.d.year
+    }
+
+    fn track(&self) -> &Option<u32> {
+        &self.d.track
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.d.title = title.filter(|s| !s.is_empty());
+    }
+
+    fn set_artist(&mut self, artist: Option<String>) {
+        self.d.artist = artist.filter(|s| !s.is_empty());
+    }
+
+    fn set_album(&mut self, album: Option<String>) {
+        self.set_text("WM/AlbumTitle", album);
+    }
+
+    fn set_comment(&mut self, comment: Option<String>) {
+        self.d.comment = comment.filter(|s| !s.is_empty());
+    }
+
+    fn set_genre(&mut self, genre: Option<String>) {
+        self.set_text("WM/Genre", genre);
+    }
+
+    fn set_year(&mut self, year: Option<u32>) {
+        self.set_text("WM/Year", year.map(|year| year.to_string()));
+    }
+
+    // also removes the older WM/Track attribute
+    fn set_track(&mut self, track: Option<u32>) {
+        self.d.attributes.retain(|(n, _)| n != "WM/Track");
+        self.set_text("WM/TrackNumber", track.map(|track| track.to_string()));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.render_content_description().is_none() && self.d.attributes.is_empty()
+    }
+}
+
+// decodes UTF-16LE text, which ends at the first null character if any
+fn read_utf16(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+
+    String::from_utf16_lossy(&units)
+}
+
+// Reads null terminated UTF-16LE text at `pos` and moves `pos` past the
+// terminator.
+fn read_utf16_terminated(data: &[u8], pos: &mut usize) -> Result<String> {
+    let start = *pos;
+    while take(data, pos, 2)? != [0, 0] {}
+
+    Ok(read_utf16(&data[start..*pos]))
+}
+
+// encodes `text` as UTF-16LE with a null terminator
+fn render_utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+fn render_object(guid: &Guid, data: &[u8]) -> Vec<u8> {
+    let mut object = guid.to_vec();
+    object.extend(((data.len() + OBJECT_HEADER_SIZE) as u64).to_le_bytes());
+    object.extend(data);
+    object
+}
+
+// Splits `data` into objects, returning their GUIDs and contents.
+fn parse_objects(data: &[u8], count: usize) -> Result<Vec<(Guid, Vec<u8>)>> {
+    let mut objects = vec![];
+    let mut pos = 0;
+
+    while objects.len() < count && pos + OBJECT_HEADER_SIZE <= data.len() {
+        let guid: Guid = take(data, &mut pos, 16)?.try_into().unwrap();
+        let size = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap());
+        if size < OBJECT_HEADER_SIZE as u64 {
+            return Err(Error::InvalidHeader);
+        }
+
+        let contents = take(data, &mut pos, size as usize - OBJECT_HEADER_SIZE)?;
+        objects.push((guid, contents.to_vec()));
+    }
+
+    Ok(objects)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsfCodec {
+    Unknown,
+    // Windows Media Audio 1
+    Wma1,
+    // Windows Media Audio 2 to 9 Standard
+    Wma2,
+    Wma9Pro,
+    Wma9Lossless,
+}
+
+#[derive(Clone)]
+pub(crate) struct AsfPropertiesPrivate {
+    length: u32,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    codec: AsfCodec,
+    encrypted: bool,
+}
+
+#[derive(Clone)]
+pub struct AsfProperties {
+    d: AsfPropertiesPrivate,
+}
+
+impl AudioProperties for AsfProperties {
+    fn length(&self) -> u32 {
+        self.length_in_seconds()
+    }
+
+    fn length_in_seconds(&self) -> u32 {
+        self.d.length / 1000
+    }
+
+    fn length_in_milliseconds(&self) -> u32 {
+        self.d.length
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.d.bitrate
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.d.sample_rate
+    }
+
+    fn channels(&self) -> u32 {
+        self.d.channels
+    }
+}
+
+impl AsfProperties {
+    // Reads the File Properties object and the first audio stream's Stream
+    // Properties object.
+    pub(crate) fn new(objects: &[(Guid, Vec<u8>)]) -> Result<Self> {
+        let find = |guid: &Guid| objects.iter().find(|(g, _)| g == guid).map(|(_, d)| d);
+
+        // The play duration counts 100 ns units and includes the preroll,
+        // which is in milliseconds.
+        let file_properties = find(&FILE_PROPERTIES_GUID).ok_or(Error::InvalidHeader)?;
+        if file_properties.len() < 64 {
+            return Err(Error::TruncatedHeader);
+        }
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(file_properties[offset..offset + 8].try_into().unwrap())
+        };
+        let length = (u64_at(40) / 10_000).saturating_sub(u64_at(56)) as u32;
+
+        let mut properties = Self {
+            d: AsfPropertiesPrivate {
+                length,
+                bitrate: 0,
+                sample_rate: 0,
+                channels: 0,
+                bits_per_sample: 0,
+                codec: AsfCodec::Unknown,
+                encrypted: find(&CONTENT_ENCRYPTION_GUID).is_some(),
+            },
+        };
+
+        // The WAVEFORMATEX structure follows the stream type, the error
+        // correction type and the fields describing them.
+        let stream = objects
+            .iter()
+            .filter(|(guid, _)| *guid == STREAM_PROPERTIES_GUID)
+            .map(|(_, data)| data)
+            .find(|data| data.get(..16) == Some(&AUDIO_MEDIA_GUID));
+        if let Some(format) = stream.and_then(|data| data.get(54..70)) {
+            let u16_at = |offset: usize| u16::from_le_bytes([format[offset], format[offset + 1]]);
+            let u32_at =
+                |offset: usize| u32::from_le_bytes(format[offset..offset + 4].try_into().unwrap());
+
+            properties.d.codec = match u16_at(0) {
+                0x160 => AsfCodec::Wma1,
+                0x161 => AsfCodec::Wma2,
+                0x162 => AsfCodec::Wma9Pro,
+                0x163 => AsfCodec::Wma9Lossless,
+                _ => AsfCodec::Unknown,
+            };
+            properties.d.channels = u16_at(2) as u32;
+            properties.d.sample_rate = u32_at(4);
+            properties.d.bitrate = (u32_at(8) * 8).div_ceil(1000);
+            properties.d.bits_per_sample = u16_at(14) as u32;
+        }
+
+        Ok(properties)
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        self.d.bits_per_sample
+    }
+
+    pub fn codec(&self) -> AsfCodec {
+        self.d.codec
+    }
+
+    // returns true if the file has a Content Encryption object
+    pub fn is_encrypted(&self) -> bool {
+        self.d.encrypted
+    }
+}
+
+pub struct AsfFile {
+    pub tag: AsfTag,
+    pub audio_properties: AsfProperties,
+    path: Option<PathBuf>,
+    // the size of the header object, including its children
+    header_size: u64,
+    // the children of the header object
+    objects: Vec<(Guid, Vec<u8>)>,
+}
+
+impl AudioFile for AsfFile {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut asf_file = Self::read_from(&mut file, style)?;
+        asf_file.path = Some(path);
+
+        Ok(asf_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl AsfFile {
+    // Reads the header object from any seekable stream.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        let file_length = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let header = read_block(file, HEADER_SIZE)?;
+        if header.len() < HEADER_SIZE {
+            return Err(Error::TruncatedHeader);
+        }
+        if header[..16] != HEADER_GUID {
+            return Err(Error::UnsupportedFormat);
+        }
+
+        let header_size = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let count = u32::from_le_bytes(header[24..28].try_into().unwrap()) as usize;
+        if header_size < HEADER_SIZE as u64 || header_size > file_length {
+            return Err(Error::InvalidHeader);
+        }
+
+        let data = read_block(file, header_size as usize - HEADER_SIZE)?;
+        let objects = parse_objects(&data, count)?;
+
+        let mut tag = AsfTag::empty();
+        for (guid, data) in &objects {
+            match *guid {
+                CONTENT_DESCRIPTION_GUID => tag.parse_content_description(data)?,
+                EXTENDED_CONTENT_DESCRIPTION_GUID => {
+                    tag.parse_extended_content_description(data)?
+                }
+                HEADER_EXTENSION_GUID if data.len() >= 22 => {
+                    for (guid, data) in parse_objects(&data[22..], usize::MAX)? {
+                        if guid == METADATA_GUID || guid == METADATA_LIBRARY_GUID {
+                            tag.parse_metadata(&data)?;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        tag.update_fields();
+
+        let audio_properties = AsfProperties::new(&objects)?;
+
+        Ok(Self {
+            tag,
+            audio_properties,
+            path: None,
+            header_size,
+            objects,
+        })
+    }
+
+    // Rewrites the header object.  The data object after it is moved if the
+    // size of the header changes.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        let content_description = self.tag.render_content_description();
+        let [extended, metadata, library] = self.tag.render_attributes();
+
+        // Replace the tag objects, keeping the others in place, and add the
+        // missing ones at the end.
+        let mut objects: Vec<(Guid, Vec<u8>)> = vec![];
+        let mut pending = [
+            (CONTENT_DESCRIPTION_GUID, content_description),
+            (EXTENDED_CONTENT_DESCRIPTION_GUID, extended),
+        ];
+        let mut extension_objects = [(METADATA_GUID, metadata), (METADATA_LIBRARY_GUID, library)];
+
+        for (guid, data) in &self.objects {
+            if let Some((_, object)) = pending.iter_mut().find(|(g, _)| g == guid) {
+                if let Some(object) = object.take() {
+                    objects.push((*guid, object[OBJECT_HEADER_SIZE..].to_vec()));
+                }
+            } else if *guid == HEADER_EXTENSION_GUID && data.len() >= 22 {
+                let mut children = vec![];
+                for (guid, data) in parse_objects(&data[22..], usize::MAX)? {
+                    match extension_objects.iter_mut().find(|(g, _)| *g == guid) {
+                        Some((_, object)) => children.extend(object.take().unwrap_or_default()),
+                        None => children.extend(render_object(&guid, &data)),
+                    }
+                }
+                for (_, object) in &mut extension_objects {
+                    children.extend(object.take().unwrap_or_default());
+                }
+
+                objects.push((*guid, render_header_extension(&data[..18], &children)));
+            } else {
+                objects.push((*guid, data.clone()));
+            }
+        }
+
+        for (guid, object) in pending {
+            if let Some(object) = object {
+                objects.push((guid, object[OBJECT_HEADER_SIZE..].to_vec()));
+            }
+        }
+        let children: Vec<u8> = extension_objects
+            .into_iter()
+            .flat_map(|(_, object)| object.unwrap_or_default())
+            .collect();
+        if !children.is_empty() {
+            let mut reserved = RESERVED_1_GUID.to_vec();
+            reserved.extend(6u16.to_le_bytes());
+            objects.push((
+                HEADER_EXTENSION_GUID,
+                render_header_extension(&reserved, &children),
+            ));
+        }
+
+        let mut data = HEADER_GUID.to_vec();
+        let header_size: usize = HEADER_SIZE
+            + objects
+                .iter()
+                .map(|(_, data)| data.len() + OBJECT_HEADER_SIZE)
+                .sum::<usize>();
+        data.extend((header_size as u64).to_le_bytes());
+        data.extend((objects.len() as u32).to_le_bytes());
+        data.extend([0x01, 0x02]);
+
+        // The File Properties object records the size of the whole file.
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let new_length = file_length - self.header_size + header_size as u64;
+        for (guid, object) in &mut objects {
+            if *guid == FILE_PROPERTIES_GUID && object.len() >= 24 {
+                object[16..24].copy_from_slice(&new_length.to_le_bytes());
+            }
+            data.extend(render_object(guid, object));
+        }
+
+        insert_block(file, &data, 0, self.header_size)?;
+
+        self.header_size = header_size as u64;
+        self.objects = objects;
+
+        Ok(())
+    }
+}
+
+// returns the contents of a header extension object with the given reserved
+// fields and children
+fn render_header_extension(reserved: &[u8], children: &[u8]) -> Vec<u8> {
+    let mut data = reserved.to_vec();
+    data.extend((children.len() as u32).to_le_bytes());
+    data.extend(children);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn metadata_record(language: u16, name: &str, value: &AsfAttributeValue) -> Vec<u8> {
+        let name = render_utf16(name);
+        let data = value.render(2);
+
+        let mut record = language.to_le_bytes().to_vec();
+        record.extend(0u16.to_le_bytes());
+        record.extend((name.len() as u16).to_le_bytes());
+        record.extend(value.value_type().to_le_bytes());
+        record.extend((data.len() as u32).to_le_bytes());
+        record.extend(name);
+        record.extend(data);
+        record
+    }
+
+    // a WMA 2 stereo stream at 44100 Hz and 128 kb/s, lasting ten seconds
+    // after a preroll of 3 seconds, with a tag in each of the tag objects
+    fn asf_file() -> Vec<u8> {
+        let mut file_properties = vec![0; 40];
+        file_properties.extend(130_000_000u64.to_le_bytes());
+        file_properties.extend(0u64.to_le_bytes());
+        file_properties.extend(3000u64.to_le_bytes());
+        file_properties.extend([0; 16]);
+
+        let mut stream_properties = AUDIO_MEDIA_GUID.to_vec();
+        stream_properties.extend([0; 38]);
+        stream_properties.extend([0x61, 0x01, 2, 0]);
+        stream_properties.extend(44100u32.to_le_bytes());
+        stream_properties.extend(16000u32.to_le_bytes());
+        stream_properties.extend([0, 0, 16, 0]);
+
+        let fields = ["Title", "Artist", "", "Comment", ""].map(render_utf16);
+        let mut content_description = vec![];
+        for field in &fields {
+            content_description.extend((field.len() as u16).to_le_bytes());
+        }
+        content_description.extend(fields.concat());
+
+        let mut extended = 2u16.to_le_bytes().to_vec();
+        for (name, value_type, value) in [
+            ("WM/AlbumTitle", 0u16, render_utf16("Album")),
+            ("WM/TrackNumber", 3u16, 7u32.to_le_bytes().to_vec()),
+        ] {
+            let name = render_utf16(name);
+            extended.extend((name.len() as u16).to_le_bytes());
+            extended.extend(name);
+            extended.extend(value_type.to_le_bytes());
+            extended.extend((value.len() as u16).to_le_bytes());
+            extended.extend(value);
+        }
+
+        let picture = AsfPicture {
+            picture_type: 3,
+            mime_type: String::from("image/png"),
+            description: String::from("Cover"),
+            data: b"\x89PNG".to_vec(),
+        };
+        let mut library = 2u16.to_le_bytes().to_vec();
+        library.extend(metadata_record(
+            0,
+            PICTURE_ATTRIBUTE,
+            &AsfAttributeValue::Bytes(picture.render()),
+        ));
+        library.extend(metadata_record(
+            1,
+            "WM/Genre",
+            &AsfAttributeValue::Unicode(String::from("Jazz")),
+        ));
+        let mut extension = RESERVED_1_GUID.to_vec();
+        extension.extend(6u16.to_le_bytes());
+        let children = render_object(&METADATA_LIBRARY_GUID, &library);
+        extension.extend((children.len() as u32).to_le_bytes());
+        extension.extend(children);
+
+        let objects = [
+            render_object(&FILE_PROPERTIES_GUID, &file_properties),
+            render_object(&STREAM_PROPERTIES_GUID, &stream_properties),
+            render_object(&HEADER_EXTENSION_GUID, &extension),
+            render_object(&CONTENT_DESCRIPTION_GUID, &content_description),
+            render_object(&EXTENDED_CONTENT_DESCRIPTION_GUID, &extended),
+        ]
+        .concat();
+
+        let mut data = HEADER_GUID.to_vec();
+        data.extend(((objects.len() + HEADER_SIZE) as u64).to_le_bytes());
+        data.extend(5u32.to_le_bytes());
+        data.extend([0x01, 0x02]);
+        data.extend(objects);
+        data.extend(b"data object");
+        data
+    }
+
+    #[test]
+    fn test_read() {
+        let mut cursor = Cursor::new(asf_file());
+        let file = AsfFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        let properties = &file.audio_properties;
+        assert_eq!(properties.length_in_milliseconds(), 10_000);
+        assert_eq!(properties.bitrate(), 128);
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.bits_per_sample(), 16);
+        assert_eq!(properties.codec(), AsfCodec::Wma2);
+        assert!(!properties.is_encrypted());
+
+        let tag = &file.tag;
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(tag.comment().as_deref(), Some("Comment"));
+        assert_eq!(*tag.copyright(), None);
+        assert_eq!(tag.album().as_deref(), Some("Album"));
+        assert_eq!(*tag.track(), Some(7));
+        assert_eq!(tag.genre().as_deref(), Some("Jazz"));
+        assert_eq!(tag.attribute("WM/Genre").unwrap()[0].language, 1);
+
+        let pictures = tag.pictures();
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].mime_type, "image/png");
+        assert_eq!(pictures[0].description, "Cover");
+
+        let properties = tag.properties();
+        assert_eq!(properties["TRACKNUMBER"], ["7"]);
+        assert_eq!(properties["GENRE"], ["Jazz"]);
+        assert!(!properties.contains_key("COPYRIGHT"));
+    }
+
+    #[test]
+    fn test_invalid_header_size() {
+        let mut data = asf_file();
+        data[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            AsfFile::read_from(&mut Cursor::new(data), ReadStyle::Average),
+            Err(Error::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn test_save() {
+        let mut cursor = Cursor::new(asf_file());
+        let mut file = AsfFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        file.tag.set_title(Some(String::from("A longer title")));
+        file.tag.set_copyright(Some(String::from("2024 Label")));
+        file.tag.set_track(Some(8));
+        let mut properties = file.tag.properties();
+        properties.remove("ALBUM");
+        properties.insert(String::from("MOOD"), vec![String::from("Calm")]);
+        properties.insert(String::from("UNKNOWN"), vec![String::from("?")]);
+        let unsupported = file.tag.set_properties(properties);
+        assert_eq!(unsupported.keys().collect::<Vec<_>>(), ["UNKNOWN"]);
+
+        let mut large = AsfAttribute::new(AsfAttributeValue::Bytes(vec![1; 70_000]));
+        large.stream = 1;
+        file.tag.add_attribute("Large", large.clone());
+        file.save_to(&mut cursor).unwrap();
+        assert!(cursor.get_ref().ends_with(b"data object"));
+
+        let file = AsfFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let tag = &file.tag;
+        assert_eq!(tag.title().as_deref(), Some("A longer title"));
+        assert_eq!(tag.copyright().as_deref(), Some("2024 Label"));
+        assert_eq!(*tag.album(), None);
+        assert_eq!(*tag.track(), Some(8));
+        assert_eq!(tag.properties()["MOOD"], ["Calm"]);
+        assert_eq!(tag.attribute("WM/Genre").unwrap()[0].language, 1);
+        assert_eq!(tag.attribute("Large").unwrap(), [large]);
+        assert_eq!(tag.pictures().len(), 1);
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 10_000);
+
+        // the file size in the File Properties object
+        let (_, file_properties) = file
+            .objects
+            .iter()
+            .find(|(guid, _)| *guid == FILE_PROPERTIES_GUID)
+            .unwrap();
+        let size = u64::from_le_bytes(file_properties[16..24].try_into().unwrap());
+        assert_eq!(size, cursor.get_ref().len() as u64);
+    }
+}
@@ -20,12 +20,12 @@ pub trait AudioProperties {
     fn channels(&self) -> u32;
 }
 
-#[derive(Clone, Copy)]
-pub(crate) enum ReadStyle {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadStyle {
     // Read as little of the file as possible
     Fast,
     // Read more of the file and make better values guesses
     Average,
     // Read as much of the file as needed to report accurate values
     Accurate,
-}
\ No newline at end of file
+}
@@ -0,0 +1,69 @@
+use std::{fmt, io};
+
+use crate::file_ref::FileType;
+
+#[derive(Debug)]
+pub enum Error {
+    // the data at the given offset does not start with the MPEG frame sync bits
+    InvalidFrameSync,
+    // no valid MPEG frame was found in the file
+    NoFrameFound,
+    // a header is shorter than its format requires
+    TruncatedHeader,
+    // a tag is shorter than the size announced by its header
+    TruncatedTag,
+    // a header contains values which are not allowed by its format
+    InvalidHeader,
+    // the checksum of a page or frame does not match its contents
+    ChecksumMismatch,
+    // there is no tag at the given location
+    NoTagFound,
+    // the tag uses a version which cannot be read
+    UnsupportedVersion,
+    // the file type could not be detected, or the file is not of the expected type
+    UnsupportedFormat,
+    // the file type was detected, but files of this type cannot be read yet
+    UnsupportedFileType(FileType),
+    // the file was not opened from a path, so it can only be saved to a stream
+    NoPath,
+    // reading from or writing to the underlying file failed
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidFrameSync => write!(f, "MPEG header did not match MPEG synch"),
+            Error::NoFrameFound => write!(f, "could not find a valid MPEG frame"),
+            Error::TruncatedHeader => write!(f, "header is truncated"),
+            Error::TruncatedTag => write!(f, "tag is truncated"),
+            Error::InvalidHeader => write!(f, "header contains invalid values"),
+            Error::ChecksumMismatch => write!(f, "checksum does not match the data"),
+            Error::NoTagFound => write!(f, "no tag found at the specified offset"),
+            Error::UnsupportedVersion => write!(f, "unsupported tag version"),
+            Error::UnsupportedFormat => write!(f, "unsupported file format"),
+            Error::UnsupportedFileType(file_type) => {
+                write!(f, "{:?} files cannot be read yet", file_type)
+            }
+            Error::NoPath => write!(f, "file has no path to save to"),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{
+    asf::{self, AsfFile},
+    audio_properties::ReadStyle,
+    error::{Error, Result},
+    flac::FlacFile,
+    id3v2::ID3v2Header,
+    mp4::Mp4File,
+    mpeg::{MpegFile, MpegHeader},
+    ogg_flac::OggFlacFile,
+    opus::OpusFile,
+    speex::SpeexFile,
+    utils::read_block,
+    vorbis::VorbisFile,
+    AudioFile,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Mpeg,
+    Flac,
+    OggVorbis,
+    OggOpus,
+    OggSpeex,
+    OggFlac,
+    Mp4,
+    Wav,
+    Aiff,
+    Ape,
+    WavPack,
+    Asf,
+}
+
+impl FileType {
+    // returns the type of the stream, judging by its first bytes
+    //
+    // An ID3v2 tag at the start of the stream is skipped, since it may be
+    // prepended to FLAC, APE and other files as well as to MPEG ones.
+    pub fn detect<R: Read + Seek>(file: &mut R) -> Result<Option<FileType>> {
+        file.seek(SeekFrom::Start(0))?;
+        let header = read_block(file, ID3v2Header::SIZE)?;
+        let offset = match ID3v2Header::parse(&header) {
+            Ok(id3v2_header) => id3v2_header.complete_tag_size() as u64,
+            Err(_) => 0,
+        };
+
+        file.seek(SeekFrom::Start(offset))?;
+        let data = read_block(file, 36)?;
+
+        if let Some(file_type) = Self::detect_magic(&data) {
+            return Ok(Some(file_type));
+        }
+
+        // MPEG streams have no magic, so require a valid frame header whose
+        // successor can be found as well.
+        match MpegHeader::new(file, offset, true) {
+            Ok(header) if header.is_valid() => Ok(Some(FileType::Mpeg)),
+            _ => Ok(None),
+        }
+    }
+
+    // returns the type matching the extension of `path`, if any
+    pub fn from_extension<P: AsRef<Path>>(path: P) -> Option<FileType> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "mp3" | "mp2" | "mpga" => Some(FileType::Mpeg),
+            "flac" => Some(FileType::Flac),
+            "ogg" | "oga" => Some(FileType::OggVorbis),
+            "opus" => Some(FileType::OggOpus),
+            "spx" => Some(FileType::OggSpeex),
+            "m4a" | "m4b" | "m4p" | "m4r" | "m4v" | "mp4" | "3g2" => Some(FileType::Mp4),
+            "wav" => Some(FileType::Wav),
+            "aif" | "aiff" | "aifc" => Some(FileType::Aiff),
+            "ape" => Some(FileType::Ape),
+            "wv" => Some(FileType::WavPack),
+            "wma" | "asf" => Some(FileType::Asf),
+            _ => None,
+        }
+    }
+
+    fn detect_magic(data: &[u8]) -> Option<FileType> {
+        let at =
+            |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+
+        if at(0, b"fLaC") {
+            Some(FileType::Flac)
+        } else if at(0, b"OggS") {
+            // The codec is named by the first packet, which starts right
+            // after the 27 byte page header and a single byte segment table.
+            if at(28, b"\x01vorbis") {
+                Some(FileType::OggVorbis)
+            } else if at(28, b"OpusHead") {
+                Some(FileType::OggOpus)
+            } else if at(28, b"Speex   ") {
+                Some(FileType::OggSpeex)
+            } else if at(28, b"\x7fFLAC") || at(28, b"fLaC") {
+                Some(FileType::OggFlac)
+            } else {
+                None
+            }
+        } else if at(4, b"ftyp") {
+            Some(FileType::Mp4)
+        } else if at(0, b"RIFF") && at(8, b"WAVE") {
+            Some(FileType::Wav)
+        } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+            Some(FileType::Aiff)
+        } else if at(0, b"MAC ") {
+            Some(FileType::Ape)
+        } else if at(0, b"wvpk") {
+            Some(FileType::WavPack)
+        } else if at(0, &asf::HEADER_GUID) {
+            Some(FileType::Asf)
+        } else {
+            None
+        }
+    }
+}
+
+// Opens audio files without knowing their format in advance.
+pub struct FileRef;
+
+impl FileRef {
+    // opens the file at `path` with the reader matching its contents, falling
+    // back to its extension when the contents are not recognised
+    //
+    // Detected types without a reader yet return `UnsupportedFileType`.
+    pub fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Box<dyn AudioFile>> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+
+        let file_type = FileType::detect(&mut file)?
+            .or_else(|| FileType::from_extension(path))
+            .ok_or(Error::UnsupportedFormat)?;
+
+        match file_type {
+            FileType::Mpeg => Ok(Box::new(MpegFile::open(path, style)?)),
+            FileType::Flac => Ok(Box::new(FlacFile::open(path, style)?)),
+            FileType::OggVorbis => Ok(Box::new(VorbisFile::open(path, style)?)),
+            FileType::OggOpus => Ok(Box::new(OpusFile::open(path, style)?)),
+            FileType::OggSpeex => Ok(Box::new(SpeexFile::open(path, style)?)),
+            FileType::OggFlac => Ok(Box::new(OggFlacFile::open(path, style)?)),
+            FileType::Mp4 => Ok(Box::new(Mp4File::open(path, style)?)),
+            FileType::Asf => Ok(Box::new(AsfFile::open(path, style)?)),
+            FileType::Wav | FileType::Aiff | FileType::Ape | FileType::WavPack => {
+                Err(Error::UnsupportedFileType(file_type))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_detect_magic() {
+        let detect = |data: &[u8]| {
+            let mut data = data.to_vec();
+            data.resize(64, 0);
+            FileType::detect(&mut Cursor::new(data)).unwrap()
+        };
+
+        assert_eq!(detect(b"fLaC\x00\x00\x00\x22"), Some(FileType::Flac));
+        assert_eq!(detect(b"\x00\x00\x00\x20ftypM4A "), Some(FileType::Mp4));
+        assert_eq!(detect(b"RIFF\x24\x00\x00\x00WAVE"), Some(FileType::Wav));
+        assert_eq!(detect(b"FORM\x24\x00\x00\x00AIFC"), Some(FileType::Aiff));
+        assert_eq!(detect(b"MAC \x96\x0f"), Some(FileType::Ape));
+        assert_eq!(detect(b"wvpk"), Some(FileType::WavPack));
+        assert_eq!(detect(&asf::HEADER_GUID), Some(FileType::Asf));
+        assert_eq!(detect(b"RIFF\x24\x00\x00\x00AVI "), None);
+        assert_eq!(detect(b"garbage"), None);
+
+        let mut ogg = b"OggS".to_vec();
+        ogg.resize(28, 0);
+        ogg.extend(b"OpusHead");
+        assert_eq!(detect(&ogg), Some(FileType::OggOpus));
+
+        // An ID3v2 tag in front of the magic is skipped.
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00".to_vec();
+        tagged.extend(b"fLaC");
+        assert_eq!(detect(&tagged), Some(FileType::Flac));
+    }
+
+    #[test]
+    fn test_detect_mpeg() {
+        let mut data = Vec::new();
+        for _ in 0..3 {
+            let mut frame = vec![0; 417];
+            frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x44]);
+            data.extend(frame);
+        }
+
+        let file_type = FileType::detect(&mut Cursor::new(data)).unwrap();
+        assert_eq!(file_type, Some(FileType::Mpeg));
+    }
+
+    #[test]
+    fn test_from_extension() {
+        assert_eq!(FileType::from_extension("a/b.MP3"), Some(FileType::Mpeg));
+        assert_eq!(FileType::from_extension("b.m4a"), Some(FileType::Mp4));
+        assert_eq!(FileType::from_extension("b.opus"), Some(FileType::OggOpus));
+        assert_eq!(FileType::from_extension("b.txt"), None);
+        assert_eq!(FileType::from_extension("b"), None);
+    }
+
+    #[test]
+    fn test_open() {
+        let path =
+            std::env::temp_dir().join(format!("rustaglib-{}-file-ref.dat", std::process::id()));
+        let mut data = Vec::new();
+        for _ in 0..10 {
+            let mut frame = vec![0; 417];
+            frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x44]);
+            data.extend(frame);
+        }
+        std::fs::write(&path, &data).unwrap();
+
+        let file = FileRef::open(&path, ReadStyle::Average).unwrap();
+        assert_eq!(file.audio_properties().bitrate(), 128);
+
+        std::fs::write(&path, b"RIFF\x24\x00\x00\x00WAVE").unwrap();
+        let result = FileRef::open(&path, ReadStyle::Average);
+        assert!(matches!(
+            result,
+            Err(Error::UnsupportedFileType(FileType::Wav))
+        ));
+
+        std::fs::write(&path, b"not audio").unwrap();
+        let result = FileRef::open(&path, ReadStyle::Average);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::UnsupportedFormat)));
+    }
+}
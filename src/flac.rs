@@ -0,0 +1,618 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    id3v2::ID3v2Header,
+    tag::Tag,
+    utils::{insert_block, read_block, take, Truncate},
+    xiph_comment::XiphComment,
+    AudioFile,
+};
+
+// the padding written when the metadata outgrows the space it had
+const DEFAULT_PADDING_SIZE: usize = 4096;
+
+// the largest length of a metadata block, which is stored in 24 bits
+const MAX_BLOCK_LENGTH: usize = 0xff_ffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum BlockType {
+    StreamInfo = 0,
+    Padding = 1,
+    Application = 2,
+    SeekTable = 3,
+    VorbisComment = 4,
+    CueSheet = 5,
+    Picture = 6,
+}
+
+// A metadata block which is kept verbatim, like SEEKTABLE or APPLICATION.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlacMetadataBlock {
+    code: u8,
+    data: Vec<u8>,
+}
+
+impl FlacMetadataBlock {
+    pub fn new(code: u8, data: Vec<u8>) -> Self {
+        Self { code, data }
+    }
+
+    // returns the type of the block, or None for reserved codes
+    pub fn block_type(&self) -> Option<BlockType> {
+        BlockType::try_from(self.code).ok()
+    }
+
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlacPicture {
+    // the ID3v2 APIC picture type, e.g. 3 for the front cover
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    pub color_depth: u32,
+    // the number of colors of indexed pictures, 0 otherwise
+    pub num_colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl FlacPicture {
+    // parses the contents of a PICTURE block
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let u32_field = |pos: &mut usize| -> Result<u32> {
+            let value = take(data, pos, 4)?;
+            Ok(u32::from_be_bytes(value.try_into().unwrap()))
+        };
+
+        let picture_type = u32_field(&mut pos)?;
+        let mime_type_length = u32_field(&mut pos)? as usize;
+        let mime_type =
+            String::from_utf8_lossy(take(data, &mut pos, mime_type_length)?).into_owned();
+        let description_length = u32_field(&mut pos)? as usize;
+        let description =
+            String::from_utf8_lossy(take(data, &mut pos, description_length)?).into_owned();
+        let width = u32_field(&mut pos)?;
+        let height = u32_field(&mut pos)?;
+        let color_depth = u32_field(&mut pos)?;
+        let num_colors = u32_field(&mut pos)?;
+        let data_length = u32_field(&mut pos)? as usize;
+        let picture_data = take(data, &mut pos, data_length)?.to_vec();
+
+        Ok(Self {
+            picture_type,
+            mime_type,
+            description,
+            width,
+            height,
+            color_depth,
+            num_colors,
+            data: picture_data,
+        })
+    }
+
+    // renders the contents of a PICTURE block
+    pub fn render(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(32 + self.data.len());
+        data.extend(self.picture_type.to_be_bytes());
+        data.extend((self.mime_type.len() as u32).to_be_bytes());
+        data.extend(self.mime_type.as_bytes());
+        data.extend((self.description.len() as u32).to_be_bytes());
+        data.extend(self.description.as_bytes());
+        for value in [
+            self.width,
+            self.height,
+            self.color_depth,
+            self.num_colors,
+            self.data.len() as u32,
+        ] {
+            data.extend(value.to_be_bytes());
+        }
+        data.extend(&self.data);
+
+        data
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct FlacPropertiesPrivate {
+    length: u32,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    sample_frames: u64,
+    signature: [u8; 16],
+}
+
+#[derive(Clone)]
+pub struct FlacProperties {
+    d: FlacPropertiesPrivate,
+}
+
+impl AudioProperties for FlacProperties {
+    fn length(&self) -> u32 {
+        self.length_in_seconds()
+    }
+
+    fn length_in_seconds(&self) -> u32 {
+        self.d.length / 1000
+    }
+
+    fn length_in_milliseconds(&self) -> u32 {
+        self.d.length
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.d.bitrate
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.d.sample_rate
+    }
+
+    fn channels(&self) -> u32 {
+        self.d.channels
+    }
+}
+
+impl FlacProperties {
+    // Reads the STREAMINFO block.  `stream_length` is the size of the audio
+    // frames, which the average bitrate is computed from.
+    pub(crate) fn new(data: &[u8], stream_length: u64) -> Result<Self> {
+        if data.len() < 34 {
+            return Err(Error::TruncatedHeader);
+        }
+
+        // Bytes 10 to 17 hold 20 bits of sample rate, 3 bits of channels, 5
+        // bits of bits per sample and 36 bits of total samples.
+        let flags = u64::from_be_bytes(data[10..18].try_into().unwrap());
+        let sample_rate = (flags >> 44) as u32;
+        let channels = ((flags >> 41) & 0x7) as u32 + 1;
+        let bits_per_sample = ((flags >> 36) & 0x1f) as u32 + 1;
+        let sample_frames = flags & 0xf_ffff_ffff;
+
+        if sample_rate == 0 {
+            return Err(Error::InvalidHeader);
+        }
+
+        let length = (sample_frames * 1000 / sample_rate as u64) as u32;
+        let bitrate = if length > 0 {
+            (stream_length * 8 / length as u64) as u32
+        } else {
+            0
+        };
+
+        Ok(Self {
+            d: FlacPropertiesPrivate {
+                length,
+                bitrate,
+                sample_rate,
+                channels,
+                bits_per_sample,
+                sample_frames,
+                signature: data[18..34].try_into().unwrap(),
+            },
+        })
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        self.d.bits_per_sample
+    }
+
+    // returns the total number of samples per channel, 0 if unknown
+    pub fn sample_frames(&self) -> u64 {
+        self.d.sample_frames
+    }
+
+    // returns the MD5 sum of the unencoded audio data, all zeros if unknown
+    pub fn signature(&self) -> &[u8; 16] {
+        &self.d.signature
+    }
+}
+
+pub struct FlacFile {
+    pub tag: XiphComment,
+    pub audio_properties: FlacProperties,
+    path: Option<PathBuf>,
+    // the offset of the first metadata block, right after "fLaC"
+    metadata_start: u64,
+    // the size of all metadata blocks, including padding
+    metadata_length: u64,
+    stream_info: Vec<u8>,
+    blocks: Vec<FlacMetadataBlock>,
+    pictures: Vec<FlacPicture>,
+}
+
+impl AudioFile for FlacFile {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut flac_file = Self::read_from(&mut file, style)?;
+        flac_file.path = Some(path);
+
+        Ok(flac_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl FlacFile {
+    // Reads the metadata blocks from any seekable stream.  Files read this way
+    // have no path, so they are saved with `save_to`.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        let file_length = file.seek(SeekFrom::End(0))?;
+
+        // Some taggers put an ID3v2 tag in front of the stream.
+        file.seek(SeekFrom::Start(0))?;
+        let header = read_block(file, ID3v2Header::SIZE)?;
+        let stream_start = match ID3v2Header::parse(&header) {
+            Ok(id3v2_header) => id3v2_header.complete_tag_size() as u64,
+            Err(_) => 0,
+        };
+
+        file.seek(SeekFrom::Start(stream_start))?;
+        if read_block(file, 4)? != b"fLaC" {
+            return Err(Error::UnsupportedFormat);
+        }
+
+        let metadata_start = stream_start + 4;
+        let mut stream_info = None;
+        let mut tag = None;
+        let mut blocks = vec![];
+        let mut pictures = vec![];
+
+        let mut is_last = false;
+        while !is_last {
+            let header = read_block(file, 4)?;
+            if header.len() < 4 {
+                return Err(Error::TruncatedHeader);
+            }
+
+            is_last = header[0] & 0x80 != 0;
+            let code = header[0] & 0x7f;
+            let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+            let data = read_block(file, length)?;
+            if data.len() < length {
+                return Err(Error::TruncatedHeader);
+            }
+
+            // Picture blocks which cannot be parsed are kept as they are.
+            // Only one comment block is allowed and a new one is written when
+            // saving, so extra and damaged comment blocks are dropped.
+            match BlockType::try_from(code) {
+                Ok(BlockType::StreamInfo) if stream_info.is_none() => stream_info = Some(data),
+                Ok(BlockType::VorbisComment) if tag.is_none() => {
+                    tag = XiphComment::parse(&data).ok()
+                }
+                Ok(BlockType::VorbisComment) => (),
+                Ok(BlockType::Picture) => match FlacPicture::parse(&data) {
+                    Ok(picture) => pictures.push(picture),
+                    Err(_) => blocks.push(FlacMetadataBlock::new(code, data)),
+                },
+                // Padding is recreated when saving.
+                Ok(BlockType::Padding) => (),
+                _ => blocks.push(FlacMetadataBlock::new(code, data)),
+            }
+        }
+
+        let stream_info = stream_info.ok_or(Error::InvalidHeader)?;
+        let stream_offset = file.stream_position()?;
+        let audio_properties = FlacProperties::new(&stream_info, file_length - stream_offset)?;
+
+        Ok(Self {
+            tag: tag.unwrap_or_default(),
+            audio_properties,
+            path: None,
+            metadata_start,
+            metadata_length: stream_offset - metadata_start,
+            stream_info,
+            blocks,
+            pictures,
+        })
+    }
+
+    // Writes the metadata blocks to the file.  The audio frames are only moved
+    // if the blocks outgrow the space of the old ones and their padding.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        let mut blocks = vec![(BlockType::StreamInfo.into(), self.stream_info.clone())];
+        blocks.extend(self.blocks.iter().map(|b| (b.code, b.data.clone())));
+        blocks.push((BlockType::VorbisComment.into(), self.tag.render(false)));
+        blocks.extend(
+            self.pictures
+                .iter()
+                .map(|p| (BlockType::Picture.into(), p.render())),
+        );
+
+        let mut data = vec![];
+        for (code, block) in &blocks {
+            if block.len() > MAX_BLOCK_LENGTH {
+                return Err(Error::InvalidHeader);
+            }
+            data.extend(render_block_header(*code, block.len(), false));
+            data.extend(block);
+        }
+
+        // Fill the old space with padding, or leave room for later edits if
+        // it is too small.
+        let padding_length = match (self.metadata_length as usize).checked_sub(data.len() + 4) {
+            Some(length) => length.min(MAX_BLOCK_LENGTH),
+            None => DEFAULT_PADDING_SIZE,
+        };
+        data.extend(render_block_header(
+            BlockType::Padding.into(),
+            padding_length,
+            true,
+        ));
+        data.resize(data.len() + padding_length, 0);
+
+        insert_block(file, &data, self.metadata_start, self.metadata_length)?;
+        self.metadata_length = data.len() as u64;
+
+        Ok(())
+    }
+
+    // returns the pictures of the PICTURE blocks
+    pub fn pictures(&self) -> &[FlacPicture] {
+        &self.pictures
+    }
+
+    pub fn add_picture(&mut self, picture: FlacPicture) {
+        self.pictures.push(picture);
+    }
+
+    pub fn remove_pictures(&mut self) {
+        self.pictures.clear();
+    }
+
+    // returns the metadata blocks which are kept verbatim, e.g. SEEKTABLE,
+    // APPLICATION and CUESHEET
+    pub fn metadata_blocks(&self) -> &[FlacMetadataBlock] {
+        &self.blocks
+    }
+}
+
+fn render_block_header(code: u8, length: usize, is_last: bool) -> [u8; 4] {
+    let length = (length as u32).to_be_bytes();
+    let code = if is_last { code | 0x80 } else { code };
+
+    [code, length[1], length[2], length[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // 44100 Hz, 2 channels, 16 bits per sample, 441000 samples
+    fn stream_info() -> Vec<u8> {
+        let mut data = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+        let flags: u64 = (44100 << 44) | (1 << 41) | (15 << 36) | 441_000;
+        data.extend(flags.to_be_bytes());
+        data.extend(1..=16u8);
+        data
+    }
+
+    fn comment_block() -> Vec<u8> {
+        let mut comment = XiphComment::empty();
+        comment.set_vendor("reference libFLAC 1.4.3");
+        comment.add_field("TITLE", "Title");
+        comment.add_field("artist", "One");
+        comment.add_field("Artist", "Two");
+        comment.render(false)
+    }
+
+    fn flac_file(padding: usize, audio: &[u8]) -> Vec<u8> {
+        let blocks = [
+            (0, stream_info()),
+            (3, vec![0; 18]),
+            (4, comment_block()),
+            (1, vec![0; padding]),
+        ];
+        flac_file_with_blocks(&blocks, audio)
+    }
+
+    fn flac_file_with_blocks(blocks: &[(u8, Vec<u8>)], audio: &[u8]) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        for (i, (code, block)) in blocks.iter().enumerate() {
+            data.extend(render_block_header(
+                *code,
+                block.len(),
+                i == blocks.len() - 1,
+            ));
+            data.extend(block);
+        }
+        data.extend(audio);
+        data
+    }
+
+    // returns the codes of the metadata blocks of `data`
+    fn block_codes(data: &[u8]) -> Vec<u8> {
+        let mut codes = vec![];
+        let mut pos = 4;
+        loop {
+            codes.push(data[pos] & 0x7f);
+            if data[pos] & 0x80 != 0 {
+                return codes;
+            }
+            pos +=
+                4 + u32::from_be_bytes([0, data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let audio = vec![0xaa; 10_000];
+        let mut cursor = Cursor::new(flac_file(100, &audio));
+        let file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        let properties = &file.audio_properties;
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.bits_per_sample(), 16);
+        assert_eq!(properties.sample_frames(), 441_000);
+        assert_eq!(properties.length_in_milliseconds(), 10_000);
+        assert_eq!(properties.bitrate(), 8);
+        assert_eq!(properties.signature()[0], 1);
+
+        assert_eq!(file.tag.vendor(), "reference libFLAC 1.4.3");
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        assert_eq!(file.tag.artist().as_deref(), Some("One"));
+        assert_eq!(file.tag.properties()["ARTIST"], ["One", "Two"]);
+        assert_eq!(file.metadata_blocks().len(), 1);
+        assert_eq!(
+            file.metadata_blocks()[0].block_type(),
+            Some(BlockType::SeekTable)
+        );
+
+        let result = FlacFile::read_from(&mut Cursor::new(audio), ReadStyle::Average);
+        assert!(matches!(result, Err(Error::UnsupportedFormat)));
+    }
+
+    #[test]
+    fn test_read_damaged_blocks() {
+        let blocks = [
+            (0, stream_info()),
+            (4, vec![0xff; 8]),
+            (6, vec![0, 0, 0, 3]),
+            (4, comment_block()),
+        ];
+        let mut cursor = Cursor::new(flac_file_with_blocks(&blocks, &[0xaa; 100]));
+        let mut file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        assert!(file.pictures().is_empty());
+        let codes: Vec<u8> = file.metadata_blocks().iter().map(|b| b.code()).collect();
+        assert_eq!(codes, [6]);
+
+        file.save_to(&mut cursor).unwrap();
+        assert_eq!(block_codes(cursor.get_ref()), [0, 6, 4, 1]);
+        let file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.metadata_blocks()[0].data(), [0, 0, 0, 3]);
+
+        // the only comment block is damaged
+        let blocks = [(0, stream_info()), (4, vec![0xff; 8])];
+        let mut cursor = Cursor::new(flac_file_with_blocks(&blocks, &[0xaa; 100]));
+        let mut file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.tag.is_empty());
+        assert!(file.metadata_blocks().is_empty());
+
+        file.tag.set_title(Some(String::from("Title")));
+        file.save_to(&mut cursor).unwrap();
+        assert_eq!(block_codes(cursor.get_ref()), [0, 4, 1]);
+    }
+
+    #[test]
+    fn test_drop_extra_comment_blocks() {
+        let mut second = XiphComment::empty();
+        second.add_field("TITLE", "Second");
+        let blocks = [
+            (0, stream_info()),
+            (4, comment_block()),
+            (4, second.render(false)),
+        ];
+        let mut cursor = Cursor::new(flac_file_with_blocks(&blocks, &[0xaa; 100]));
+        let mut file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        assert!(file.metadata_blocks().is_empty());
+
+        file.save_to(&mut cursor).unwrap();
+        assert!(!cursor.get_ref().windows(6).any(|w| w == b"Second"));
+        let file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn test_save_uses_padding() {
+        let audio = vec![0xaa; 1000];
+        let data = flac_file(100, &audio);
+        let length = data.len();
+        let mut cursor = Cursor::new(data);
+
+        let mut file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        file.tag.set_album(Some(String::from("Album")));
+        file.tag.set_track(Some(3));
+        file.save_to(&mut cursor).unwrap();
+
+        assert_eq!(cursor.get_ref().len(), length);
+        assert_eq!(cursor.get_ref()[length - audio.len()..], audio[..]);
+
+        let file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.album().as_deref(), Some("Album"));
+        assert_eq!(*file.tag.track(), Some(3));
+        assert_eq!(file.tag.properties()["ARTIST"], ["One", "Two"]);
+        assert_eq!(file.metadata_blocks().len(), 1);
+    }
+
+    #[test]
+    fn test_save_pictures() {
+        let audio = vec![0xaa; 1000];
+        let mut cursor = Cursor::new(flac_file(0, &audio));
+
+        let picture = FlacPicture {
+            picture_type: 3,
+            mime_type: String::from("image/png"),
+            description: String::from("Cover"),
+            width: 1,
+            height: 1,
+            color_depth: 24,
+            num_colors: 0,
+            data: vec![0x89, b'P', b'N', b'G'],
+        };
+        assert_eq!(FlacPicture::parse(&picture.render()).unwrap(), picture);
+
+        let mut file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        file.add_picture(picture.clone());
+        file.save_to(&mut cursor).unwrap();
+
+        // the metadata grew, so the default padding was added
+        let length = cursor.get_ref().len();
+        assert!(length > flac_file(0, &audio).len() + DEFAULT_PADDING_SIZE);
+        assert_eq!(cursor.get_ref()[length - audio.len()..], audio[..]);
+
+        let mut file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.pictures(), [picture]);
+
+        file.remove_pictures();
+        file.save_to(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref().len(), length);
+
+        let file = FlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.pictures().is_empty());
+    }
+}
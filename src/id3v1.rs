@@ -1,13 +1,167 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{Read, Seek},
+    io::{Read, Seek, SeekFrom},
+    sync::{Arc, RwLock},
 };
 
-use crate::tag::Tag;
+use crate::{
+    error::{Error, Result},
+    tag::{PropertyMap, Tag},
+    utils::read_block,
+};
+
+// Decodes and encodes the text fields of ID3v1 tags.  The specification
+// requires ISO-8859-1, but many tags were written in the local codepage of the
+// tagging software.  Implement this trait to support other charsets, e.g. with
+// Shift-JIS or GBK decoders from an encoding library.
+pub trait StringHandler: Send + Sync {
+    // decodes a field, which has already been cut at its first NUL byte
+    fn parse(&self, data: &[u8]) -> String;
+
+    // encodes a field; the result is truncated to the width of the field
+    fn render(&self, s: &str) -> Vec<u8>;
+}
+
+// ISO-8859-1, the default.  Characters outside of it are rendered as '?'.
+pub struct Latin1StringHandler;
+
+impl StringHandler for Latin1StringHandler {
+    fn parse(&self, data: &[u8]) -> String {
+        data.iter().map(|&b| b as char).collect()
+    }
+
+    fn render(&self, s: &str) -> Vec<u8> {
+        s.chars()
+            .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+            .collect()
+    }
+}
+
+// UTF-8, as written by some newer taggers.  Invalid sequences are replaced.
+pub struct Utf8StringHandler;
+
+impl StringHandler for Utf8StringHandler {
+    fn parse(&self, data: &[u8]) -> String {
+        String::from_utf8_lossy(data).into_owned()
+    }
+
+    fn render(&self, s: &str) -> Vec<u8> {
+        s.as_bytes().to_vec()
+    }
+}
+
+// Windows-1251 (Cyrillic)
+pub struct Windows1251StringHandler;
+
+impl StringHandler for Windows1251StringHandler {
+    fn parse(&self, data: &[u8]) -> String {
+        parse_single_byte(data, &WINDOWS_1251)
+    }
+
+    fn render(&self, s: &str) -> Vec<u8> {
+        render_single_byte(s, &WINDOWS_1251)
+    }
+}
+
+// Windows-1252 (Western European)
+pub struct Windows1252StringHandler;
+
+impl StringHandler for Windows1252StringHandler {
+    fn parse(&self, data: &[u8]) -> String {
+        parse_single_byte(data, &WINDOWS_1252)
+    }
+
+    fn render(&self, s: &str) -> Vec<u8> {
+        render_single_byte(s, &WINDOWS_1252)
+    }
+}
+
+// Maps the bytes 0x80 to 0xff to `table`, the lower half is ASCII.
+fn parse_single_byte(data: &[u8], table: &[char; 128]) -> String {
+    data.iter()
+        .map(|&b| {
+            if b < 0x80 {
+                b as char
+            } else {
+                table[b as usize - 0x80]
+            }
+        })
+        .collect()
+}
+
+fn render_single_byte(s: &str, table: &[char; 128]) -> Vec<u8> {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                c as u8
+            } else {
+                table
+                    .iter()
+                    .position(|&t| t == c)
+                    .map_or(b'?', |i| (i + 0x80) as u8)
+            }
+        })
+        .collect()
+}
+
+// Bytes which are undefined in a codepage map to the C1 control character of
+// the same value, like ISO-8859-1 does.
+const WINDOWS_1251: [char; 128] = [
+    '\u{0402}', '\u{0403}', '\u{201a}', '\u{0453}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{20ac}', '\u{2030}', '\u{0409}', '\u{2039}', '\u{040a}', '\u{040c}', '\u{040b}', '\u{040f}',
+    '\u{0452}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{0098}', '\u{2122}', '\u{0459}', '\u{203a}', '\u{045a}', '\u{045c}', '\u{045b}', '\u{045f}',
+    '\u{00a0}', '\u{040e}', '\u{045e}', '\u{0408}', '\u{00a4}', '\u{0490}', '\u{00a6}', '\u{00a7}',
+    '\u{0401}', '\u{00a9}', '\u{0404}', '\u{00ab}', '\u{00ac}', '\u{00ad}', '\u{00ae}', '\u{0407}',
+    '\u{00b0}', '\u{00b1}', '\u{0406}', '\u{0456}', '\u{0491}', '\u{00b5}', '\u{00b6}', '\u{00b7}',
+    '\u{0451}', '\u{2116}', '\u{0454}', '\u{00bb}', '\u{0458}', '\u{0405}', '\u{0455}', '\u{0457}',
+    '\u{0410}', '\u{0411}', '\u{0412}', '\u{0413}', '\u{0414}', '\u{0415}', '\u{0416}', '\u{0417}',
+    '\u{0418}', '\u{0419}', '\u{041a}', '\u{041b}', '\u{041c}', '\u{041d}', '\u{041e}', '\u{041f}',
+    '\u{0420}', '\u{0421}', '\u{0422}', '\u{0423}', '\u{0424}', '\u{0425}', '\u{0426}', '\u{0427}',
+    '\u{0428}', '\u{0429}', '\u{042a}', '\u{042b}', '\u{042c}', '\u{042d}', '\u{042e}', '\u{042f}',
+    '\u{0430}', '\u{0431}', '\u{0432}', '\u{0433}', '\u{0434}', '\u{0435}', '\u{0436}', '\u{0437}',
+    '\u{0438}', '\u{0439}', '\u{043a}', '\u{043b}', '\u{043c}', '\u{043d}', '\u{043e}', '\u{043f}',
+    '\u{0440}', '\u{0441}', '\u{0442}', '\u{0443}', '\u{0444}', '\u{0445}', '\u{0446}', '\u{0447}',
+    '\u{0448}', '\u{0449}', '\u{044a}', '\u{044b}', '\u{044c}', '\u{044d}', '\u{044e}', '\u{044f}',
+];
+
+const WINDOWS_1252: [char; 128] = [
+    '\u{20ac}', '\u{0081}', '\u{201a}', '\u{0192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02c6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008d}', '\u{017d}', '\u{008f}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02dc}', '\u{2122}', '\u{0161}', '\u{203a}', '\u{0153}', '\u{009d}', '\u{017e}', '\u{0178}',
+    '\u{00a0}', '\u{00a1}', '\u{00a2}', '\u{00a3}', '\u{00a4}', '\u{00a5}', '\u{00a6}', '\u{00a7}',
+    '\u{00a8}', '\u{00a9}', '\u{00aa}', '\u{00ab}', '\u{00ac}', '\u{00ad}', '\u{00ae}', '\u{00af}',
+    '\u{00b0}', '\u{00b1}', '\u{00b2}', '\u{00b3}', '\u{00b4}', '\u{00b5}', '\u{00b6}', '\u{00b7}',
+    '\u{00b8}', '\u{00b9}', '\u{00ba}', '\u{00bb}', '\u{00bc}', '\u{00bd}', '\u{00be}', '\u{00bf}',
+    '\u{00c0}', '\u{00c1}', '\u{00c2}', '\u{00c3}', '\u{00c4}', '\u{00c5}', '\u{00c6}', '\u{00c7}',
+    '\u{00c8}', '\u{00c9}', '\u{00ca}', '\u{00cb}', '\u{00cc}', '\u{00cd}', '\u{00ce}', '\u{00cf}',
+    '\u{00d0}', '\u{00d1}', '\u{00d2}', '\u{00d3}', '\u{00d4}', '\u{00d5}', '\u{00d6}', '\u{00d7}',
+    '\u{00d8}', '\u{00d9}', '\u{00da}', '\u{00db}', '\u{00dc}', '\u{00dd}', '\u{00de}', '\u{00df}',
+    '\u{00e0}', '\u{00e1}', '\u{00e2}', '\u{00e3}', '\u{00e4}', '\u{00e5}', '\u{00e6}', '\u{00e7}',
+    '\u{00e8}', '\u{00e9}', '\u{00ea}', '\u{00eb}', '\u{00ec}', '\u{00ed}', '\u{00ee}', '\u{00ef}',
+    '\u{00f0}', '\u{00f1}', '\u{00f2}', '\u{00f3}', '\u{00f4}', '\u{00f5}', '\u{00f6}', '\u{00f7}',
+    '\u{00f8}', '\u{00f9}', '\u{00fa}', '\u{00fb}', '\u{00fc}', '\u{00fd}', '\u{00fe}', '\u{00ff}',
+];
+
+// the handler installed with ID3v1Tag::set_string_handler, None for ISO-8859-1
+static STRING_HANDLER: RwLock<Option<Arc<dyn StringHandler>>> = RwLock::new(None);
 
+fn string_handler() -> Arc<dyn StringHandler> {
+    match STRING_HANDLER.read() {
+        Ok(handler) => handler
+            .clone()
+            .unwrap_or_else(|| Arc::new(Latin1StringHandler)),
+        Err(_) => Arc::new(Latin1StringHandler),
+    }
+}
+
+// the size of the "TAG+" block which may precede the tag
+pub const EXTENDED_TAG_SIZE: usize = 227;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct ID3v1TagPrivate {
     tag_offset: usize,
+    extended: bool,
     title: Option<String>,
     artist: Option<String>,
     album: Option<String>,
@@ -15,23 +169,83 @@ pub(crate) struct ID3v1TagPrivate {
     comment: Option<String>,
     track: Option<u32>,
     genre: Option<String>,
+    speed: Option<u8>,
+    start_time: Option<String>,
+    end_time: Option<String>,
 }
 
-pub(crate) struct ID3v1Tag {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ID3v1Tag {
     d: ID3v1TagPrivate,
 }
 
 impl Tag for ID3v1Tag {
-    fn properties(&self) -> &crate::tag::PropertyMap {
-        todo!()
+    fn properties(&self) -> PropertyMap {
+        let mut properties = PropertyMap::new();
+        let mut insert = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                properties.insert(String::from(key), vec![value]);
+            }
+        };
+
+        insert("TITLE", self.d.title.clone());
+        insert("ARTIST", self.d.artist.clone());
+        insert("ALBUM", self.d.album.clone());
+        insert("DATE", self.d.year.map(|year| year.to_string()));
+        insert("COMMENT", self.d.comment.clone());
+        insert("TRACKNUMBER", self.d.track.map(|track| track.to_string()));
+        insert("GENRE", self.d.genre.clone());
+
+        properties
     }
 
-    fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
-        todo!()
+    fn remove_unsupported_properties(&mut self, _properties: Vec<String>) {
+        // ID3v1 only has fixed fields, so there is nothing unsupported to remove.
     }
 
-    fn set_properties(&mut self, properties: crate::tag::PropertyMap) {
-        todo!()
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        let mut unsupported = properties;
+        unsupported.retain(|_, values| !values.is_empty());
+
+        // Only the first value of each key can be stored; the rest, and all
+        // unknown keys, are returned.
+        let mut take = |key: &str| -> Option<String> {
+            let values = unsupported.get_mut(key)?;
+            let value = values.remove(0);
+            if values.is_empty() {
+                unsupported.remove(key);
+            }
+
+            Some(value)
+        };
+
+        let title = take("TITLE");
+        let artist = take("ARTIST");
+        let album = take("ALBUM");
+        let date = take("DATE");
+        let comment = take("COMMENT");
+        let track = take("TRACKNUMBER");
+        let genre = take("GENRE");
+
+        self.set_title(title);
+        self.set_artist(artist);
+        self.set_album(album);
+        self.set_year(date.as_deref().and_then(leading_number));
+        self.set_comment(comment);
+        self.set_track(track.as_deref().and_then(leading_number));
+
+        match genre {
+            Some(name) if !self.d.extended && genre_number(&name).is_none() => {
+                unsupported
+                    .entry(String::from("GENRE"))
+                    .or_default()
+                    .insert(0, name);
+                self.set_genre(None);
+            }
+            name => self.set_genre(name),
+        }
+
+        unsupported
     }
 
     fn title(&self) -> &Option<String> {
@@ -51,7 +265,7 @@ impl Tag for ID3v1Tag {
     }
 
     fn genre(&self) -> &Option<String> {
-        todo!()
+        &self.d.genre
     }
 
     fn year(&self) -> &Option<u32> {
@@ -63,23 +277,29 @@ impl Tag for ID3v1Tag {
     }
 
     fn set_title(&mut self, title: Option<String>) {
-        self.d.title = title
+        self.d.title = fit(title, self.text_size())
     }
 
     fn set_artist(&mut self, artist: Option<String>) {
-        self.d.artist = artist
+        self.d.artist = fit(artist, self.text_size())
     }
 
     fn set_album(&mut self, album: Option<String>) {
-        self.d.album = album
+        self.d.album = fit(album, self.text_size())
     }
 
     fn set_comment(&mut self, comment: Option<String>) {
-        self.d.comment = comment
+        self.d.comment = fit(comment, 30)
     }
 
-    fn set_genre(&mut self, genre: Option<String>) {
-        todo!()
+    // Only genres from the ID3v1 list can be stored, unless the tag is
+    // extended; others clear the genre.
+    fn set_genre(&mut self, name: Option<String>) {
+        self.d.genre = match name.as_deref().and_then(genre_number) {
+            Some(i) => genre_name(i).map(String::from),
+            None if self.d.extended => fit(name, 30),
+            None => None,
+        }
     }
 
     fn set_year(&mut self, year: Option<u32>) {
@@ -94,57 +314,111 @@ impl Tag for ID3v1Tag {
     }
 
     fn is_empty(&self) -> bool {
-        todo!()
+        self.d.title.is_none()
+            && self.d.artist.is_none()
+            && self.d.album.is_none()
+            && self.d.year.is_none()
+            && self.d.comment.is_none()
+            && self.d.track.is_none()
+            && self.d.genre.is_none()
+            && self.d.speed.is_none()
+            && self.d.start_time.is_none()
+            && self.d.end_time.is_none()
     }
 }
 
 impl ID3v1Tag {
-    pub(crate) fn new(mut file: File, tag_offset: usize) -> std::io::Result<Self> {
-        let mut d = ID3v1TagPrivate {
-            tag_offset,
-            title: None,
-            artist: None,
-            album: None,
-            year: None,
-            comment: None,
-            track: Some(0),
-            genre: Some(String::from("")),
-        };
+    // Reads the tag at `tag_offset`, together with the extended "TAG+" block
+    // right before it if there is one.  The offset of the tag is then the
+    // offset of the extended block.
+    pub fn new<R: Read + Seek>(file: &mut R, tag_offset: usize) -> Result<Self> {
+        if let Some(offset) = tag_offset.checked_sub(EXTENDED_TAG_SIZE) {
+            file.seek(SeekFrom::Start(offset as u64))?;
+            let data = read_block(file, EXTENDED_TAG_SIZE + 128)?;
 
-        file.seek(std::io::SeekFrom::Start(d.tag_offset.try_into().unwrap()))?;
+            if data.starts_with(b"TAG+") {
+                let mut tag = Self::parse(&data)?;
+                tag.d.tag_offset = offset;
+
+                return Ok(tag);
+            }
+        }
+
+        file.seek(SeekFrom::Start(tag_offset as u64))?;
 
         // read the tag, always 128 bytes
-        let mut data = [0u8; 128];
-        file.read_exact(&mut data)?;
-
-        if data[0] != b'T' || data[1] != b'A' || data[2] != b'G' {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "ID3v1 tag is not valid or could not be read at the specified offset.",
-            ));
+        let data = read_block(file, 128)?;
+
+        let mut tag = Self::parse(&data)?;
+        tag.d.tag_offset = tag_offset;
+
+        Ok(tag)
+    }
+
+    // creates a tag without any fields set
+    pub fn empty() -> Self {
+        Self {
+            d: ID3v1TagPrivate {
+                tag_offset: 0,
+                extended: false,
+                title: None,
+                artist: None,
+                album: None,
+                year: None,
+                comment: None,
+                track: None,
+                genre: None,
+                speed: None,
+                start_time: None,
+                end_time: None,
+            },
+        }
+    }
+
+    // Sets the string handler used by all ID3v1 tags which are read or rendered
+    // afterwards.  `None` restores the default ISO-8859-1 handler.
+    pub fn set_string_handler(handler: Option<Arc<dyn StringHandler>>) {
+        if let Ok(mut current) = STRING_HANDLER.write() {
+            *current = handler;
         }
+    }
+
+    // Parses the tag in `data` with the installed string handler.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_with(data, string_handler().as_ref())
+    }
+
+    // Parses the 128 byte tag in `data`, which may be preceded by a 227 byte
+    // "TAG+" block.  Fields are decoded with `handler` and end at the first NUL
+    // byte; empty fields and unparsable years are None.
+    pub fn parse_with(data: &[u8], handler: &dyn StringHandler) -> Result<Self> {
+        let (extended, data) = match data.strip_prefix(b"TAG+") {
+            Some(_) if data.len() >= EXTENDED_TAG_SIZE + 128 => data.split_at(EXTENDED_TAG_SIZE),
+            _ => (&[][..], data),
+        };
+
+        if data.len() < 128 || &data[0..3] != b"TAG" {
+            return Err(Error::NoTagFound);
+        }
+
+        let mut d = Self::empty().d;
+
+        // The extended block continues the title, artist and album.
+        let tail = |field: usize| extended.get(4 + field * 60..4 + (field + 1) * 60);
 
         let mut offset = 3;
-        d.title = Some(parse(
-            String::from_utf8(data[offset..offset + 30].to_vec()).unwrap(),
-        ));
+        d.title = parse_joined(&data[offset..offset + 30], tail(0), handler);
         offset += 30;
 
-        d.artist = Some(parse(
-            String::from_utf8(data[offset..offset + 30].to_vec()).unwrap(),
-        ));
+        d.artist = parse_joined(&data[offset..offset + 30], tail(1), handler);
         offset += 30;
 
-        d.album = Some(parse(
-            String::from_utf8(data[offset..offset + 30].to_vec()).unwrap(),
-        ));
+        d.album = parse_joined(&data[offset..offset + 30], tail(2), handler);
         offset += 30;
 
-        d.year = Some(
-            parse(String::from_utf8(data[offset..offset + 4].to_vec()).unwrap())
-                .parse::<u32>()
-                .unwrap(),
-        );
+        d.year = parse(&data[offset..offset + 4], handler)
+            .and_then(|year| year.parse::<u32>().ok())
+            .filter(|&year| year > 0);
         offset += 4;
 
         // Check for ID3v1.1 -- Note that ID3v1 *does not* support "track zero" -- this
@@ -154,75 +428,198 @@ impl ID3v1Tag {
 
         if data[offset + 28] == 0 && data[offset + 29] != 0 {
             // ID3v1.1 detected
-            d.comment = Some(parse(
-                String::from_utf8(data[offset..offset + 28].to_vec()).unwrap(),
-            ));
+            d.comment = parse(&data[offset..offset + 28], handler);
             d.track = Some(data[offset + 29] as u32);
         } else {
-            d.comment = Some(parse(
-                String::from_utf8(data[offset..offset + 30].to_vec()).unwrap(),
-            ));
+            d.comment = parse(&data[offset..offset + 30], handler);
         }
 
         offset += 30;
 
-        //d.genre = Some(data[offset] as u32);
-        d.genre = Some(String::from(genre(data[offset] as usize)));
+        d.genre = genre_name(data[offset]).map(String::from);
+
+        if !extended.is_empty() {
+            d.extended = true;
+            d.speed = Some(extended[184]).filter(|&speed| speed != 0);
+            d.start_time = parse(&extended[215..221], handler);
+            d.end_time = parse(&extended[221..227], handler);
+
+            // The free-text genre is more specific than the genre number.
+            if let Some(genre) = parse(&extended[185..215], handler) {
+                d.genre = Some(genre);
+            }
+        }
 
         Ok(Self { d })
     }
 
-    pub(crate) fn set_genre_number(&mut self, i: Option<u32>) {
-        let x = match i {
-            Some(n) if n < 256 => n,
-            _ => 255,
-        };
-
-        self.d.genre = Some(String::from(genre(x as usize)));
+    // returns true if the tag is written with a "TAG+" block when its values do
+    // not fit into the plain tag
+    pub fn is_extended(&self) -> bool {
+        self.d.extended
     }
 
-    // probably useless
-    /* pub(crate) fn get_genre_number(&self) -> usize {
-        //genre_index(self.d.genre.unwrap().as_str())
-        if self.d.genre.is_some() {
-            let s = self.d.genre.clone();
+    // Enables or disables the extended "TAG+" block, which allows 90 byte
+    // titles, artists and albums, and free-text genres.  When it is disabled,
+    // values which do not fit into the plain tag are cut or cleared.
+    pub fn set_extended(&mut self, extended: bool) {
+        self.d.extended = extended;
 
-            return genre_index(s.unwrap().as_str());
+        if !extended {
+            self.d.title = fit(self.d.title.take(), 30);
+            self.d.artist = fit(self.d.artist.take(), 30);
+            self.d.album = fit(self.d.album.take(), 30);
+            self.d.genre = self.genre_number().and_then(genre_name).map(String::from);
+            self.d.speed = None;
+            self.d.start_time = None;
+            self.d.end_time = None;
         }
+    }
 
-        255
-    } */
+    // returns the speed from the extended tag: 1 for slow, 2 for medium, 3 for
+    // fast and 4 for hardcore
+    pub fn speed(&self) -> Option<u8> {
+        self.d.speed
+    }
 
-    pub(crate) fn render(&self) -> Vec<u8> {
-        let mut data: Vec<u8> = vec![];
+    pub fn set_speed(&mut self, speed: Option<u8>) {
+        self.d.speed = speed.filter(|&speed| speed != 0 && self.d.extended);
+    }
 
-        data.append(&mut vec![b'T', b'A', b'G']);
-        let s = self.d.title.clone();
-        data.append(&mut resize(&s.unwrap().as_bytes().to_vec(), 30));
+    // returns the start time of the music from the extended tag, as "mmm:ss"
+    pub fn start_time(&self) -> &Option<String> {
+        &self.d.start_time
+    }
 
-        let s = self.d.artist.clone();
-        data.append(&mut resize(&s.unwrap().as_bytes().to_vec(), 30));
+    pub fn set_start_time(&mut self, time: Option<String>) {
+        self.d.start_time = fit(time.filter(|_| self.d.extended), 6);
+    }
 
-        let s = self.d.album.clone();
-        data.append(&mut resize(&s.unwrap().as_bytes().to_vec(), 30));
+    // returns the end time of the music from the extended tag, as "mmm:ss"
+    pub fn end_time(&self) -> &Option<String> {
+        &self.d.end_time
+    }
+
+    pub fn set_end_time(&mut self, time: Option<String>) {
+        self.d.end_time = fit(time.filter(|_| self.d.extended), 6);
+    }
+
+    // returns the offset of the tag in the file it was read from, including a
+    // preceding extended block
+    pub(crate) fn offset(&self) -> u64 {
+        self.d.tag_offset as u64
+    }
+
+    // returns the size available for titles, artists and albums
+    fn text_size(&self) -> usize {
+        if self.d.extended {
+            90
+        } else {
+            30
+        }
+    }
+
+    // sets the genre by its index in the ID3v1 genre list; unknown indices
+    // clear the genre
+    pub fn set_genre_number(&mut self, i: Option<u32>) {
+        self.d.genre = i
+            .and_then(|n| u8::try_from(n).ok())
+            .and_then(genre_name)
+            .map(String::from);
+    }
+
+    // returns the index of the genre in the ID3v1 genre list
+    pub fn genre_number(&self) -> Option<u8> {
+        self.d.genre.as_deref().and_then(genre_number)
+    }
+
+    // Renders the tag with the installed string handler.
+    pub fn render(&self) -> Vec<u8> {
+        self.render_with(string_handler().as_ref())
+    }
+
+    // Renders the tag into exactly 128 bytes.  Fields which are too long are
+    // cut on character boundaries; the ID3v1.1 layout, which takes the last two
+    // bytes of the comment, is only used if there is a track number.
+    pub fn render_with(&self, handler: &dyn StringHandler) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(128);
 
-        let s = self.d.year.clone();
-        data.append(&mut resize(&s.unwrap().to_string().as_bytes().to_vec(), 4));
+        data.extend(b"TAG");
+        data.extend(render_field(text(&self.d.title), 30, handler));
+        data.extend(render_field(text(&self.d.artist), 30, handler));
+        data.extend(render_field(text(&self.d.album), 30, handler));
 
-        let s = self.d.comment.clone();
-        data.append(&mut resize(&s.unwrap().as_bytes().to_vec(), 28));
+        let year = self.d.year.filter(|&year| year <= 9999);
+        data.extend(resize(
+            year.map(|year| year.to_string())
+                .unwrap_or_default()
+                .as_bytes(),
+            4,
+        ));
 
-        data.push(0);
-        data.push(self.d.track.unwrap() as u8);
+        match self.d.track {
+            Some(track) => {
+                data.extend(render_field(text(&self.d.comment), 28, handler));
+                data.push(0);
+                data.push(track as u8);
+            }
+            None => data.extend(render_field(text(&self.d.comment), 30, handler)),
+        }
 
-        let s = self.d.genre.clone();
-        data.push(genre_index(s.unwrap().as_str()) as u8);
+        let genre = self.d.genre.as_deref().and_then(genre_number);
+        data.push(genre.unwrap_or(255));
 
         data
     }
+
+    // Renders the extended block with the installed string handler.
+    pub fn render_extended(&self) -> Option<Vec<u8>> {
+        self.render_extended_with(string_handler().as_ref())
+    }
+
+    // Renders the 227 byte "TAG+" block, which goes right before the tag.  It
+    // is only needed, and rendered, if the tag is extended and has values which
+    // do not fit into the plain tag.
+    pub fn render_extended_with(&self, handler: &dyn StringHandler) -> Option<Vec<u8>> {
+        // returns the part of a field which does not fit into the plain tag
+        let tail = |s: &Option<String>| {
+            let s = text(s);
+            String::from(&s[truncate(s, 30, handler).len()..])
+        };
+
+        let titles = [&self.d.title, &self.d.artist, &self.d.album].map(tail);
+        let free_genre = self
+            .d
+            .genre
+            .as_deref()
+            .filter(|g| genre_number(g).is_none());
+
+        let needed = titles.iter().any(|s| !s.is_empty())
+            || free_genre.is_some()
+            || self.d.speed.is_some()
+            || self.d.start_time.is_some()
+            || self.d.end_time.is_some();
+
+        if !self.d.extended || !needed {
+            return None;
+        }
+
+        let mut data: Vec<u8> = Vec::with_capacity(EXTENDED_TAG_SIZE);
+
+        data.extend(b"TAG+");
+        for s in &titles {
+            data.extend(render_field(s, 60, handler));
+        }
+        data.push(self.d.speed.unwrap_or(0));
+        data.extend(render_field(free_genre.unwrap_or_default(), 30, handler));
+        data.extend(render_field(text(&self.d.start_time), 6, handler));
+        data.extend(render_field(text(&self.d.end_time), 6, handler));
+
+        Some(data)
+    }
 }
 
-const genres: &'static [&'static str] = &[
+const GENRES: &[&str] = &[
     "Blues",
     "Classic Rock",
     "Country",
@@ -417,7 +814,7 @@ const genres: &'static [&'static str] = &[
     "Psybient",
 ];
 
-const fix_up_genres: &'static [(&'static str, usize)] = &[
+const FIX_UP_GENRES: &[(&str, usize)] = &[
     ("Jazz+Funk", 29),
     ("Folk/Rock", 81),
     ("Bebob", 85),
@@ -428,49 +825,107 @@ const fix_up_genres: &'static [(&'static str, usize)] = &[
     ("Negerpunk", 133),
 ];
 
-fn genre(i: usize) -> &'static str {
-    if i < genres.len() {
-        genres[i]
-    } else {
-        ""
-    }
+// returns the name of the genre with the given index in the Winamp 5.6 list
+pub fn genre_name(index: u8) -> Option<&'static str> {
+    GENRES.get(index as usize).copied()
 }
 
-fn genre_index(name: &str) -> usize {
-    let i = genres.iter().position(|&x| x == name);
+// Returns the index of the genre `name`, ignoring case.  Older spellings like
+// "Jazz+Funk" are accepted, as are ID3v2 style references like "(17)" or "17".
+pub fn genre_number(name: &str) -> Option<u8> {
+    let name = name.trim();
+    let reference = name
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .unwrap_or(name);
 
-    if let Some(n) = i {
-        return n;
+    if let Ok(i) = reference.parse::<u8>() {
+        return genre_name(i).map(|_| i);
     }
 
-    let mut i = 0;
-    while i < fix_up_genres.len() {
-        if fix_up_genres[i].0 == name {
-            return fix_up_genres[i].1;
-        }
+    GENRES
+        .iter()
+        .position(|g| g.eq_ignore_ascii_case(name))
+        .or_else(|| {
+            FIX_UP_GENRES
+                .iter()
+                .find(|(g, _)| g.eq_ignore_ascii_case(name))
+                .map(|&(_, i)| i)
+        })
+        .map(|i| i as u8)
+}
 
-        i += 1;
-    }
+// returns the names of all genres, ordered by their index
+pub fn all_genres() -> &'static [&'static str] {
+    GENRES
+}
 
-    255
+// Pads `data` with NUL bytes or cuts it to exactly `new_size` bytes.
+fn resize(data: &[u8], new_size: usize) -> Vec<u8> {
+    let mut new_data = data.to_vec();
+    new_data.resize(new_size, 0);
+    new_data
 }
 
-fn resize(data: &Vec<u8>, new_size: usize) -> Vec<u8> {
-    let mut new_data = data.clone();
-    if data.len() >= new_size {
-        return new_data;
-    }
+// Cuts `s` so that it fits into a field of `size` bytes once rendered with
+// `handler`.  Characters are never split.
+fn truncate(s: &str, size: usize, handler: &dyn StringHandler) -> String {
+    let mut s = String::from(s);
 
-    let old_size = data.len();
-    for _ in 0..(new_size - old_size) {
-        new_data.push(0);
+    while handler.render(&s).len() > size {
+        s.pop();
     }
 
-    new_data
+    s
 }
 
-fn parse(s: String) -> String {
-    String::from(s.trim())
+// Like `truncate`, with the installed string handler.
+fn fit(s: Option<String>, size: usize) -> Option<String> {
+    let s = s.filter(|s| !s.is_empty())?;
+
+    Some(truncate(&s, size, string_handler().as_ref()))
+}
+
+// returns the value of a text field, or "" if it is not set
+fn text(s: &Option<String>) -> &str {
+    s.as_deref().unwrap_or_default()
+}
+
+// Renders a text field of exactly `size` bytes.
+fn render_field(s: &str, size: usize, handler: &dyn StringHandler) -> Vec<u8> {
+    let s = truncate(s, size, handler);
+
+    resize(&handler.render(&s), size)
+}
+
+// Parses the number at the start of `s`, e.g. the year of "2001-05-03" or the
+// track of "3/12".  Zero cannot be stored in ID3v1 and is None.
+fn leading_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+
+    s[..end].parse::<u32>().ok().filter(|&n| n > 0)
+}
+
+// Decodes a fixed-width field, which ends at the first NUL byte and may be
+// padded with spaces.
+fn parse(data: &[u8], handler: &dyn StringHandler) -> Option<String> {
+    parse_joined(data, None, handler)
+}
+
+// Like `parse`, for a field continued by `tail` in the extended block.
+fn parse_joined(data: &[u8], tail: Option<&[u8]>, handler: &dyn StringHandler) -> Option<String> {
+    let decode = |data: &[u8]| {
+        let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        handler.parse(&data[..end])
+    };
+
+    let mut s = decode(data);
+    if let Some(tail) = tail {
+        s.push_str(&decode(tail));
+    }
+
+    Some(String::from(s.trim())).filter(|s| !s.is_empty())
 }
 
 #[cfg(test)]
@@ -483,5 +938,323 @@ mod tests {
         let data = resize(&data, 8);
         assert_eq!(data, [b'H', b'e', b'l', b'l', b'o', 0, 0, 0]);
         assert_eq!(data.len(), 8);
+        assert_eq!(resize(&data, 3), [b'H', b'e', b'l']);
+    }
+
+    fn tag_data(fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut data = b"TAG".to_vec();
+        data.resize(128, 0);
+        for (offset, field) in fields {
+            data[*offset..*offset + field.len()].copy_from_slice(field);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = tag_data(&[
+            (3, b"Title\0garbage after nul"),
+            (33, b"Artist    "),
+            (63, b"Album"),
+            (93, b"2003"),
+            (97, b"Comment"),
+            (126, &[12]),
+            (127, &[8]),
+        ]);
+        let tag = ID3v1Tag::parse(&data).unwrap();
+
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(tag.album().as_deref(), Some("Album"));
+        assert_eq!(*tag.year(), Some(2003));
+        assert_eq!(tag.comment().as_deref(), Some("Comment"));
+        assert_eq!(*tag.track(), Some(12));
+        assert_eq!(tag.genre().as_deref(), Some("Jazz"));
+    }
+
+    #[test]
+    fn test_parse_latin1() {
+        let data = tag_data(&[(3, b"Caf\xe9 \xc0 la mode"), (33, b"\xff\xfe\x80")]);
+        let tag = ID3v1Tag::parse(&data).unwrap();
+
+        assert_eq!(tag.title().as_deref(), Some("Caf\u{e9} \u{c0} la mode"));
+        assert_eq!(tag.artist().as_deref(), Some("\u{ff}\u{fe}\u{80}"));
+    }
+
+    #[test]
+    fn test_string_handlers() {
+        let data = tag_data(&[(3, b"\xcf\xf0\xe8\xe2\xe5\xf2"), (33, b"\x80 Caf\xc3\xa9")]);
+
+        let tag = ID3v1Tag::parse_with(&data, &Windows1251StringHandler).unwrap();
+        assert_eq!(
+            tag.title().as_deref(),
+            Some("\u{41f}\u{440}\u{438}\u{432}\u{435}\u{442}")
+        );
+        assert_eq!(
+            Windows1251StringHandler.render("\u{41f}\u{440}\u{438}\u{432}\u{435}\u{442}"),
+            b"\xcf\xf0\xe8\xe2\xe5\xf2"
+        );
+
+        let tag = ID3v1Tag::parse_with(&data, &Windows1252StringHandler).unwrap();
+        assert_eq!(tag.artist().as_deref(), Some("\u{20ac} Caf\u{c3}\u{a9}"));
+        assert_eq!(
+            Windows1252StringHandler.render("\u{20ac}\u{e9}\u{3042}"),
+            b"\x80\xe9?"
+        );
+
+        let tag = ID3v1Tag::parse_with(&data, &Utf8StringHandler).unwrap();
+        assert_eq!(tag.artist().as_deref(), Some("\u{fffd} Caf\u{e9}"));
+
+        assert_eq!(Latin1StringHandler.render("\u{e9}\u{20ac}"), b"\xe9?");
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        // empty fields, an unset genre and no track
+        let tag = ID3v1Tag::parse(&tag_data(&[(127, &[255])])).unwrap();
+        assert!(tag.title().is_none());
+        assert!(tag.year().is_none());
+        assert!(tag.comment().is_none());
+        assert!(tag.track().is_none());
+        assert!(tag.genre().is_none());
+
+        // years which are not numbers
+        for year in [&b"    "[..], b"19xx", b"\xff\xff\xff\xff", b"0000", b"-199"] {
+            let tag = ID3v1Tag::parse(&tag_data(&[(93, year)])).unwrap();
+            assert!(tag.year().is_none());
+        }
+
+        // a 30 byte comment leaves no room for a track number
+        let tag = ID3v1Tag::parse(&tag_data(&[(97, &[b'x'; 30])])).unwrap();
+        assert_eq!(tag.comment().as_deref(), Some("x".repeat(30).as_str()));
+        assert!(tag.track().is_none());
+
+        // random bytes after a valid identifier
+        let mut data = b"TAG".to_vec();
+        data.extend((0..125u32).map(|i| (i * 97 + 13) as u8));
+        assert!(ID3v1Tag::parse(&data).is_ok());
+
+        assert!(matches!(ID3v1Tag::parse(b"TAG"), Err(Error::NoTagFound)));
+        assert!(matches!(
+            ID3v1Tag::parse(&[0u8; 128]),
+            Err(Error::NoTagFound)
+        ));
+    }
+
+    #[test]
+    fn test_properties() {
+        let data = tag_data(&[(3, b"Title"), (93, b"2001"), (126, &[7]), (127, &[17])]);
+        let mut tag = ID3v1Tag::parse(&data).unwrap();
+        assert!(!tag.is_empty());
+
+        let properties = tag.properties();
+        assert_eq!(properties.len(), 4);
+        assert_eq!(properties["TITLE"], ["Title"]);
+        assert_eq!(properties["DATE"], ["2001"]);
+        assert_eq!(properties["TRACKNUMBER"], ["7"]);
+        assert_eq!(properties["GENRE"], ["Rock"]);
+
+        assert!(tag.set_properties(PropertyMap::new()).is_empty());
+        assert!(tag.is_empty());
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let mut tag = ID3v1Tag::parse(&tag_data(&[(127, &[255])])).unwrap();
+        assert!(tag.is_empty());
+
+        let mut properties = PropertyMap::new();
+        let mut insert = |key: &str, values: &[&str]| {
+            let values = values.iter().map(|v| String::from(*v)).collect();
+            properties.insert(String::from(key), values);
+        };
+        insert("TITLE", &["A title which is longer than thirty bytes"]);
+        insert("ARTIST", &["Artist", "Second artist"]);
+        insert("DATE", &["2001-05-03"]);
+        insert("TRACKNUMBER", &["3/12"]);
+        insert("GENRE", &["Not a genre"]);
+        insert("COMPOSER", &["Composer"]);
+        insert("ALBUM", &[]);
+
+        let unsupported = tag.set_properties(properties);
+
+        assert_eq!(
+            tag.title().as_deref(),
+            Some("A title which is longer than t")
+        );
+        assert_eq!(tag.artist().as_deref(), Some("Artist"));
+        assert!(tag.album().is_none());
+        assert_eq!(*tag.year(), Some(2001));
+        assert_eq!(*tag.track(), Some(3));
+        assert!(tag.genre().is_none());
+
+        assert_eq!(unsupported.len(), 3);
+        assert_eq!(unsupported["ARTIST"], ["Second artist"]);
+        assert_eq!(unsupported["GENRE"], ["Not a genre"]);
+        assert_eq!(unsupported["COMPOSER"], ["Composer"]);
+
+        // fixed-up genre names map to their canonical spelling
+        tag.set_genre(Some(String::from("Jazz+Funk")));
+        assert_eq!(tag.genre().as_deref(), Some("Jazz-Funk"));
+
+        // multi-byte characters are not split
+        tag.set_title(Some("\u{e9}".repeat(40)));
+        assert_eq!(tag.title().as_ref().map(|t| t.chars().count()), Some(30));
+    }
+
+    // returns the tag read back from its rendering
+    fn round_trip(tag: &ID3v1Tag) -> ID3v1Tag {
+        let data = tag.render();
+        assert_eq!(data.len(), 128);
+
+        ID3v1Tag::new(&mut std::io::Cursor::new(data), 0).unwrap()
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        let mut tag = ID3v1Tag::empty();
+        assert_eq!(round_trip(&tag), tag);
+
+        tag.set_title(Some(String::from("Title")));
+        tag.set_artist(Some(String::from("Caf\u{e9}")));
+        tag.set_year(Some(1999));
+        tag.set_comment(Some("c".repeat(30)));
+        tag.set_genre(Some(String::from("Rock")));
+        assert_eq!(round_trip(&tag), tag);
+
+        // without a track number the whole comment is kept (ID3v1.0)
+        let data = tag.render();
+        assert_eq!(data[97..127], *"c".repeat(30).as_bytes());
+        assert_eq!(data[127], 17);
+
+        tag.set_comment(Some(String::from("Comment")));
+        tag.set_track(Some(12));
+        assert_eq!(round_trip(&tag), tag);
+
+        let data = tag.render_with(&Utf8StringHandler);
+        assert_eq!(
+            ID3v1Tag::parse_with(&data, &Utf8StringHandler).unwrap(),
+            tag
+        );
+
+        let data = tag.render();
+        assert_eq!(data[125..], [0, 12, 17]);
+    }
+
+    #[test]
+    fn test_render_truncates() {
+        let data = tag_data(&[(3, &[b'x'; 30]), (97, &[b'y'; 30])]);
+        let mut tag = ID3v1Tag::parse(&data).unwrap();
+        tag.set_track(Some(1));
+
+        let data = tag.render();
+        assert_eq!(data.len(), 128);
+        let tag = ID3v1Tag::parse(&data).unwrap();
+        assert_eq!(tag.comment().as_deref(), Some("y".repeat(28).as_str()));
+        assert_eq!(*tag.track(), Some(1));
+
+        // multi-byte characters are cut as a whole
+        let mut tag = ID3v1Tag::empty();
+        tag.d.title = Some(format!("x{}", "\u{e9}".repeat(20)));
+        tag.d.year = Some(123456);
+
+        let data = tag.render_with(&Utf8StringHandler);
+        assert_eq!(data.len(), 128);
+        let tag = ID3v1Tag::parse_with(&data, &Utf8StringHandler).unwrap();
+        assert_eq!(
+            tag.title().as_deref(),
+            Some(format!("x{}", "\u{e9}".repeat(14)).as_str())
+        );
+        assert!(tag.year().is_none());
+    }
+
+    #[test]
+    fn test_genres() {
+        assert_eq!(all_genres().len(), 192);
+        assert_eq!(genre_name(0), Some("Blues"));
+        assert_eq!(genre_name(191), Some("Psybient"));
+        assert_eq!(genre_name(192), None);
+
+        assert_eq!(genre_number("Rock"), Some(17));
+        assert_eq!(genre_number("hip-hop"), Some(7));
+        assert_eq!(genre_number("BRITPOP"), Some(132));
+        assert_eq!(genre_number("(17)"), Some(17));
+        assert_eq!(genre_number("144"), Some(144));
+        assert_eq!(genre_number("(200)"), None);
+        assert_eq!(genre_number("Shoegazing"), None);
+
+        let mut tag = ID3v1Tag::empty();
+        tag.set_genre(Some(String::from("heavy metal")));
+        assert_eq!(tag.genre().as_deref(), Some("Heavy Metal"));
+        assert_eq!(tag.genre_number(), Some(137));
+
+        tag.set_genre_number(Some(300));
+        assert!(tag.genre().is_none());
+        assert_eq!(tag.genre_number(), None);
+    }
+
+    fn extended_data(fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut data = b"TAG+".to_vec();
+        data.resize(EXTENDED_TAG_SIZE, 0);
+        for (offset, field) in fields {
+            data[*offset..*offset + field.len()].copy_from_slice(field);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_extended() {
+        let title = "A title which is longer than thirty characters";
+        let mut data = extended_data(&[
+            (4, &title.as_bytes()[30..]),
+            (64, b"Artist"),
+            (184, &[3]),
+            (185, b"Shoegazing"),
+            (215, b"001:30"),
+            (221, b"004:05"),
+        ]);
+        data.extend(tag_data(&[(3, &title.as_bytes()[..30]), (127, &[17])]));
+
+        let tag = ID3v1Tag::parse(&data).unwrap();
+        assert!(tag.is_extended());
+        assert_eq!(tag.title().as_deref(), Some(title));
+        assert_eq!(tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(tag.genre().as_deref(), Some("Shoegazing"));
+        assert_eq!(tag.speed(), Some(3));
+        assert_eq!(tag.start_time().as_deref(), Some("001:30"));
+        assert_eq!(tag.end_time().as_deref(), Some("004:05"));
+
+        let mut rendered = tag.render_extended().unwrap();
+        assert_eq!(rendered.len(), EXTENDED_TAG_SIZE);
+        rendered.extend(tag.render());
+        assert_eq!(ID3v1Tag::parse(&rendered).unwrap(), tag);
+
+        // a block which is only preceded by a plain tag is ignored
+        let mut cursor = std::io::Cursor::new(data[EXTENDED_TAG_SIZE..].to_vec());
+        assert!(!ID3v1Tag::new(&mut cursor, 0).unwrap().is_extended());
+    }
+
+    #[test]
+    fn test_set_extended() {
+        let title = "x".repeat(100);
+
+        let mut tag = ID3v1Tag::empty();
+        tag.set_title(Some(title.clone()));
+        tag.set_speed(Some(1));
+        assert_eq!(tag.title().as_ref().map(String::len), Some(30));
+        assert!(tag.speed().is_none());
+        assert!(tag.render_extended().is_none());
+
+        tag.set_extended(true);
+        tag.set_title(Some(title));
+        tag.set_genre(Some(String::from("Shoegazing")));
+        assert_eq!(tag.title().as_ref().map(String::len), Some(90));
+        assert_eq!(tag.genre().as_deref(), Some("Shoegazing"));
+        assert!(tag.render_extended().is_some());
+
+        tag.set_extended(false);
+        assert_eq!(tag.title().as_ref().map(String::len), Some(30));
+        assert!(tag.genre().is_none());
+        assert!(tag.render_extended().is_none());
     }
 }
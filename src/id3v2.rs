@@ -0,0 +1,1612 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::{
+    error::{Error, Result},
+    id3v1,
+    tag::{PropertyMap, Tag},
+    utils::{read_block, take},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ID3v2Header {
+    major_version: u8,
+    revision_number: u8,
+    unsynchronisation: bool,
+    extended_header: bool,
+    experimental_indicator: bool,
+    footer_present: bool,
+    tag_size: u32,
+}
+
+impl ID3v2Header {
+    // the size of the header (and of the footer) in bytes
+    pub const SIZE: usize = 10;
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::SIZE || &data[0..3] != b"ID3" {
+            return Err(Error::NoTagFound);
+        }
+
+        // The version and revision bytes are never 0xff.
+        if data[3] == 0xff || data[4] == 0xff {
+            return Err(Error::InvalidHeader);
+        }
+
+        if !(2..=4).contains(&data[3]) {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        // The tag size is a 28 bit synchsafe integer, so the most significant bit of
+        // every byte must be zero.
+        let size_data = &data[6..10];
+        if size_data.iter().any(|b| b & 0x80 != 0) {
+            return Err(Error::InvalidHeader);
+        }
+
+        let flags = data[5];
+
+        Ok(Self {
+            major_version: data[3],
+            revision_number: data[4],
+            unsynchronisation: (flags & 0x80) != 0,
+            extended_header: (flags & 0x40) != 0,
+            experimental_indicator: (flags & 0x20) != 0,
+            footer_present: (flags & 0x10) != 0,
+            tag_size: synch_data_to_u32(size_data),
+        })
+    }
+
+    // returns the major version, 2, 3 or 4 for ID3v2.2, ID3v2.3 and ID3v2.4
+    pub fn major_version(&self) -> u8 {
+        self.major_version
+    }
+
+    pub fn revision_number(&self) -> u8 {
+        self.revision_number
+    }
+
+    // returns true if the whole tag is unsynchronised (ID3v2.3 and earlier)
+    pub fn unsynchronisation(&self) -> bool {
+        self.unsynchronisation
+    }
+
+    pub fn extended_header(&self) -> bool {
+        self.extended_header
+    }
+
+    pub fn experimental_indicator(&self) -> bool {
+        self.experimental_indicator
+    }
+
+    pub fn footer_present(&self) -> bool {
+        self.footer_present
+    }
+
+    // returns the size of the frames and the padding, excluding header and footer
+    pub fn tag_size(&self) -> u32 {
+        self.tag_size
+    }
+
+    // returns the size of the tag including header and footer
+    pub fn complete_tag_size(&self) -> u32 {
+        let footer_size = if self.footer_present { Self::SIZE } else { 0 };
+
+        self.tag_size + (Self::SIZE + footer_size) as u32
+    }
+}
+
+// Decodes a 28 bit integer stored in 4 bytes of 7 bits each.
+pub(crate) fn synch_data_to_u32(data: &[u8]) -> u32 {
+    data.iter()
+        .take(4)
+        .fold(0u32, |value, &b| (value << 7) | (b & 0x7f) as u32)
+}
+
+// Encodes `value` as a 28 bit integer stored in 4 bytes of 7 bits each.
+pub(crate) fn u32_to_synch_data(value: u32) -> [u8; 4] {
+    [
+        ((value >> 21) & 0x7f) as u8,
+        ((value >> 14) & 0x7f) as u8,
+        ((value >> 7) & 0x7f) as u8,
+        (value & 0x7f) as u8,
+    ]
+}
+
+// Reverses the unsynchronisation scheme, which inserts a zero byte after every
+// 0xff byte.
+fn decode_unsynchronisation(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+
+    let mut previous = 0u8;
+    for &b in data {
+        if !(previous == 0xff && b == 0x00) {
+            decoded.push(b);
+        }
+        previous = b;
+    }
+
+    decoded
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum TextEncoding {
+    // ISO-8859-1
+    Latin1 = 0,
+    // UTF-16 with byte order mark
+    Utf16 = 1,
+    // UTF-16 big endian, without byte order mark (ID3v2.4 only)
+    Utf16Be = 2,
+    // UTF-8 (ID3v2.4 only)
+    Utf8 = 3,
+}
+
+impl TextEncoding {
+    fn terminator_size(&self) -> usize {
+        match self {
+            TextEncoding::Utf16 | TextEncoding::Utf16Be => 2,
+            _ => 1,
+        }
+    }
+
+    // Returns an encoding able to represent all of `strings`.  Text that does not
+    // fit into Latin-1 is written as UTF-8.
+    fn fit<'a>(self, strings: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut strings = strings.into_iter();
+
+        if self == TextEncoding::Latin1 && strings.any(|s| s.chars().any(|c| c as u32 > 0xff)) {
+            TextEncoding::Utf8
+        } else {
+            self
+        }
+    }
+}
+
+fn decode_text(data: &[u8], encoding: TextEncoding) -> String {
+    let decode_utf16 = |data: &[u8], little_endian: bool| {
+        let units: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|c| {
+                if little_endian {
+                    u16::from_le_bytes([c[0], c[1]])
+                } else {
+                    u16::from_be_bytes([c[0], c[1]])
+                }
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+
+    let text = match encoding {
+        TextEncoding::Latin1 => data.iter().map(|&b| b as char).collect(),
+        TextEncoding::Utf8 => String::from_utf8_lossy(data).into_owned(),
+        TextEncoding::Utf16Be => decode_utf16(data, false),
+        TextEncoding::Utf16 => match data {
+            [0xff, 0xfe, rest @ ..] => decode_utf16(rest, true),
+            [0xfe, 0xff, rest @ ..] => decode_utf16(rest, false),
+            // Without a byte order mark, assume big endian.
+            _ => decode_utf16(data, false),
+        },
+    };
+
+    text.trim_end_matches('\0').to_string()
+}
+
+fn encode_text(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    match encoding {
+        TextEncoding::Latin1 => text.chars().map(|c| c as u32 as u8).collect(),
+        TextEncoding::Utf8 => text.as_bytes().to_vec(),
+        TextEncoding::Utf16Be => text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect(),
+        TextEncoding::Utf16 => {
+            let mut data = vec![0xff, 0xfe];
+            data.extend(text.encode_utf16().flat_map(|u| u.to_le_bytes()));
+            data
+        }
+    }
+}
+
+// Encodes `text` followed by a terminator.
+fn encode_terminated(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    let mut data = encode_text(text, encoding);
+    data.resize(data.len() + encoding.terminator_size(), 0);
+    data
+}
+
+// Returns the offset of the first string terminator in `data`.
+fn find_terminator(data: &[u8], encoding: TextEncoding) -> Option<usize> {
+    match encoding.terminator_size() {
+        1 => data.iter().position(|&b| b == 0),
+        _ => data
+            .chunks_exact(2)
+            .position(|c| c[0] == 0 && c[1] == 0)
+            .map(|i| i * 2),
+    }
+}
+
+// Reads a terminated string from the start of `data` and returns it along with
+// the data following the terminator.
+fn read_terminated(data: &[u8], encoding: TextEncoding) -> (String, &[u8]) {
+    match find_terminator(data, encoding) {
+        Some(end) => (
+            decode_text(&data[..end], encoding),
+            &data[end + encoding.terminator_size()..],
+        ),
+        None => (decode_text(data, encoding), &[]),
+    }
+}
+
+// Splits `data` into its terminator separated strings.
+fn split_text(mut data: &[u8], encoding: TextEncoding) -> Vec<String> {
+    let mut values = vec![];
+
+    while !data.is_empty() {
+        let (value, rest) = read_terminated(data, encoding);
+        values.push(value);
+        data = rest;
+    }
+
+    // A single trailing terminator does not start a new value.
+    if values.len() > 1 && values.last().is_some_and(|v| v.is_empty()) {
+        values.pop();
+    }
+
+    values
+}
+
+// Returns the body and the ID3v2.4 format flags of a frame that is kept
+// verbatim, or None if the frame is too short for its flags.  ID3v2.4 frames
+// keep their own flags, while the additional data of an ID3v2.3 frame is moved
+// into ID3v2.4 order: group, encryption method, then data length.
+fn opaque_frame_body(
+    data: &[u8],
+    major_version: u8,
+    flags: u16,
+    grouping: bool,
+    compressed: bool,
+    encrypted: bool,
+) -> Option<(Vec<u8>, u8)> {
+    if major_version >= 4 {
+        return Some((data.to_vec(), flags as u8));
+    }
+
+    let mut pos = 0;
+    let data_length = if compressed {
+        Some(u32::from_be_bytes(
+            take(data, &mut pos, 4).ok()?.try_into().unwrap(),
+        ))
+    } else {
+        None
+    };
+    let method = if encrypted {
+        take(data, &mut pos, 1).ok()?
+    } else {
+        &[]
+    };
+    let group = if grouping {
+        take(data, &mut pos, 1).ok()?
+    } else {
+        &[]
+    };
+
+    let mut body = group.to_vec();
+    body.extend(method);
+    let mut format_flags = 0;
+    if grouping {
+        format_flags |= 0x40;
+    }
+    if encrypted {
+        format_flags |= 0x04;
+    }
+    if let Some(length) = data_length {
+        body.extend(u32_to_synch_data(length));
+        format_flags |= 0x08 | 0x01;
+    }
+    body.extend(&data[pos..]);
+
+    Some((body, format_flags))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ID3v2FrameContent {
+    // text information frames (T***), except TXXX
+    Text {
+        encoding: TextEncoding,
+        values: Vec<String>,
+    },
+    // user defined text information frame (TXXX)
+    UserText {
+        encoding: TextEncoding,
+        description: String,
+        values: Vec<String>,
+    },
+    // comment (COMM) and unsynchronised lyrics (USLT) frames
+    Comment {
+        encoding: TextEncoding,
+        language: [u8; 3],
+        description: String,
+        text: String,
+    },
+    // URL link frames (W***), except WXXX
+    Url(String),
+    // user defined URL link frame (WXXX)
+    UserUrl {
+        encoding: TextEncoding,
+        description: String,
+        url: String,
+    },
+    // attached picture frame (APIC)
+    Picture {
+        encoding: TextEncoding,
+        mime_type: String,
+        picture_type: u8,
+        description: String,
+        data: Vec<u8>,
+    },
+    // any other frame, kept verbatim
+    Unknown(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ID3v2Frame {
+    id: String,
+    content: ID3v2FrameContent,
+    // the ID3v2.4 format flags of a compressed or encrypted frame, whose
+    // content is kept verbatim
+    format_flags: u8,
+}
+
+impl ID3v2Frame {
+    pub fn new(id: &str, content: ID3v2FrameContent) -> Self {
+        Self {
+            id: String::from(id),
+            content,
+            format_flags: 0,
+        }
+    }
+
+    // returns the four character ID3v2.4 frame ID
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn content(&self) -> &ID3v2FrameContent {
+        &self.content
+    }
+
+    pub fn content_mut(&mut self) -> &mut ID3v2FrameContent {
+        &mut self.content
+    }
+
+    // Parses the frame body.  Frames that cannot be understood are kept as
+    // unknown frames.
+    fn parse(id: &str, data: &[u8], major_version: u8) -> Self {
+        let content = Self::parse_content(id, data, major_version)
+            .unwrap_or_else(|| ID3v2FrameContent::Unknown(data.to_vec()));
+
+        Self::new(id, content)
+    }
+
+    fn parse_content(id: &str, data: &[u8], major_version: u8) -> Option<ID3v2FrameContent> {
+        let is_text = id.starts_with('T') || matches!(id, "COMM" | "USLT" | "WXXX" | "APIC");
+
+        let (encoding, data) = if is_text {
+            let (&encoding, rest) = data.split_first()?;
+            (TextEncoding::try_from(encoding).ok()?, rest)
+        } else {
+            (TextEncoding::Latin1, data)
+        };
+
+        let content = match id {
+            "TXXX" => {
+                let (description, rest) = read_terminated(data, encoding);
+                ID3v2FrameContent::UserText {
+                    encoding,
+                    description,
+                    values: split_text(rest, encoding),
+                }
+            }
+            _ if id.starts_with('T') => ID3v2FrameContent::Text {
+                encoding,
+                values: split_text(data, encoding),
+            },
+            "COMM" | "USLT" => {
+                if data.len() < 3 {
+                    return None;
+                }
+
+                let (description, rest) = read_terminated(&data[3..], encoding);
+                ID3v2FrameContent::Comment {
+                    encoding,
+                    language: data[0..3].try_into().ok()?,
+                    description,
+                    text: decode_text(rest, encoding),
+                }
+            }
+            "WXXX" => {
+                let (description, rest) = read_terminated(data, encoding);
+                ID3v2FrameContent::UserUrl {
+                    encoding,
+                    description,
+                    url: read_terminated(rest, TextEncoding::Latin1).0,
+                }
+            }
+            _ if id.starts_with('W') => {
+                ID3v2FrameContent::Url(read_terminated(data, TextEncoding::Latin1).0)
+            }
+            "APIC" => {
+                // ID3v2.2 uses a three character image format instead of a MIME type.
+                let (mime_type, rest) = if major_version == 2 {
+                    if data.len() < 3 {
+                        return None;
+                    }
+
+                    let format = decode_text(&data[0..3], TextEncoding::Latin1);
+                    let mime_type = match format.to_uppercase().as_str() {
+                        "JPG" => String::from("image/jpeg"),
+                        "PNG" => String::from("image/png"),
+                        _ => format,
+                    };
+                    (mime_type, &data[3..])
+                } else {
+                    read_terminated(data, TextEncoding::Latin1)
+                };
+
+                let (&picture_type, rest) = rest.split_first()?;
+                let (description, rest) = read_terminated(rest, encoding);
+
+                ID3v2FrameContent::Picture {
+                    encoding,
+                    mime_type,
+                    picture_type,
+                    description,
+                    data: rest.to_vec(),
+                }
+            }
+            _ => ID3v2FrameContent::Unknown(data.to_vec()),
+        };
+
+        Some(content)
+    }
+
+    // renders the frame, including its ID3v2.4 frame header
+    pub fn render(&self) -> Vec<u8> {
+        let body = self.render_content();
+
+        let mut data = self.id.as_bytes().to_vec();
+        data.extend(u32_to_synch_data(body.len() as u32));
+        data.extend([0, self.format_flags]);
+        data.extend(body);
+        data
+    }
+
+    fn render_content(&self) -> Vec<u8> {
+        let mut data = vec![];
+
+        match &self.content {
+            ID3v2FrameContent::Text { encoding, values } => {
+                let encoding = encoding.fit(values.iter().map(|v| v.as_str()));
+                data.push(encoding.into());
+
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        data.resize(data.len() + encoding.terminator_size(), 0);
+                    }
+                    data.extend(encode_text(value, encoding));
+                }
+            }
+            ID3v2FrameContent::UserText {
+                encoding,
+                description,
+                values,
+            } => {
+                let encoding = encoding.fit(
+                    values
+                        .iter()
+                        .map(|v| v.as_str())
+                        .chain([description.as_str()]),
+                );
+                data.push(encoding.into());
+                data.extend(encode_terminated(description, encoding));
+
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        data.resize(data.len() + encoding.terminator_size(), 0);
+                    }
+                    data.extend(encode_text(value, encoding));
+                }
+            }
+            ID3v2FrameContent::Comment {
+                encoding,
+                language,
+                description,
+                text,
+            } => {
+                let encoding = encoding.fit([description.as_str(), text.as_str()]);
+                data.push(encoding.into());
+                data.extend(language);
+                data.extend(encode_terminated(description, encoding));
+                data.extend(encode_text(text, encoding));
+            }
+            ID3v2FrameContent::Url(url) => {
+                data.extend(encode_text(url, TextEncoding::Latin1));
+            }
+            ID3v2FrameContent::UserUrl {
+                encoding,
+                description,
+                url,
+            } => {
+                let encoding = encoding.fit([description.as_str()]);
+                data.push(encoding.into());
+                data.extend(encode_terminated(description, encoding));
+                data.extend(encode_text(url, TextEncoding::Latin1));
+            }
+            ID3v2FrameContent::Picture {
+                encoding,
+                mime_type,
+                picture_type,
+                description,
+                data: picture,
+            } => {
+                let encoding = encoding.fit([description.as_str()]);
+                data.push(encoding.into());
+                data.extend(encode_terminated(mime_type, TextEncoding::Latin1));
+                data.push(*picture_type);
+                data.extend(encode_terminated(description, encoding));
+                data.extend(picture);
+            }
+            ID3v2FrameContent::Unknown(body) => data.extend(body),
+        }
+
+        data
+    }
+
+    // Returns the property this frame maps to, if any.
+    fn property(&self) -> Option<(String, Vec<String>)> {
+        match &self.content {
+            ID3v2FrameContent::Text { values, .. } => {
+                let key = frame_id_to_key(&self.id)?;
+
+                let values = if self.id == "TCON" {
+                    values.iter().flat_map(|v| parse_genre(v)).collect()
+                } else {
+                    values.clone()
+                };
+
+                Some((String::from(key), values))
+            }
+            ID3v2FrameContent::UserText {
+                description,
+                values,
+                ..
+            } if !description.is_empty() => Some((description.to_uppercase(), values.clone())),
+            ID3v2FrameContent::Comment {
+                description, text, ..
+            } => {
+                let key = if self.id == "COMM" {
+                    "COMMENT"
+                } else {
+                    "LYRICS"
+                };
+
+                if description.is_empty() {
+                    Some((String::from(key), vec![text.clone()]))
+                } else {
+                    Some((
+                        format!("{}:{}", key, description.to_uppercase()),
+                        vec![text.clone()],
+                    ))
+                }
+            }
+            ID3v2FrameContent::Url(url) => {
+                let key = frame_id_to_key(&self.id)?;
+                Some((String::from(key), vec![url.clone()]))
+            }
+            ID3v2FrameContent::UserUrl {
+                description, url, ..
+            } => {
+                if description.is_empty() {
+                    Some((String::from("URL"), vec![url.clone()]))
+                } else {
+                    Some((
+                        format!("URL:{}", description.to_uppercase()),
+                        vec![url.clone()],
+                    ))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Creates the frames storing the property `key`.
+    fn from_property(key: &str, values: &[String]) -> Vec<Self> {
+        let (prefix, description) = match key.split_once(':') {
+            Some((prefix, description)) => (prefix, description),
+            None => (key, ""),
+        };
+
+        match prefix {
+            "COMMENT" | "LYRICS" => {
+                let id = if prefix == "COMMENT" { "COMM" } else { "USLT" };
+
+                values
+                    .iter()
+                    .map(|text| {
+                        Self::new(
+                            id,
+                            ID3v2FrameContent::Comment {
+                                encoding: TextEncoding::Utf8,
+                                language: *b"XXX",
+                                description: String::from(description),
+                                text: text.clone(),
+                            },
+                        )
+                    })
+                    .collect()
+            }
+            "URL" => values
+                .iter()
+                .map(|url| {
+                    Self::new(
+                        "WXXX",
+                        ID3v2FrameContent::UserUrl {
+                            encoding: TextEncoding::Utf8,
+                            description: String::from(description),
+                            url: url.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            _ => match key_to_frame_id(key) {
+                Some(id) if id.starts_with('W') => values
+                    .iter()
+                    .map(|url| Self::new(id, ID3v2FrameContent::Url(url.clone())))
+                    .collect(),
+                Some(id) => vec![Self::new(
+                    id,
+                    ID3v2FrameContent::Text {
+                        encoding: TextEncoding::Utf8,
+                        values: values.to_vec(),
+                    },
+                )],
+                None => vec![Self::new(
+                    "TXXX",
+                    ID3v2FrameContent::UserText {
+                        encoding: TextEncoding::Utf8,
+                        description: String::from(key),
+                        values: values.to_vec(),
+                    },
+                )],
+            },
+        }
+    }
+}
+
+const FRAME_KEYS: &[(&str, &str)] = &[
+    ("TALB", "ALBUM"),
+    ("TBPM", "BPM"),
+    ("TCMP", "COMPILATION"),
+    ("TCOM", "COMPOSER"),
+    ("TCON", "GENRE"),
+    ("TCOP", "COPYRIGHT"),
+    ("TDEN", "ENCODINGTIME"),
+    ("TDLY", "PLAYLISTDELAY"),
+    ("TDOR", "ORIGINALDATE"),
+    ("TDRC", "DATE"),
+    ("TDRL", "RELEASEDATE"),
+    ("TDTG", "TAGGINGDATE"),
+    ("TENC", "ENCODEDBY"),
+    ("TEXT", "LYRICIST"),
+    ("TFLT", "FILETYPE"),
+    ("TIT1", "CONTENTGROUP"),
+    ("TIT2", "TITLE"),
+    ("TIT3", "SUBTITLE"),
+    ("TKEY", "INITIALKEY"),
+    ("TLAN", "LANGUAGE"),
+    ("TLEN", "LENGTH"),
+    ("TMED", "MEDIA"),
+    ("TMOO", "MOOD"),
+    ("TOAL", "ORIGINALALBUM"),
+    ("TOFN", "ORIGINALFILENAME"),
+    ("TOLY", "ORIGINALLYRICIST"),
+    ("TOPE", "ORIGINALARTIST"),
+    ("TOWN", "OWNER"),
+    ("TPE1", "ARTIST"),
+    ("TPE2", "ALBUMARTIST"),
+    ("TPE3", "CONDUCTOR"),
+    ("TPE4", "REMIXER"),
+    ("TPOS", "DISCNUMBER"),
+    ("TPRO", "PRODUCEDNOTICE"),
+    ("TPUB", "LABEL"),
+    ("TRCK", "TRACKNUMBER"),
+    ("TRSN", "RADIOSTATION"),
+    ("TRSO", "RADIOSTATIONOWNER"),
+    ("TSO2", "ALBUMARTISTSORT"),
+    ("TSOA", "ALBUMSORT"),
+    ("TSOC", "COMPOSERSORT"),
+    ("TSOP", "ARTISTSORT"),
+    ("TSOT", "TITLESORT"),
+    ("TSRC", "ISRC"),
+    ("TSSE", "ENCODING"),
+    ("WCOP", "COPYRIGHTURL"),
+    ("WOAF", "FILEWEBPAGE"),
+    ("WOAR", "ARTISTWEBPAGE"),
+    ("WOAS", "AUDIOSOURCEWEBPAGE"),
+    ("WORS", "RADIOSTATIONWEBPAGE"),
+    ("WPAY", "PAYMENTWEBPAGE"),
+    ("WPUB", "PUBLISHERWEBPAGE"),
+];
+
+fn frame_id_to_key(id: &str) -> Option<&'static str> {
+    FRAME_KEYS
+        .iter()
+        .find(|(frame_id, _)| *frame_id == id)
+        .map(|(_, key)| *key)
+}
+
+fn key_to_frame_id(key: &str) -> Option<&'static str> {
+    FRAME_KEYS
+        .iter()
+        .find(|(_, k)| *k == key)
+        .map(|(frame_id, _)| *frame_id)
+}
+
+// ID3v2.2 frame IDs and their ID3v2.4 counterparts.  ID3v2.2 frames that are
+// not listed have no equivalent and are dropped.
+const FRAME_ID_V2_TO_V4: &[(&str, &str)] = &[
+    ("BUF", "RBUF"),
+    ("CNT", "PCNT"),
+    ("COM", "COMM"),
+    ("CRA", "AENC"),
+    ("ETC", "ETCO"),
+    ("GEO", "GEOB"),
+    ("IPL", "TIPL"),
+    ("MCI", "MCDI"),
+    ("MLL", "MLLT"),
+    ("PIC", "APIC"),
+    ("POP", "POPM"),
+    ("REV", "RVRB"),
+    ("SLT", "SYLT"),
+    ("STC", "SYTC"),
+    ("TAL", "TALB"),
+    ("TBP", "TBPM"),
+    ("TCM", "TCOM"),
+    ("TCO", "TCON"),
+    ("TCP", "TCMP"),
+    ("TCR", "TCOP"),
+    ("TDA", "TDAT"),
+    ("TDY", "TDLY"),
+    ("TEN", "TENC"),
+    ("TFT", "TFLT"),
+    ("TIM", "TIME"),
+    ("TKE", "TKEY"),
+    ("TLA", "TLAN"),
+    ("TLE", "TLEN"),
+    ("TMT", "TMED"),
+    ("TOA", "TOPE"),
+    ("TOF", "TOFN"),
+    ("TOL", "TOLY"),
+    ("TOR", "TDOR"),
+    ("TOT", "TOAL"),
+    ("TP1", "TPE1"),
+    ("TP2", "TPE2"),
+    ("TP3", "TPE3"),
+    ("TP4", "TPE4"),
+    ("TPA", "TPOS"),
+    ("TPB", "TPUB"),
+    ("TRC", "TSRC"),
+    ("TRK", "TRCK"),
+    ("TS2", "TSO2"),
+    ("TSA", "TSOA"),
+    ("TSC", "TSOC"),
+    ("TSP", "TSOP"),
+    ("TSS", "TSSE"),
+    ("TST", "TSOT"),
+    ("TT1", "TIT1"),
+    ("TT2", "TIT2"),
+    ("TT3", "TIT3"),
+    ("TXT", "TEXT"),
+    ("TXX", "TXXX"),
+    ("TYE", "TDRC"),
+    ("UFI", "UFID"),
+    ("ULT", "USLT"),
+    ("WAF", "WOAF"),
+    ("WAR", "WOAR"),
+    ("WAS", "WOAS"),
+    ("WCM", "WCOM"),
+    ("WCP", "WCOP"),
+    ("WPB", "WPUB"),
+    ("WXX", "WXXX"),
+];
+
+// Converts a frame ID of the given major version to its ID3v2.4 equivalent.
+// Returns None for frames which cannot be represented in ID3v2.4.
+fn convert_frame_id(id: &str, major_version: u8) -> Option<&str> {
+    match major_version {
+        2 => FRAME_ID_V2_TO_V4
+            .iter()
+            .find(|(v2, _)| *v2 == id)
+            .map(|(_, v4)| *v4),
+        3 => match id {
+            "TORY" => Some("TDOR"),
+            "TYER" => Some("TDRC"),
+            "IPLS" => Some("TIPL"),
+            "EQUA" | "RVAD" | "TRDA" | "TSIZ" => None,
+            _ => Some(id),
+        },
+        _ => Some(id),
+    }
+}
+
+// Resolves a TCON value into genre names.  ID3v2.3 allows references to ID3v1
+// genres like "(17)" or "(4)Eurodisco", ID3v2.4 stores them as plain numbers.
+fn parse_genre(value: &str) -> Vec<String> {
+    let mut genres = vec![];
+    let mut s = value;
+
+    while let Some(rest) = s.strip_prefix('(') {
+        // "((" escapes a refinement starting with a parenthesis.
+        if rest.starts_with('(') {
+            s = rest;
+            break;
+        }
+
+        let Some(end) = rest.find(')') else {
+            break;
+        };
+
+        let code = &rest[..end];
+        s = &rest[end + 1..];
+
+        match code {
+            "RX" => genres.push(String::from("Remix")),
+            "CR" => genres.push(String::from("Cover")),
+            _ => {
+                if let Some(name) = id3v1::genre_number(code).and_then(id3v1::genre_name) {
+                    // "(17)Rock" repeats the genre name as refinement.
+                    if !name.eq_ignore_ascii_case(s) {
+                        genres.push(String::from(name));
+                    }
+                }
+            }
+        }
+    }
+
+    if !s.is_empty() {
+        let is_number = s.bytes().all(|b| b.is_ascii_digit());
+        match id3v1::genre_number(s).and_then(id3v1::genre_name) {
+            Some(name) if is_number => genres.push(String::from(name)),
+            _ => genres.push(String::from(s)),
+        }
+    }
+
+    genres
+}
+
+#[derive(Clone)]
+pub(crate) struct ID3v2TagPrivate {
+    header: ID3v2Header,
+    frames: Vec<ID3v2Frame>,
+    // the values below are derived from the frames and kept in sync with them
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    comment: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+    track: Option<u32>,
+}
+
+#[derive(Clone)]
+pub struct ID3v2Tag {
+    d: ID3v2TagPrivate,
+}
+
+impl ID3v2Tag {
+    pub fn new<R: Read + Seek>(file: &mut R, tag_offset: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(tag_offset))?;
+
+        let header = ID3v2Header::parse(&read_block(file, ID3v2Header::SIZE)?)?;
+        let data = read_block(file, header.tag_size() as usize)?;
+
+        if data.len() < header.tag_size() as usize {
+            return Err(Error::TruncatedTag);
+        }
+
+        Ok(Self::from_parts(header, &data))
+    }
+
+    // creates an empty ID3v2.4 tag
+    pub fn empty() -> Self {
+        Self::from_parts(
+            ID3v2Header {
+                major_version: 4,
+                revision_number: 0,
+                unsynchronisation: false,
+                extended_header: false,
+                experimental_indicator: false,
+                footer_present: false,
+                tag_size: 0,
+            },
+            &[],
+        )
+    }
+
+    // parses a complete tag, starting with its header
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let header = ID3v2Header::parse(data)?;
+        let end = ID3v2Header::SIZE + header.tag_size() as usize;
+
+        if data.len() < end {
+            return Err(Error::TruncatedTag);
+        }
+
+        Ok(Self::from_parts(header, &data[ID3v2Header::SIZE..end]))
+    }
+
+    fn from_parts(header: ID3v2Header, data: &[u8]) -> Self {
+        let major_version = header.major_version();
+
+        // ID3v2.3 and earlier unsynchronise the whole tag, ID3v2.4 unsynchronises
+        // each frame separately.
+        let data = if header.unsynchronisation() && major_version < 4 {
+            decode_unsynchronisation(data)
+        } else {
+            data.to_vec()
+        };
+
+        let mut offset = 0;
+
+        if header.extended_header() && major_version >= 3 && data.len() >= 4 {
+            offset = if major_version == 3 {
+                // the size of the ID3v2.3 extended header excludes the size field
+                u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize + 4
+            } else {
+                synch_data_to_u32(&data[0..4]) as usize
+            };
+        }
+
+        let frame_header_size = if major_version == 2 { 6 } else { 10 };
+        let id_size = if major_version == 2 { 3 } else { 4 };
+
+        let mut frames = vec![];
+
+        while offset + frame_header_size <= data.len() {
+            let frame_header = &data[offset..offset + frame_header_size];
+
+            // We've reached the padding, or garbage.
+            let id = &frame_header[0..id_size];
+            if !id
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+            {
+                break;
+            }
+            let id = String::from_utf8_lossy(id).into_owned();
+
+            let (frame_size, flags) = match major_version {
+                2 => (
+                    u32::from_be_bytes([0, frame_header[3], frame_header[4], frame_header[5]]),
+                    0u16,
+                ),
+                3 => (
+                    u32::from_be_bytes(frame_header[4..8].try_into().unwrap()),
+                    u16::from_be_bytes([frame_header[8], frame_header[9]]),
+                ),
+                _ => {
+                    // Some taggers write plain integers in ID3v2.4 tags.
+                    let size_data = &frame_header[4..8];
+                    let frame_size = if size_data.iter().any(|b| b & 0x80 != 0) {
+                        u32::from_be_bytes(size_data.try_into().unwrap())
+                    } else {
+                        synch_data_to_u32(size_data)
+                    };
+                    (
+                        frame_size,
+                        u16::from_be_bytes([frame_header[8], frame_header[9]]),
+                    )
+                }
+            };
+
+            let start = offset + frame_header_size;
+            let end = start + frame_size as usize;
+
+            if frame_size == 0 || end > data.len() {
+                break;
+            }
+
+            offset = end;
+
+            let mut frame_data = &data[start..end];
+
+            let (grouping, compressed, encrypted, unsynchronised, data_length_indicator) =
+                match major_version {
+                    3 => (
+                        flags & 0x0020 != 0,
+                        flags & 0x0080 != 0,
+                        flags & 0x0040 != 0,
+                        false,
+                        false,
+                    ),
+                    4 => (
+                        flags & 0x0040 != 0,
+                        flags & 0x0008 != 0,
+                        flags & 0x0004 != 0,
+                        flags & 0x0002 != 0,
+                        flags & 0x0001 != 0,
+                    ),
+                    _ => (false, false, false, false, false),
+                };
+
+            // Compressed and encrypted frames cannot be decoded without zlib or the
+            // encryption method, so they are kept as unknown frames and written
+            // back unchanged.
+            if compressed || encrypted {
+                let id = convert_frame_id(&id, major_version);
+                let body = opaque_frame_body(
+                    frame_data,
+                    major_version,
+                    flags,
+                    grouping,
+                    compressed,
+                    encrypted,
+                );
+                if let (Some(id), Some((body, format_flags))) = (id, body) {
+                    let mut frame = ID3v2Frame::new(id, ID3v2FrameContent::Unknown(body));
+                    frame.format_flags = format_flags;
+                    frames.push(frame);
+                }
+                continue;
+            }
+
+            if grouping && !frame_data.is_empty() {
+                frame_data = &frame_data[1..];
+            }
+
+            if data_length_indicator && frame_data.len() >= 4 {
+                frame_data = &frame_data[4..];
+            }
+
+            let decoded;
+            if unsynchronised {
+                decoded = decode_unsynchronisation(frame_data);
+                frame_data = &decoded;
+            }
+
+            if let Some(id) = convert_frame_id(&id, major_version) {
+                frames.push(ID3v2Frame::parse(id, frame_data, major_version));
+            }
+        }
+
+        if major_version < 4 {
+            merge_date_frames(&mut frames);
+        }
+
+        let mut tag = Self {
+            d: ID3v2TagPrivate {
+                header,
+                frames,
+                title: None,
+                artist: None,
+                album: None,
+                comment: None,
+                genre: None,
+                year: None,
+                track: None,
+            },
+        };
+
+        tag.update_fields();
+        tag
+    }
+
+    pub fn header(&self) -> &ID3v2Header {
+        &self.d.header
+    }
+
+    // Renders the tag as ID3v2.4, followed by `padding_size` zero bytes.  The
+    // header of this tag is updated to describe the rendered data.
+    pub fn render(&mut self, padding_size: usize) -> Vec<u8> {
+        let mut frames: Vec<u8> = self.d.frames.iter().flat_map(|f| f.render()).collect();
+        frames.resize(frames.len() + padding_size, 0);
+
+        self.d.header = ID3v2Header {
+            major_version: 4,
+            revision_number: 0,
+            unsynchronisation: false,
+            extended_header: false,
+            experimental_indicator: false,
+            footer_present: false,
+            tag_size: frames.len() as u32,
+        };
+
+        let mut data = b"ID3".to_vec();
+        data.extend([4, 0, 0]);
+        data.extend(u32_to_synch_data(frames.len() as u32));
+        data.extend(frames);
+        data
+    }
+
+    // returns all frames of the tag, in the order they were read
+    pub fn frames(&self) -> &[ID3v2Frame] {
+        &self.d.frames
+    }
+
+    // returns the frames with the given ID3v2.4 frame ID
+    pub fn frames_by_id<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a ID3v2Frame> {
+        self.d.frames.iter().filter(move |f| f.id == id)
+    }
+
+    pub fn add_frame(&mut self, frame: ID3v2Frame) {
+        self.d.frames.push(frame);
+        self.update_fields();
+    }
+
+    // removes all frames with the given ID3v2.4 frame ID
+    pub fn remove_frames(&mut self, id: &str) {
+        self.d.frames.retain(|f| f.id != id);
+        self.update_fields();
+    }
+
+    fn text(&self, id: &str) -> Option<String> {
+        self.frames_by_id(id).find_map(|f| match &f.content {
+            ID3v2FrameContent::Text { values, .. } => values.first().cloned(),
+            _ => None,
+        })
+    }
+
+    fn set_text(&mut self, id: &str, value: Option<String>) {
+        self.d.frames.retain(|f| f.id != id);
+
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            self.d.frames.push(ID3v2Frame::new(
+                id,
+                ID3v2FrameContent::Text {
+                    encoding: TextEncoding::Utf8,
+                    values: vec![value],
+                },
+            ));
+        }
+
+        self.update_fields();
+    }
+
+    // Refreshes the values returned by the Tag accessors from the frames.
+    fn update_fields(&mut self) {
+        self.d.title = self.text("TIT2");
+        self.d.artist = self.text("TPE1");
+        self.d.album = self.text("TALB");
+
+        // Prefer the comment without description.
+        let comments: Vec<(&String, &String)> = self
+            .frames_by_id("COMM")
+            .filter_map(|f| match &f.content {
+                ID3v2FrameContent::Comment {
+                    description, text, ..
+                } => Some((description, text)),
+                _ => None,
+            })
+            .collect();
+        self.d.comment = comments
+            .iter()
+            .find(|(description, _)| description.is_empty())
+            .or(comments.first())
+            .map(|(_, text)| (*text).clone());
+
+        let genre = self
+            .frames_by_id("TCON")
+            .flat_map(|f| match &f.content {
+                ID3v2FrameContent::Text { values, .. } => values.clone(),
+                _ => vec![],
+            })
+            .flat_map(|v| parse_genre(&v))
+            .next();
+
+        self.d.genre = genre;
+
+        self.d.year = self
+            .text("TDRC")
+            .and_then(|date| date.split('-').next()?.trim().parse().ok());
+
+        self.d.track = self
+            .text("TRCK")
+            .and_then(|track| track.split('/').next()?.trim().parse().ok());
+    }
+}
+
+// ID3v2.3 splits the recording time into TYER (already renamed to TDRC), TDAT
+// (DDMM) and TIME (HHMM).  These are merged into the ID3v2.4 TDRC frame.
+fn merge_date_frames(frames: &mut Vec<ID3v2Frame>) {
+    let text = |frames: &[ID3v2Frame], id: &str| {
+        frames.iter().find_map(|f| match &f.content {
+            ID3v2FrameContent::Text { values, .. } if f.id == id => values.first().cloned(),
+            _ => None,
+        })
+    };
+
+    let date = text(frames, "TDAT");
+    let time = text(frames, "TIME");
+
+    frames.retain(|f| f.id != "TDAT" && f.id != "TIME");
+
+    let is_digits = |s: &str| s.len() == 4 && s.bytes().all(|b| b.is_ascii_digit());
+
+    let Some(date) = date.filter(|d| is_digits(d)) else {
+        return;
+    };
+
+    for frame in frames.iter_mut().filter(|f| f.id == "TDRC") {
+        if let ID3v2FrameContent::Text { values, .. } = &mut frame.content {
+            if let Some(year) = values.first_mut().filter(|y| is_digits(y)) {
+                year.push_str(&format!("-{}-{}", &date[2..4], &date[0..2]));
+
+                if let Some(time) = time.as_deref().filter(|t| is_digits(t)) {
+                    year.push_str(&format!("T{}:{}", &time[0..2], &time[2..4]));
+                }
+            }
+        }
+    }
+}
+
+impl Tag for ID3v2Tag {
+    fn properties(&self) -> PropertyMap {
+        let mut properties = PropertyMap::new();
+
+        for (key, values) in self.d.frames.iter().filter_map(|f| f.property()) {
+            properties.entry(key).or_default().extend(values);
+        }
+
+        properties
+    }
+
+    // removes all frames with the given frame IDs
+    fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
+        self.d.frames.retain(|f| !properties.contains(&f.id));
+        self.update_fields();
+    }
+
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        // Frames which do not map to a property, like pictures, are kept.
+        self.d.frames.retain(|f| f.property().is_none());
+
+        for (key, values) in &properties {
+            if !values.is_empty() {
+                self.d.frames.extend(ID3v2Frame::from_property(key, values));
+            }
+        }
+
+        self.update_fields();
+
+        // Anything unknown is stored in a TXXX frame.
+        PropertyMap::new()
+    }
+
+    fn title(&self) -> &Option<String> {
+        &self.d.title
+    }
+
+    fn artist(&self) -> &Option<String> {
+        &self.d.artist
+    }
+
+    fn album(&self) -> &Option<String> {
+        &self.d.album
+    }
+
+    fn comment(&self) -> &Option<String> {
+        &self.d.comment
+    }
+
+    fn genre(&self) -> &Option<String> {
+        &self.d.genre
+    }
+
+    fn year(&self) -> &Option<u32> {
+        &self.d.year
+    }
+
+    fn track(&self) -> &Option<u32> {
+        &self.d.track
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.set_text("TIT2", title);
+    }
+
+    fn set_artist(&mut self, artist: Option<String>) {
+        self.set_text("TPE1", artist);
+    }
+
+    fn set_album(&mut self, album: Option<String>) {
+        self.set_text("TALB", album);
+    }
+
+    fn set_comment(&mut self, comment: Option<String>) {
+        // Replace the comment without description, keep the others.
+        self.d.frames.retain(|f| {
+            !matches!(&f.content, ID3v2FrameContent::Comment { description, .. }
+                if f.id == "COMM" && description.is_empty())
+        });
+
+        if let Some(text) = comment.filter(|c| !c.is_empty()) {
+            self.d.frames.push(ID3v2Frame::new(
+                "COMM",
+                ID3v2FrameContent::Comment {
+                    encoding: TextEncoding::Utf8,
+                    language: *b"XXX",
+                    description: String::new(),
+                    text,
+                },
+            ));
+        }
+
+        self.update_fields();
+    }
+
+    fn set_genre(&mut self, genre: Option<String>) {
+        self.set_text("TCON", genre);
+    }
+
+    fn set_year(&mut self, year: Option<u32>) {
+        self.set_text("TDRC", year.filter(|&y| y > 0).map(|y| y.to_string()));
+    }
+
+    fn set_track(&mut self, track: Option<u32>) {
+        self.set_text("TRCK", track.filter(|&t| t > 0).map(|t| t.to_string()));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.d.frames.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag_data(major_version: u8, flags: u8, frames: &[u8]) -> Vec<u8> {
+        let size = frames.len() as u32;
+        let mut data = vec![b'I', b'D', b'3', major_version, 0, flags];
+        data.extend([
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ]);
+        data.extend(frames);
+        data
+    }
+
+    fn frame_v3(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.extend((body.len() as u32).to_be_bytes());
+        data.extend([0, 0]);
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn test_synch_data() {
+        assert_eq!(synch_data_to_u32(&[0, 0, 0x02, 0x01]), 257);
+        assert_eq!(synch_data_to_u32(&[0x7f, 0x7f, 0x7f, 0x7f]), 0x0fff_ffff);
+    }
+
+    #[test]
+    fn test_unsynchronisation() {
+        assert_eq!(
+            decode_unsynchronisation(&[0xff, 0x00, 0xe0, 0xff, 0x00, 0x00]),
+            [0xff, 0xe0, 0xff, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let header = ID3v2Header::parse(&tag_data(4, 0x10, &[0u8; 257])).unwrap();
+        assert_eq!(header.major_version(), 4);
+        assert!(header.footer_present());
+        assert_eq!(header.tag_size(), 257);
+        assert_eq!(header.complete_tag_size(), 277);
+
+        assert!(matches!(
+            ID3v2Header::parse(b"ID3\x05\x00\x00\x00\x00\x00\x00"),
+            Err(Error::UnsupportedVersion)
+        ));
+        assert!(matches!(
+            ID3v2Header::parse(b"ID3\x04\x00\x00\x00\x00\x80\x00"),
+            Err(Error::InvalidHeader)
+        ));
+        assert!(matches!(ID3v2Header::parse(b"TAG"), Err(Error::NoTagFound)));
+    }
+
+    #[test]
+    fn test_read_v23() {
+        let mut frames = frame_v3(b"TIT2", b"\x00Title");
+        frames.extend(frame_v3(
+            b"TPE1",
+            b"\x01\xff\xfeA\x00r\x00t\x00i\x00s\x00t\x00\x00\x00",
+        ));
+        frames.extend(frame_v3(b"TYER", b"\x002001"));
+        frames.extend(frame_v3(b"TDAT", b"\x002512"));
+        frames.extend(frame_v3(b"TRCK", b"\x003/12"));
+        frames.extend(frame_v3(b"TCON", b"\x00(17)Rock"));
+        frames.extend(frame_v3(b"COMM", b"\x00engdesc\x00Other\x00"));
+        frames.extend(frame_v3(b"COMM", b"\x00eng\x00Comment"));
+        frames.extend(frame_v3(b"TXXX", b"\x00MusicBrainz Album Id\x00abc"));
+        frames.extend([0u8; 16]);
+
+        let tag = ID3v2Tag::parse(&tag_data(3, 0, &frames)).unwrap();
+
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(*tag.year(), Some(2001));
+        assert_eq!(*tag.track(), Some(3));
+        assert_eq!(tag.genre().as_deref(), Some("Rock"));
+        assert_eq!(tag.comment().as_deref(), Some("Comment"));
+
+        let properties = tag.properties();
+        assert_eq!(properties["DATE"], vec!["2001-12-25"]);
+        assert_eq!(properties["TRACKNUMBER"], vec!["3/12"]);
+        assert_eq!(properties["COMMENT:DESC"], vec!["Other"]);
+        assert_eq!(properties["MUSICBRAINZ ALBUM ID"], vec!["abc"]);
+        assert!(!properties.contains_key("TDAT"));
+    }
+
+    #[test]
+    fn test_read_v24() {
+        let mut frame = b"TPE1".to_vec();
+        frame.extend([0, 0, 0, 14, 0, 0]);
+        frame.extend(b"\x03One\x00Two\x00Three");
+        let mut frame_with_unsync = b"TALB".to_vec();
+        frame_with_unsync.extend([0, 0, 0, 6, 0, 0x02]);
+        frame_with_unsync.extend(b"\x00A\xff\x00\xe0B");
+
+        let mut frames = frame;
+        frames.extend(frame_with_unsync);
+        let tag = ID3v2Tag::parse(&tag_data(4, 0, &frames)).unwrap();
+
+        assert_eq!(tag.properties()["ARTIST"], vec!["One", "Two", "Three"]);
+        assert_eq!(tag.artist().as_deref(), Some("One"));
+        assert_eq!(tag.album().as_deref(), Some("A\u{ff}\u{e0}B"));
+    }
+
+    #[test]
+    fn test_read_v22() {
+        let mut frames = b"TT2\x00\x00\x06\x00Title".to_vec();
+        frames.extend(b"TCO\x00\x00\x05\x00(13)");
+        frames.extend(b"PIC\x00\x00\x08\x00PNG\x03\x00\x89P");
+
+        let tag = ID3v2Tag::parse(&tag_data(2, 0, &frames)).unwrap();
+
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.genre().as_deref(), Some("Pop"));
+
+        let picture = tag.frames_by_id("APIC").next().unwrap();
+        assert_eq!(
+            picture.content(),
+            &ID3v2FrameContent::Picture {
+                encoding: TextEncoding::Latin1,
+                mime_type: String::from("image/png"),
+                picture_type: 3,
+                description: String::new(),
+                data: vec![0x89, b'P'],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_genre() {
+        assert_eq!(parse_genre("(17)"), vec!["Rock"]);
+        assert_eq!(parse_genre("(17)Rock"), vec!["Rock"]);
+        assert_eq!(parse_genre("(17)rock"), vec!["rock"]);
+        assert_eq!(parse_genre("(4)Eurodisco"), vec!["Disco", "Eurodisco"]);
+        assert_eq!(parse_genre("(RX)(CR)"), vec!["Remix", "Cover"]);
+        assert_eq!(parse_genre("((Foo)"), vec!["(Foo)"]);
+        assert_eq!(parse_genre("9"), vec!["Metal"]);
+        assert_eq!(parse_genre("Shoegaze"), vec!["Shoegaze"]);
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        let mut tag = ID3v2Tag::empty();
+        tag.set_title(Some(String::from("Title")));
+        tag.set_artist(Some(String::from(
+            "\u{30a2}\u{30fc}\u{30c6}\u{30a3}\u{30b9}\u{30c8}",
+        )));
+        tag.set_comment(Some(String::from("Comment")));
+        tag.set_year(Some(1999));
+        tag.add_frame(ID3v2Frame::new(
+            "TXXX",
+            ID3v2FrameContent::UserText {
+                encoding: TextEncoding::Utf16,
+                description: String::from("Key"),
+                values: vec![String::from("A"), String::from("B")],
+            },
+        ));
+        tag.add_frame(ID3v2Frame::new(
+            "APIC",
+            ID3v2FrameContent::Picture {
+                encoding: TextEncoding::Latin1,
+                mime_type: String::from("image/jpeg"),
+                picture_type: 3,
+                description: String::from("Cover"),
+                data: vec![0xff, 0xd8, 0x00, 0xff],
+            },
+        ));
+
+        let data = tag.render(64);
+        assert_eq!(
+            tag.header().tag_size() as usize + ID3v2Header::SIZE,
+            data.len()
+        );
+        assert_eq!(data[data.len() - 64..], [0u8; 64]);
+
+        let parsed = ID3v2Tag::parse(&data).unwrap();
+        assert_eq!(parsed.frames(), tag.frames());
+        assert_eq!(parsed.artist(), tag.artist());
+        assert_eq!(*parsed.year(), Some(1999));
+    }
+
+    #[test]
+    fn test_keep_compressed_frames() {
+        let mut frames = frame_v3(b"TIT2", b"\x00Title");
+        let mut compressed = frame_v3(b"TALB", b"\x00\x00\x00\x05\x78\x9c\x01\x02");
+        compressed[9] = 0x80;
+        frames.extend(compressed);
+        let mut encrypted = frame_v3(b"PRIV", b"\x80\x01secret");
+        encrypted[9] = 0x60;
+        frames.extend(encrypted);
+
+        let mut tag = ID3v2Tag::parse(&tag_data(3, 0, &frames)).unwrap();
+        assert_eq!(tag.frames().len(), 3);
+        assert_eq!(*tag.album(), None);
+
+        // the additional data moves into ID3v2.4 order
+        let data = tag.render(0);
+        let mut expected = b"TALB\x00\x00\x00\x08\x00\x09".to_vec();
+        expected.extend(b"\x00\x00\x00\x05\x78\x9c\x01\x02");
+        expected.extend(b"PRIV\x00\x00\x00\x08\x00\x44\x01\x80secret");
+        assert!(data.ends_with(&expected));
+
+        let parsed = ID3v2Tag::parse(&data).unwrap();
+        assert_eq!(parsed.frames(), tag.frames());
+        assert_eq!(parsed.title().as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn test_render_latin1_fallback() {
+        let frame = ID3v2Frame::new(
+            "TIT2",
+            ID3v2FrameContent::Text {
+                encoding: TextEncoding::Latin1,
+                values: vec![String::from("\u{e9}\u{20ac}")],
+            },
+        );
+
+        assert_eq!(frame.render_content()[0], TextEncoding::Utf8 as u8);
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let mut tag = ID3v2Tag::parse(&tag_data(4, 0, &[])).unwrap();
+        assert!(tag.is_empty());
+
+        let mut properties = PropertyMap::new();
+        properties.insert(String::from("TITLE"), vec![String::from("Title")]);
+        properties.insert(String::from("COMMENT"), vec![String::from("Comment")]);
+        properties.insert(String::from("CUSTOM"), vec![String::from("Value")]);
+        properties.insert(
+            String::from("ARTISTWEBPAGE"),
+            vec![String::from("http://a")],
+        );
+        assert!(tag.set_properties(properties.clone()).is_empty());
+
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.comment().as_deref(), Some("Comment"));
+        assert_eq!(tag.properties(), properties);
+
+        tag.set_title(None);
+        assert!(tag.title().is_none());
+        assert!(tag.frames_by_id("TIT2").next().is_none());
+    }
+}
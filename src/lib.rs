@@ -1,14 +1,45 @@
+pub mod ape;
+pub mod asf;
+pub mod audio_properties;
+mod error;
+pub mod file_ref;
+pub mod flac;
+pub mod id3v1;
+pub mod id3v2;
+pub mod lyrics3;
+pub mod mp4;
+pub mod mpeg;
+pub mod ogg;
+pub mod ogg_flac;
+pub mod opus;
+pub mod speex;
+pub mod tag;
 mod utils;
-mod audio_properties;
-mod tag;
-mod mpeg;
-mod id3v1;
+pub mod vorbis;
+pub mod xiph_comment;
 use std::path::Path;
 
-use crate::{audio_properties::AudioProperties, tag::Tag};
+pub use crate::{
+    asf::AsfFile,
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    file_ref::{FileRef, FileType},
+    flac::FlacFile,
+    mp4::Mp4File,
+    mpeg::MpegFile,
+    ogg_flac::OggFlacFile,
+    opus::OpusFile,
+    speex::SpeexFile,
+    tag::{PropertyMap, Tag},
+    utils::Truncate,
+    vorbis::VorbisFile,
+};
 
 pub trait AudioFile {
-    fn new();
+    // opens the file at `path`, reading its tags and audio properties
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self>
+    where
+        Self: Sized;
 
     fn tag(&self) -> Box<dyn Tag>;
 
@@ -0,0 +1,477 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{
+    error::{Error, Result},
+    id3v1::{Latin1StringHandler, StringHandler},
+    tag::{PropertyMap, Tag},
+    utils::read_block,
+};
+
+// the largest lyrics a Lyrics3 v1 block may hold
+const V1_MAX_LYRICS_SIZE: usize = 5100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lyrics3Version {
+    // "LYRICSBEGIN" + lyrics + "LYRICSEND"
+    V1,
+    // "LYRICSBEGIN" + fields + size + "LYRICS200"
+    V2,
+}
+
+// Maps the Lyrics3 v2 fields to property keys.  IND and IMG have no property.
+const FIELD_KEYS: &[(&str, &str)] = &[
+    ("LYR", "LYRICS"),
+    ("INF", "COMMENT"),
+    ("AUT", "LYRICIST"),
+    ("EAL", "ALBUM"),
+    ("EAR", "ARTIST"),
+    ("ETT", "TITLE"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Lyrics3TagPrivate {
+    version: Lyrics3Version,
+    indications: Option<String>,
+    lyrics: Option<String>,
+    comment: Option<String>,
+    author: Option<String>,
+    album: Option<String>,
+    artist: Option<String>,
+    title: Option<String>,
+    images: Option<String>,
+    // fields with other IDs, kept as they are
+    other_fields: Vec<(String, String)>,
+}
+
+// A Lyrics3 block, found between the audio data and the ID3v1 tag of MPEG
+// files.  Its text is ISO-8859-1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lyrics3Tag {
+    d: Lyrics3TagPrivate,
+}
+
+impl Lyrics3Tag {
+    // reads the block of `size` bytes at `offset`
+    pub fn new<R: Read + Seek>(file: &mut R, offset: u64, size: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+        let data = read_block(file, size as usize)?;
+
+        Self::parse(&data)
+    }
+
+    // creates an empty Lyrics3 v2 block
+    pub fn empty() -> Self {
+        Self {
+            d: Lyrics3TagPrivate {
+                version: Lyrics3Version::V2,
+                indications: None,
+                lyrics: None,
+                comment: None,
+                author: None,
+                album: None,
+                artist: None,
+                title: None,
+                images: None,
+                other_fields: vec![],
+            },
+        }
+    }
+
+    // Parses a complete block, from "LYRICSBEGIN" up to and including
+    // "LYRICSEND" or "LYRICS200".
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let body = data.strip_prefix(b"LYRICSBEGIN").ok_or(Error::NoTagFound)?;
+        let mut tag = Self::empty();
+
+        if let Some(lyrics) = body.strip_suffix(b"LYRICSEND") {
+            tag.d.version = Lyrics3Version::V1;
+            tag.d.lyrics = decode(lyrics);
+
+            return Ok(tag);
+        }
+
+        // The six digit size is followed by "LYRICS200".
+        let mut fields = body
+            .strip_suffix(b"LYRICS200")
+            .and_then(|body| body.len().checked_sub(6).map(|end| &body[..end]))
+            .ok_or(Error::NoTagFound)?;
+
+        while !fields.is_empty() {
+            if fields.len() < 8 {
+                return Err(Error::TruncatedTag);
+            }
+
+            let id = String::from_utf8_lossy(&fields[..3]).into_owned();
+            let size = parse_number(&fields[3..8]).ok_or(Error::InvalidHeader)?;
+            let value = fields.get(8..8 + size).ok_or(Error::TruncatedTag)?;
+            fields = &fields[8 + size..];
+
+            let value = decode(value);
+            match id.as_str() {
+                "IND" => tag.d.indications = value,
+                "LYR" => tag.d.lyrics = value,
+                "INF" => tag.d.comment = value,
+                "AUT" => tag.d.author = value,
+                "EAL" => tag.d.album = value,
+                "EAR" => tag.d.artist = value,
+                "ETT" => tag.d.title = value,
+                "IMG" => tag.d.images = value,
+                _ => tag.d.other_fields.push((id, value.unwrap_or_default())),
+            }
+        }
+
+        Ok(tag)
+    }
+
+    // Renders the tag as a Lyrics3 v2 block, whatever version it was read as.
+    pub fn render(&self) -> Vec<u8> {
+        let mut data = b"LYRICSBEGIN".to_vec();
+
+        // The first indicator tells whether there are lyrics; the others are
+        // kept as read.
+        let indications = self.d.indications.as_deref().unwrap_or("00");
+        let mut indications = String::from(indications.get(1..).unwrap_or("0"));
+        indications.insert(0, if self.d.lyrics.is_some() { '1' } else { '0' });
+
+        let fields = [
+            ("IND", Some(&indications)),
+            ("LYR", self.d.lyrics.as_ref()),
+            ("INF", self.d.comment.as_ref()),
+            ("AUT", self.d.author.as_ref()),
+            ("EAL", self.d.album.as_ref()),
+            ("EAR", self.d.artist.as_ref()),
+            ("ETT", self.d.title.as_ref()),
+            ("IMG", self.d.images.as_ref()),
+        ];
+        let other_fields = self
+            .d
+            .other_fields
+            .iter()
+            .map(|(id, v)| (id.as_str(), Some(v)));
+
+        for (id, value) in fields.into_iter().chain(other_fields) {
+            let Some(value) = value else {
+                continue;
+            };
+
+            let mut value = Latin1StringHandler.render(value);
+            value.truncate(99999);
+
+            data.extend(id.as_bytes());
+            data.extend(format!("{:05}", value.len()).as_bytes());
+            data.extend(value);
+        }
+
+        data.extend(format!("{:06}", data.len()).as_bytes());
+        data.extend(b"LYRICS200");
+
+        data
+    }
+
+    // returns the version the tag was read as
+    pub fn version(&self) -> Lyrics3Version {
+        self.d.version
+    }
+
+    // returns the lyrics, with lines separated by CR LF
+    pub fn lyrics(&self) -> &Option<String> {
+        &self.d.lyrics
+    }
+
+    pub fn set_lyrics(&mut self, lyrics: Option<String>) {
+        self.d.lyrics = lyrics.filter(|s| !s.is_empty());
+    }
+
+    // returns the author of the lyrics
+    pub fn author(&self) -> &Option<String> {
+        &self.d.author
+    }
+
+    pub fn set_author(&mut self, author: Option<String>) {
+        self.d.author = author.filter(|s| !s.is_empty());
+    }
+
+    // returns the indication flags, a string of '0' and '1' telling whether
+    // there are lyrics, whether they have time stamps and whether tracks may
+    // be played randomly
+    pub fn indications(&self) -> &Option<String> {
+        &self.d.indications
+    }
+
+    // returns the links to images, one per line
+    pub fn images(&self) -> &Option<String> {
+        &self.d.images
+    }
+}
+
+impl Tag for Lyrics3Tag {
+    fn properties(&self) -> PropertyMap {
+        let mut properties = PropertyMap::new();
+
+        for (id, key) in FIELD_KEYS {
+            if let Some(value) = self.field(id) {
+                properties.insert(String::from(*key), vec![value.clone()]);
+            }
+        }
+
+        properties
+    }
+
+    fn remove_unsupported_properties(&mut self, _properties: Vec<String>) {
+        // Only the fields above are exposed, so there is nothing to remove.
+    }
+
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        let mut unsupported = properties;
+        unsupported.retain(|_, values| !values.is_empty());
+
+        for (id, key) in FIELD_KEYS {
+            let value = match unsupported.get_mut(*key) {
+                Some(values) => {
+                    let value = values.remove(0);
+                    if values.is_empty() {
+                        unsupported.remove(*key);
+                    }
+                    Some(value)
+                }
+                None => None,
+            };
+
+            *self.field_mut(id) = value.filter(|s| !s.is_empty());
+        }
+
+        unsupported
+    }
+
+    fn title(&self) -> &Option<String> {
+        &self.d.title
+    }
+
+    fn artist(&self) -> &Option<String> {
+        &self.d.artist
+    }
+
+    fn album(&self) -> &Option<String> {
+        &self.d.album
+    }
+
+    fn comment(&self) -> &Option<String> {
+        &self.d.comment
+    }
+
+    fn genre(&self) -> &Option<String> {
+        &None
+    }
+
+    fn year(&self) -> &Option<u32> {
+        &None
+    }
+
+    fn track(&self) -> &Option<u32> {
+        &None
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.d.title = title.filter(|s| !s.is_empty());
+    }
+
+    fn set_artist(&mut self, artist: Option<String>) {
+        self.d.artist = artist.filter(|s| !s.is_empty());
+    }
+
+    fn set_album(&mut self, album: Option<String>) {
+        self.d.album = album.filter(|s| !s.is_empty());
+    }
+
+    fn set_comment(&mut self, comment: Option<String>) {
+        self.d.comment = comment.filter(|s| !s.is_empty());
+    }
+
+    // Lyrics3 has no genre, year or track fields.
+    fn set_genre(&mut self, _genre: Option<String>) {}
+
+    fn set_year(&mut self, _year: Option<u32>) {}
+
+    fn set_track(&mut self, _track: Option<u32>) {}
+
+    fn is_empty(&self) -> bool {
+        FIELD_KEYS.iter().all(|(id, _)| self.field(id).is_none())
+            && self.d.images.is_none()
+            && self.d.other_fields.is_empty()
+    }
+}
+
+impl Lyrics3Tag {
+    fn field(&self, id: &str) -> &Option<String> {
+        match id {
+            "LYR" => &self.d.lyrics,
+            "INF" => &self.d.comment,
+            "AUT" => &self.d.author,
+            "EAL" => &self.d.album,
+            "EAR" => &self.d.artist,
+            "ETT" => &self.d.title,
+            _ => &None,
+        }
+    }
+
+    fn field_mut(&mut self, id: &str) -> &mut Option<String> {
+        match id {
+            "LYR" => &mut self.d.lyrics,
+            "INF" => &mut self.d.comment,
+            "AUT" => &mut self.d.author,
+            "EAL" => &mut self.d.album,
+            "EAR" => &mut self.d.artist,
+            "ETT" => &mut self.d.title,
+            _ => unreachable!("{} is not in FIELD_KEYS", id),
+        }
+    }
+}
+
+// Looks for a Lyrics3 block ending at `end`, which is usually the offset of the
+// ID3v1 tag, and returns its offset and size.
+pub(crate) fn find<R: Read + Seek>(file: &mut R, end: u64) -> Result<Option<(u64, u64)>> {
+    if end < 15 {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(end - 15))?;
+    let trailer = read_block(file, 15)?;
+
+    if &trailer[6..] == b"LYRICS200" {
+        // The size counts everything from "LYRICSBEGIN" to the size itself.
+        let Some(size) = parse_number(&trailer[..6]).map(|size| size as u64 + 15) else {
+            return Ok(None);
+        };
+
+        if size > end {
+            return Ok(None);
+        }
+
+        file.seek(SeekFrom::Start(end - size))?;
+        if read_block(file, 11)? == b"LYRICSBEGIN" {
+            return Ok(Some((end - size, size)));
+        }
+    } else if &trailer[6..] == b"LYRICSEND" {
+        // Version 1 has no size, so look for the last "LYRICSBEGIN" before it.
+        let start = (end - 9).saturating_sub((V1_MAX_LYRICS_SIZE + 11) as u64);
+        file.seek(SeekFrom::Start(start))?;
+        let data = read_block(file, (end - 9 - start) as usize)?;
+
+        if let Some(position) = data.windows(11).rposition(|w| w == b"LYRICSBEGIN") {
+            let offset = start + position as u64;
+            return Ok(Some((offset, end - offset)));
+        }
+    }
+
+    Ok(None)
+}
+
+// parses a fixed-width decimal number
+fn parse_number(data: &[u8]) -> Option<usize> {
+    if !data.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+fn decode(data: &[u8]) -> Option<String> {
+    Some(Latin1StringHandler.parse(data)).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn v2_block(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = b"LYRICSBEGIN".to_vec();
+        for (id, value) in fields {
+            data.extend(format!("{}{:05}{}", id, value.len(), value).as_bytes());
+        }
+        data.extend(format!("{:06}LYRICS200", data.len()).as_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let data = v2_block(&[
+            ("IND", "10"),
+            ("LYR", "[00:01]Line one\r\nLine two"),
+            ("EAR", "An artist with a name longer than thirty characters"),
+            ("XYZ", "Kept"),
+        ]);
+        let tag = Lyrics3Tag::parse(&data).unwrap();
+
+        assert_eq!(tag.version(), Lyrics3Version::V2);
+        assert_eq!(tag.indications().as_deref(), Some("10"));
+        assert_eq!(tag.lyrics().as_deref(), Some("[00:01]Line one\r\nLine two"));
+        assert_eq!(
+            tag.artist().as_deref(),
+            Some("An artist with a name longer than thirty characters")
+        );
+
+        let properties = tag.properties();
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties["LYRICS"], ["[00:01]Line one\r\nLine two"]);
+
+        assert_eq!(tag.render(), data);
+        assert_eq!(Lyrics3Tag::parse(&tag.render()).unwrap(), tag);
+
+        assert!(matches!(
+            Lyrics3Tag::parse(&data[..data.len() - 20]),
+            Err(Error::NoTagFound)
+        ));
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let tag = Lyrics3Tag::parse(b"LYRICSBEGINSome lyricsLYRICSEND").unwrap();
+
+        assert_eq!(tag.version(), Lyrics3Version::V1);
+        assert_eq!(tag.lyrics().as_deref(), Some("Some lyrics"));
+        assert!(tag.title().is_none());
+    }
+
+    #[test]
+    fn test_find() {
+        let block = v2_block(&[("IND", "10"), ("LYR", "Lyrics")]);
+        let mut data = vec![0u8; 100];
+        data.extend(&block);
+        let end = data.len() as u64;
+        data.extend(b"TAG");
+
+        let mut cursor = Cursor::new(data);
+        assert_eq!(
+            find(&mut cursor, end).unwrap(),
+            Some((100, block.len() as u64))
+        );
+        assert_eq!(find(&mut cursor, end - 1).unwrap(), None);
+
+        let mut data = vec![0u8; 100];
+        data.extend(b"LYRICSBEGINSome lyricsLYRICSEND");
+        let end = data.len() as u64;
+        assert_eq!(
+            find(&mut Cursor::new(data), end).unwrap(),
+            Some((100, end - 100))
+        );
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let mut tag = Lyrics3Tag::parse(b"LYRICSBEGINOld lyricsLYRICSEND").unwrap();
+
+        let mut properties = PropertyMap::new();
+        properties.insert(String::from("LYRICS"), vec![String::from("New")]);
+        properties.insert(String::from("GENRE"), vec![String::from("Rock")]);
+        let unsupported = tag.set_properties(properties);
+
+        assert_eq!(tag.lyrics().as_deref(), Some("New"));
+        assert_eq!(unsupported.len(), 1);
+        assert!(unsupported.contains_key("GENRE"));
+
+        let tag = Lyrics3Tag::parse(&tag.render()).unwrap();
+        assert_eq!(tag.version(), Lyrics3Version::V2);
+        assert_eq!(tag.indications().as_deref(), Some("10"));
+    }
+}
@@ -0,0 +1,1599 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    id3v1,
+    tag::{PropertyMap, Tag},
+    utils::{insert_block, read_block, Truncate},
+    AudioFile,
+};
+
+// Atoms whose contents are other atoms, and the number of bytes before the
+// first child.
+const CONTAINERS: &[(&str, u64)] = &[
+    ("moov", 0),
+    ("udta", 0),
+    ("mdia", 0),
+    ("meta", 4),
+    ("ilst", 0),
+    ("stbl", 0),
+    ("minf", 0),
+    ("moof", 0),
+    ("traf", 0),
+    ("trak", 0),
+    ("stsd", 8),
+];
+
+// the padding added after the ilst atom when it grows rounds the tag up to
+// a multiple of this size
+const PADDING_SIZE: usize = 1024;
+
+// the prefix of the freeform items written by iTunes
+const ITUNES_FREEFORM_PREFIX: &str = "----:com.apple.iTunes:";
+
+// Maps item names to property keys.
+const KEY_MAP: &[(&str, &str)] = &[
+    ("\u{a9}nam", "TITLE"),
+    ("\u{a9}ART", "ARTIST"),
+    ("\u{a9}alb", "ALBUM"),
+    ("\u{a9}cmt", "COMMENT"),
+    ("\u{a9}gen", "GENRE"),
+    ("\u{a9}day", "DATE"),
+    ("\u{a9}wrt", "COMPOSER"),
+    ("\u{a9}grp", "GROUPING"),
+    ("\u{a9}lyr", "LYRICS"),
+    ("\u{a9}too", "ENCODEDBY"),
+    ("aART", "ALBUMARTIST"),
+    ("trkn", "TRACKNUMBER"),
+    ("disk", "DISCNUMBER"),
+    ("cpil", "COMPILATION"),
+    ("tmpo", "BPM"),
+    ("cprt", "COPYRIGHT"),
+    ("sonm", "TITLESORT"),
+    ("soar", "ARTISTSORT"),
+    ("soal", "ALBUMSORT"),
+    ("soaa", "ALBUMARTISTSORT"),
+    ("soco", "COMPOSERSORT"),
+    (
+        "----:com.apple.iTunes:MusicBrainz Track Id",
+        "MUSICBRAINZ_TRACKID",
+    ),
+    (
+        "----:com.apple.iTunes:MusicBrainz Album Id",
+        "MUSICBRAINZ_ALBUMID",
+    ),
+    (
+        "----:com.apple.iTunes:MusicBrainz Artist Id",
+        "MUSICBRAINZ_ARTISTID",
+    ),
+    (
+        "----:com.apple.iTunes:MusicBrainz Album Artist Id",
+        "MUSICBRAINZ_ALBUMARTISTID",
+    ),
+    (
+        "----:com.apple.iTunes:MusicBrainz Release Group Id",
+        "MUSICBRAINZ_RELEASEGROUPID",
+    ),
+    ("----:com.apple.iTunes:ASIN", "ASIN"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mp4Atom {
+    offset: u64,
+    length: u64,
+    header_size: u64,
+    name: String,
+    children: Vec<Mp4Atom>,
+}
+
+impl Mp4Atom {
+    // Reads the atom at `offset`, and its children if it is a container.  The
+    // atom may not extend beyond `end`.
+    fn read<R: Read + Seek>(file: &mut R, offset: u64, end: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+        let header = read_block(file, 8)?;
+        if header.len() < 8 {
+            return Err(Error::TruncatedHeader);
+        }
+
+        let name = atom_name(&header[4..8]);
+        let (length, header_size) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            // The atom extends to the end of the file.
+            0 => (end - offset, 8),
+            // The length follows as a 64-bit number.
+            1 => {
+                let size = read_block(file, 8)?;
+                if size.len() < 8 {
+                    return Err(Error::TruncatedHeader);
+                }
+                (u64::from_be_bytes(size.try_into().unwrap()), 16)
+            }
+            length => (length as u64, 8),
+        };
+
+        if length < header_size || offset + length > end {
+            return Err(Error::InvalidHeader);
+        }
+
+        let mut atom = Self {
+            offset,
+            length,
+            header_size,
+            name,
+            children: vec![],
+        };
+
+        if let Some(&(_, mut skip)) = CONTAINERS.iter().find(|(n, _)| *n == atom.name) {
+            // QuickTime files have meta atoms without version and flags.
+            if atom.name == "meta" {
+                file.seek(SeekFrom::Start(offset + header_size + 4))?;
+                if read_block(file, 4)? == b"hdlr" {
+                    skip = 0;
+                }
+            }
+
+            let mut child_offset = offset + header_size + skip;
+            while child_offset + 8 <= offset + length {
+                // Damaged children end the container, keeping the valid ones.
+                match Self::read(file, child_offset, offset + length) {
+                    Ok(child) => {
+                        child_offset += child.length;
+                        atom.children.push(child);
+                    }
+                    Err(Error::Io(e)) => return Err(Error::Io(e)),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        Ok(atom)
+    }
+
+    // returns the name, with bytes above 0x7f read as Latin-1, e.g. "©nam"
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // returns the length of the atom, including its header
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn children(&self) -> &[Mp4Atom] {
+        &self.children
+    }
+
+    // returns the descendant at `path`, e.g. ["mdia", "mdhd"]
+    pub fn find(&self, path: &[&str]) -> Option<&Mp4Atom> {
+        match path.split_first() {
+            None => Some(self),
+            Some((name, rest)) => self
+                .children
+                .iter()
+                .find(|child| child.name == *name)
+                .and_then(|child| child.find(rest)),
+        }
+    }
+
+    // returns all children named `name`
+    pub fn find_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Mp4Atom> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    // reads the contents of the atom, without its header
+    pub(crate) fn read_data<R: Read + Seek>(&self, file: &mut R) -> Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(self.offset + self.header_size))?;
+        let length = (self.length - self.header_size) as usize;
+        let data = read_block(file, length)?;
+        if data.len() < length {
+            return Err(Error::TruncatedTag);
+        }
+
+        Ok(data)
+    }
+}
+
+// The top-level atoms of a file.
+pub struct Mp4Atoms {
+    atoms: Vec<Mp4Atom>,
+}
+
+impl Mp4Atoms {
+    pub fn new<R: Read + Seek>(file: &mut R) -> Result<Self> {
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let mut atoms = vec![];
+
+        let mut offset = 0;
+        while offset + 8 <= file_length {
+            // Trailing garbage after the last valid atom is ignored.
+            match Mp4Atom::read(file, offset, file_length) {
+                Ok(atom) => {
+                    offset += atom.length;
+                    atoms.push(atom);
+                }
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(_) if atoms.is_empty() => return Err(Error::InvalidHeader),
+                Err(_) => break,
+            }
+        }
+
+        Ok(Self { atoms })
+    }
+
+    pub fn atoms(&self) -> &[Mp4Atom] {
+        &self.atoms
+    }
+
+    // returns the atom at `path`, e.g. ["moov", "udta", "meta", "ilst"]
+    pub fn find(&self, path: &[&str]) -> Option<&Mp4Atom> {
+        let (name, rest) = path.split_first()?;
+
+        self.atoms
+            .iter()
+            .find(|atom| atom.name == *name)
+            .and_then(|atom| atom.find(rest))
+    }
+
+    // returns the atoms along `path`, which stops at the first missing one
+    pub fn path(&self, path: &[&str]) -> Vec<&Mp4Atom> {
+        let mut atoms = vec![];
+        let mut children = &self.atoms[..];
+
+        for name in path {
+            match children.iter().find(|atom| atom.name == *name) {
+                Some(atom) => {
+                    atoms.push(atom);
+                    children = &atom.children;
+                }
+                None => break,
+            }
+        }
+
+        atoms
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum Mp4CoverArtFormat {
+    Gif = 12,
+    Jpeg = 13,
+    Png = 14,
+    Bmp = 27,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mp4CoverArt {
+    // None for data of unknown type
+    pub format: Option<Mp4CoverArtFormat>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mp4Item {
+    Text(Vec<String>),
+    // a number and a total, like "trkn" and "disk"
+    IntPair(i32, i32),
+    Bool(bool),
+    // a 16-bit number, like "tmpo"
+    Int(i32),
+    Byte(u8),
+    UInt(u32),
+    LongLong(i64),
+    CoverArt(Vec<Mp4CoverArt>),
+    Binary(Vec<Vec<u8>>),
+}
+
+// the type codes of data atoms
+const TYPE_IMPLICIT: u32 = 0;
+const TYPE_UTF8: u32 = 1;
+const TYPE_INTEGER: u32 = 21;
+
+#[derive(Clone, Default)]
+pub(crate) struct Mp4TagPrivate {
+    // items by name, in the order they were read or added
+    items: Vec<(String, Mp4Item)>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    comment: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+    track: Option<u32>,
+}
+
+// The iTunes metadata of a file, stored in the "moov/udta/meta/ilst" atom.
+#[derive(Clone, Default)]
+pub struct Mp4Tag {
+    d: Mp4TagPrivate,
+}
+
+impl Mp4Tag {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    // reads the items of the ilst atom
+    pub fn new<R: Read + Seek>(file: &mut R, ilst: &Mp4Atom) -> Result<Self> {
+        let mut tag = Self::empty();
+
+        for atom in &ilst.children {
+            // Items which cannot be parsed are dropped.
+            let data = atom.read_data(file)?;
+            if let Some((name, item)) = parse_item(&atom.name, &data) {
+                tag.d.items.retain(|(n, _)| *n != name);
+                tag.d.items.push((name, item));
+            }
+        }
+
+        // Genres stored as ID3v1 numbers are kept as names.
+        let gnre = tag.d.items.iter().position(|(name, _)| name == "gnre");
+        if let Some(index) = gnre {
+            let (_, item) = tag.d.items.remove(index);
+            if let (Mp4Item::Int(number), None) = (item, tag.item("\u{a9}gen")) {
+                let name = u8::try_from(number - 1).ok().and_then(id3v1::genre_name);
+                if let Some(name) = name {
+                    let item = Mp4Item::Text(vec![String::from(name)]);
+                    tag.d.items.push((String::from("\u{a9}gen"), item));
+                }
+            }
+        }
+
+        tag.update_fields();
+
+        Ok(tag)
+    }
+
+    // returns the items by name, e.g. "©nam" or "----:com.apple.iTunes:ASIN"
+    pub fn items(&self) -> &[(String, Mp4Item)] {
+        &self.d.items
+    }
+
+    pub fn item(&self, name: &str) -> Option<&Mp4Item> {
+        self.d
+            .items
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, item)| item)
+    }
+
+    // adds `item`, replacing any item with the same name
+    pub fn set_item(&mut self, name: &str, item: Mp4Item) {
+        match self.d.items.iter_mut().find(|(n, _)| n == name) {
+            Some((_, i)) => *i = item,
+            None => self.d.items.push((String::from(name), item)),
+        }
+
+        self.update_fields();
+    }
+
+    pub fn remove_item(&mut self, name: &str) {
+        self.d.items.retain(|(n, _)| n != name);
+        self.update_fields();
+    }
+
+    // renders the ilst atom.  Items with names which are not four Latin-1
+    // characters are skipped.
+    pub fn render(&self) -> Vec<u8> {
+        let items: Vec<u8> = self
+            .d
+            .items
+            .iter()
+            .filter_map(|(name, item)| render_item(name, item))
+            .flatten()
+            .collect();
+
+        render_atom("ilst", &items)
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        match self.item(name) {
+            Some(Mp4Item::Text(values)) => values.first().filter(|v| !v.is_empty()).cloned(),
+            _ => None,
+        }
+    }
+
+    fn set_text(&mut self, name: &str, value: Option<String>) {
+        match value.filter(|value| !value.is_empty()) {
+            Some(value) => self.set_item(name, Mp4Item::Text(vec![value])),
+            None => self.remove_item(name),
+        }
+    }
+
+    fn update_fields(&mut self) {
+        self.d.title = self.text("\u{a9}nam");
+        self.d.artist = self.text("\u{a9}ART");
+        self.d.album = self.text("\u{a9}alb");
+        self.d.comment = self.text("\u{a9}cmt");
+        self.d.genre = self.text("\u{a9}gen");
+        self.d.year = self.text("\u{a9}day").and_then(|date| {
+            let end = date
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(date.len());
+            date[..end].parse().ok().filter(|&year| year > 0)
+        });
+        self.d.track = match self.item("trkn") {
+            Some(&Mp4Item::IntPair(number, _)) if number > 0 => Some(number as u32),
+            _ => None,
+        };
+    }
+
+    // returns the item name of a property key.  Property keys of freeform
+    // items are uppercase, so an existing freeform item is found by
+    // comparing names case-insensitively and keeps its own name.
+    fn property_item_name(&self, key: &str) -> Option<String> {
+        let name = item_name(key)?;
+        if !name.starts_with(ITUNES_FREEFORM_PREFIX) {
+            return Some(name);
+        }
+
+        match self
+            .d
+            .items
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(&name))
+        {
+            Some((n, _)) => Some(n.clone()),
+            None => Some(name),
+        }
+    }
+}
+
+impl Tag for Mp4Tag {
+    // Text, number and boolean items are properties.  Cover art and binary
+    // items are not.
+    fn properties(&self) -> PropertyMap {
+        let mut properties = PropertyMap::new();
+
+        for (name, item) in &self.d.items {
+            let Some(key) = property_key(name) else {
+                continue;
+            };
+
+            let values = match item {
+                Mp4Item::Text(values) => values.clone(),
+                Mp4Item::IntPair(number, 0) => vec![number.to_string()],
+                Mp4Item::IntPair(number, total) => vec![format!("{}/{}", number, total)],
+                Mp4Item::Bool(value) => vec![String::from(if *value { "1" } else { "0" })],
+                Mp4Item::Int(value) => vec![value.to_string()],
+                Mp4Item::Byte(value) => vec![value.to_string()],
+                Mp4Item::UInt(value) => vec![value.to_string()],
+                Mp4Item::LongLong(value) => vec![value.to_string()],
+                Mp4Item::CoverArt(_) | Mp4Item::Binary(_) => continue,
+            };
+            properties.insert(key, values);
+        }
+
+        properties
+    }
+
+    fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
+        for key in properties {
+            if let Some(name) = self.property_item_name(&key) {
+                self.remove_item(&name);
+            }
+        }
+    }
+
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        let mut unsupported = PropertyMap::new();
+
+        // Items which are properties but not in the map are removed.
+        let current = self.properties();
+        for key in current.keys().filter(|key| !properties.contains_key(*key)) {
+            if let Some(name) = self.property_item_name(key) {
+                self.d.items.retain(|(n, _)| *n != name);
+            }
+        }
+
+        for (key, values) in properties {
+            let Some(name) = self.property_item_name(&key) else {
+                unsupported.insert(key, values);
+                continue;
+            };
+            if current.get(&key) == Some(&values) {
+                continue;
+            }
+
+            let first = values.first().map_or("", |value| value.trim());
+            let number = |s: &str| s.trim().parse::<i32>().unwrap_or(0);
+            let item = match name.as_str() {
+                "trkn" | "disk" => {
+                    let (number_part, total_part) = first.split_once('/').unwrap_or((first, ""));
+                    Mp4Item::IntPair(number(number_part), number(total_part))
+                }
+                "cpil" => Mp4Item::Bool(number(first) != 0),
+                "tmpo" => Mp4Item::Int(number(first)),
+                _ => Mp4Item::Text(values.clone()),
+            };
+
+            if values.is_empty() {
+                self.d.items.retain(|(n, _)| *n != name);
+            } else {
+                match self.d.items.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, i)) => *i = item,
+                    None => self.d.items.push((name, item)),
+                }
+            }
+        }
+
+        self.update_fields();
+
+        unsupported
+    }
+
+    fn title(&self) -> &Option<String> {
+        &self.d.title
+    }
+
+    fn artist(&self) -> &Option<String> {
+        &self.d.artist
+    }
+
+    fn album(&self) -> &Option<String> {
+        &self.d.album
+    }
+
+    fn comment(&self) -> &Option<String> {
+        &self.d.comment
+    }
+
+    fn genre(&self) -> &Option<String> {
+        &self.d.genre
+    }
+
+    fn year(&self) -> &Option<u32> {
+        &self.d.year
+    }
+
+    fn track(&self) -> &Option<u32> {
+        &self.d.track
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.set_text("\u{a9}nam", title);
+    }
+
+    fn set_artist(&mut self, artist: Option<String>) {
+        self.set_text("\u{a9}ART", artist);
+    }
+
+    fn set_album(&mut self, album: Option<String>) {
+        self.set_text("\u{a9}alb", album);
+    }
+
+    fn set_comment(&mut self, comment: Option<String>) {
+        self.set_text("\u{a9}cmt", comment);
+    }
+
+    fn set_genre(&mut self, genre: Option<String>) {
+        self.set_text("\u{a9}gen", genre);
+    }
+
+    fn set_year(&mut self, year: Option<u32>) {
+        self.set_text("\u{a9}day", year.map(|year| year.to_string()));
+    }
+
+    // keeps the total number of tracks
+    fn set_track(&mut self, track: Option<u32>) {
+        let total = match self.item("trkn") {
+            Some(&Mp4Item::IntPair(_, total)) => total,
+            _ => 0,
+        };
+
+        match track {
+            Some(track) => self.set_item("trkn", Mp4Item::IntPair(track as i32, total)),
+            None => self.remove_item("trkn"),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.d.items.is_empty()
+    }
+}
+
+// returns the atom name for the four bytes of `name`
+fn atom_name(name: &[u8]) -> String {
+    name.iter().map(|&b| b as char).collect()
+}
+
+// returns the property key of an item name
+fn property_key(name: &str) -> Option<String> {
+    match KEY_MAP.iter().find(|(n, _)| *n == name) {
+        Some((_, key)) => Some(String::from(*key)),
+        None => name
+            .strip_prefix(ITUNES_FREEFORM_PREFIX)
+            .map(|key| key.to_uppercase()),
+    }
+}
+
+// returns the item name of a property key, with unknown keys stored as
+// iTunes freeform items
+fn item_name(key: &str) -> Option<String> {
+    let key = key.to_uppercase();
+
+    match KEY_MAP.iter().find(|(_, k)| *k == key) {
+        Some((name, _)) => Some(String::from(*name)),
+        None if !key.is_empty() && key.is_ascii() => {
+            Some(format!("{}{}", ITUNES_FREEFORM_PREFIX, key))
+        }
+        None => None,
+    }
+}
+
+// Returns the payloads of the data atoms in `data`, with their types.
+// Freeform items start with "mean" and "name" atoms, which are returned as
+// well.
+fn parse_data_atoms(data: &[u8]) -> Vec<(String, u32, &[u8])> {
+    let mut atoms = vec![];
+    let mut pos = 0;
+
+    while pos + 8 <= data.len() {
+        let length = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        if length < 12 || pos + length > data.len() {
+            break;
+        }
+
+        let name = atom_name(&data[pos + 4..pos + 8]);
+        let flags = u32::from_be_bytes(data[pos + 8..pos + 12].try_into().unwrap());
+        // Data atoms have a locale after the type.
+        let payload_start = if name == "data" { 16 } else { 12 };
+        if length >= payload_start {
+            atoms.push((
+                name,
+                flags & 0x00ff_ffff,
+                &data[pos + payload_start..pos + length],
+            ));
+        }
+
+        pos += length;
+    }
+
+    atoms
+}
+
+// Parses the contents of an ilst child into its name and item.
+fn parse_item(name: &str, data: &[u8]) -> Option<(String, Mp4Item)> {
+    let atoms = parse_data_atoms(data);
+    let data_atoms: Vec<(u32, &[u8])> = atoms
+        .iter()
+        .filter(|(n, _, _)| n == "data")
+        .map(|&(_, data_type, payload)| (data_type, payload))
+        .collect();
+    let (first_type, first) = *data_atoms.first()?;
+
+    let be = |bytes: &[u8]| bytes.iter().fold(0i64, |n, &b| (n << 8) | b as i64);
+    let item = match name {
+        "----" => {
+            let text = |n: &str| {
+                atoms
+                    .iter()
+                    .find(|(atom_name, _, _)| atom_name == n)
+                    .map(|(_, _, payload)| String::from_utf8_lossy(payload).into_owned())
+            };
+            let name = format!("----:{}:{}", text("mean")?, text("name")?);
+
+            let item = if first_type == TYPE_UTF8 {
+                Mp4Item::Text(
+                    data_atoms
+                        .iter()
+                        .map(|(_, d)| String::from_utf8_lossy(d).into_owned())
+                        .collect(),
+                )
+            } else {
+                Mp4Item::Binary(data_atoms.iter().map(|(_, d)| d.to_vec()).collect())
+            };
+
+            return Some((name, item));
+        }
+        "trkn" | "disk" if first.len() >= 6 => {
+            Mp4Item::IntPair(be(&first[2..4]) as i32, be(&first[4..6]) as i32)
+        }
+        "cpil" | "pgap" | "pcst" | "hdvd" | "shwm" if !first.is_empty() => {
+            Mp4Item::Bool(first[0] != 0)
+        }
+        "tmpo" | "gnre" | "\u{a9}mvi" | "\u{a9}mvc" if first.len() >= 2 => {
+            Mp4Item::Int(be(&first[..2]) as i16 as i32)
+        }
+        "rtng" | "stik" | "akID" if !first.is_empty() => Mp4Item::Byte(first[0]),
+        "tvsn" | "tves" | "cnID" | "sfID" | "atID" | "geID" | "cmID" if first.len() >= 4 => {
+            Mp4Item::UInt(be(&first[..4]) as u32)
+        }
+        "plID" if first.len() >= 8 => Mp4Item::LongLong(be(&first[..8])),
+        "covr" => Mp4Item::CoverArt(
+            data_atoms
+                .iter()
+                .map(|&(data_type, d)| Mp4CoverArt {
+                    format: Mp4CoverArtFormat::try_from(data_type).ok(),
+                    data: d.to_vec(),
+                })
+                .collect(),
+        ),
+        _ if first_type == TYPE_UTF8 => Mp4Item::Text(
+            data_atoms
+                .iter()
+                .map(|(_, d)| String::from_utf8_lossy(d).into_owned())
+                .collect(),
+        ),
+        _ if first_type == TYPE_INTEGER || first_type == TYPE_IMPLICIT => match first.len() {
+            1 => Mp4Item::Byte(first[0]),
+            2 => Mp4Item::Int(be(first) as i16 as i32),
+            4 => Mp4Item::UInt(be(first) as u32),
+            8 => Mp4Item::LongLong(be(first)),
+            _ => Mp4Item::Binary(data_atoms.iter().map(|(_, d)| d.to_vec()).collect()),
+        },
+        _ => Mp4Item::Binary(data_atoms.iter().map(|(_, d)| d.to_vec()).collect()),
+    };
+
+    Some((String::from(name), item))
+}
+
+// Renders an atom with a 32-bit size.  Names must be four Latin-1 characters.
+fn render_atom(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut atom = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+    atom.extend(name.chars().map(|c| c as u8));
+    atom.extend(data);
+    atom
+}
+
+fn render_data(data_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = data_type.to_be_bytes().to_vec();
+    // the locale, which is always zero
+    data.extend([0; 4]);
+    data.extend(payload);
+    render_atom("data", &data)
+}
+
+// returns a free atom of `length` bytes, including its header
+fn render_padding(length: usize) -> Vec<u8> {
+    render_atom("free", &vec![0; length - 8])
+}
+
+// returns the length of the free atom to add after `length` bytes of tag
+fn padding_length(length: usize) -> usize {
+    (length + 8).next_multiple_of(PADDING_SIZE) - length
+}
+
+// Renders an item as a child of the ilst atom, or returns None if its name
+// cannot be written.
+fn render_item(name: &str, item: &Mp4Item) -> Option<Vec<u8>> {
+    let is_latin1 = |s: &str| s.chars().all(|c| (c as u32) < 0x100);
+
+    let data = match item {
+        Mp4Item::Text(values) => values
+            .iter()
+            .flat_map(|value| render_data(TYPE_UTF8, value.as_bytes()))
+            .collect(),
+        Mp4Item::IntPair(number, total) => {
+            let mut payload = vec![0, 0];
+            payload.extend((*number as u16).to_be_bytes());
+            payload.extend((*total as u16).to_be_bytes());
+            // Track numbers have two more bytes, which iTunes requires.
+            payload.extend([0, 0]);
+            if name == "disk" {
+                payload.truncate(6);
+            }
+            render_data(TYPE_IMPLICIT, &payload)
+        }
+        Mp4Item::Bool(value) => render_data(TYPE_INTEGER, &[*value as u8]),
+        Mp4Item::Int(value) => render_data(TYPE_INTEGER, &(*value as i16).to_be_bytes()),
+        Mp4Item::Byte(value) => render_data(TYPE_INTEGER, &[*value]),
+        Mp4Item::UInt(value) => render_data(TYPE_INTEGER, &value.to_be_bytes()),
+        Mp4Item::LongLong(value) => render_data(TYPE_INTEGER, &value.to_be_bytes()),
+        Mp4Item::CoverArt(pictures) => pictures
+            .iter()
+            .flat_map(|picture| {
+                let data_type = picture.format.map_or(TYPE_IMPLICIT, u32::from);
+                render_data(data_type, &picture.data)
+            })
+            .collect(),
+        Mp4Item::Binary(values) => values
+            .iter()
+            .flat_map(|value| render_data(TYPE_IMPLICIT, value))
+            .collect(),
+    };
+
+    // Freeform items name themselves in "mean" and "name" atoms.
+    if let Some(freeform) = name.strip_prefix("----:") {
+        let (mean, name) = freeform.split_once(':')?;
+
+        let mut children = render_atom("mean", &[&[0; 4], mean.as_bytes()].concat());
+        children.extend(render_atom("name", &[&[0; 4], name.as_bytes()].concat()));
+        children.extend(data);
+        return Some(render_atom("----", &children));
+    }
+
+    if name.chars().count() != 4 || !is_latin1(name) {
+        return None;
+    }
+
+    Some(render_atom(name, &data))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mp4Codec {
+    Unknown,
+    Aac,
+    Alac,
+}
+
+#[derive(Clone)]
+pub(crate) struct Mp4PropertiesPrivate {
+    length: u32,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    codec: Mp4Codec,
+}
+
+#[derive(Clone)]
+pub struct Mp4Properties {
+    d: Mp4PropertiesPrivate,
+}
+
+impl AudioProperties for Mp4Properties {
+    fn length(&self) -> u32 {
+        self.length_in_seconds()
+    }
+
+    fn length_in_seconds(&self) -> u32 {
+        self.d.length / 1000
+    }
+
+    fn length_in_milliseconds(&self) -> u32 {
+        self.d.length
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.d.bitrate
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.d.sample_rate
+    }
+
+    fn channels(&self) -> u32 {
+        self.d.channels
+    }
+}
+
+impl Mp4Properties {
+    // Reads the properties of the first sound track, from its "mdhd" atom and
+    // the sample entry in "stsd".
+    pub(crate) fn new<R: Read + Seek>(file: &mut R, atoms: &Mp4Atoms) -> Result<Self> {
+        let moov = atoms.find(&["moov"]).ok_or(Error::InvalidHeader)?;
+
+        let mut track = None;
+        for trak in moov.find_all("trak") {
+            if let Some(hdlr) = trak.find(&["mdia", "hdlr"]) {
+                let data = hdlr.read_data(file)?;
+                if data.get(8..12) == Some(b"soun") {
+                    track = Some(trak);
+                    break;
+                }
+            }
+        }
+        let track = track.ok_or(Error::NoFrameFound)?;
+
+        let mdhd = track
+            .find(&["mdia", "mdhd"])
+            .ok_or(Error::InvalidHeader)?
+            .read_data(file)?;
+        let (time_scale, duration) = match mdhd.first() {
+            Some(1) if mdhd.len() >= 32 => (
+                u32::from_be_bytes(mdhd[20..24].try_into().unwrap()) as u64,
+                u64::from_be_bytes(mdhd[24..32].try_into().unwrap()),
+            ),
+            Some(0) if mdhd.len() >= 20 => (
+                u32::from_be_bytes(mdhd[12..16].try_into().unwrap()) as u64,
+                u32::from_be_bytes(mdhd[16..20].try_into().unwrap()) as u64,
+            ),
+            _ => return Err(Error::TruncatedHeader),
+        };
+        let length = duration
+            .checked_mul(1000)
+            .and_then(|ms| ms.checked_div(time_scale))
+            .unwrap_or(0) as u32;
+
+        let mut properties = Self {
+            d: Mp4PropertiesPrivate {
+                length,
+                bitrate: 0,
+                sample_rate: 0,
+                channels: 0,
+                bits_per_sample: 0,
+                codec: Mp4Codec::Unknown,
+            },
+        };
+
+        let entry = track
+            .find(&["mdia", "minf", "stbl", "stsd"])
+            .and_then(|stsd| stsd.children.first());
+        if let Some(entry) = entry {
+            // The fields of audio sample entries, counted from the start of
+            // the atom.
+            let data = entry.read_data(file)?;
+            if data.len() >= 28 {
+                let u16_at = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+                properties.d.channels = u16_at(16) as u32;
+                properties.d.bits_per_sample = u16_at(18) as u32;
+                properties.d.sample_rate = u16_at(24) as u32;
+
+                match entry.name.as_str() {
+                    "mp4a" => {
+                        properties.d.codec = Mp4Codec::Aac;
+                        properties.d.bitrate = esds_bitrate(&data[28..]).div_ceil(1000);
+                    }
+                    "alac" => {
+                        properties.d.codec = Mp4Codec::Alac;
+                        properties.read_alac(&data[28..]);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // Fall back to the average over the media data.
+        if properties.d.bitrate == 0 && length > 0 {
+            let mdat_length: u64 = atoms
+                .atoms
+                .iter()
+                .filter(|atom| atom.name == "mdat")
+                .map(|atom| atom.length - atom.header_size)
+                .sum();
+            properties.d.bitrate = (mdat_length * 8 / length as u64) as u32;
+        }
+
+        Ok(properties)
+    }
+
+    // reads the ALACSpecificConfig from the "alac" child of the sample entry
+    fn read_alac(&mut self, data: &[u8]) {
+        if data.len() >= 36 && &data[4..8] == b"alac" {
+            let config = &data[12..];
+            let u32_at =
+                |offset: usize| u32::from_be_bytes(config[offset..offset + 4].try_into().unwrap());
+
+            self.d.bits_per_sample = config[5] as u32;
+            self.d.channels = config[9] as u32;
+            self.d.bitrate = u32_at(16) / 1000;
+            self.d.sample_rate = u32_at(20);
+        }
+    }
+
+    pub fn bits_per_sample(&self) -> u32 {
+        self.d.bits_per_sample
+    }
+
+    pub fn codec(&self) -> Mp4Codec {
+        self.d.codec
+    }
+}
+
+// Returns the average bitrate in b/s from the DecoderConfigDescriptor in the
+// "esds" atom at the start of `data`, or 0 if it is missing.
+fn esds_bitrate(data: &[u8]) -> u32 {
+    if data.len() < 12 || &data[4..8] != b"esds" {
+        return 0;
+    }
+    let length = (u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize).min(data.len());
+    let mut pos = 12;
+
+    // Descriptors have a tag and a length of up to four bytes with seven bits
+    // each.
+    let descriptor = |pos: &mut usize| -> Option<u8> {
+        let tag = *data.get(*pos)?;
+        *pos += 1;
+        for _ in 0..4 {
+            let b = *data.get(*pos)?;
+            *pos += 1;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        Some(tag)
+    };
+
+    // ES_Descriptor: ES_ID and flags, with optional fields which are skipped.
+    if descriptor(&mut pos) == Some(0x03) {
+        let Some(&flags) = data.get(pos + 2) else {
+            return 0;
+        };
+        pos += 3;
+        if flags & 0x80 != 0 {
+            pos += 2;
+        }
+        if flags & 0x40 != 0 {
+            pos += 1 + *data.get(pos).unwrap_or(&0) as usize;
+        }
+        if flags & 0x20 != 0 {
+            pos += 2;
+        }
+    } else {
+        return 0;
+    }
+
+    // DecoderConfigDescriptor: object type, stream type, buffer size, maximum
+    // and average bitrate.
+    if descriptor(&mut pos) != Some(0x04) || pos + 13 > length {
+        return 0;
+    }
+
+    u32::from_be_bytes(data[pos + 9..pos + 13].try_into().unwrap())
+}
+
+pub struct Mp4File {
+    pub tag: Mp4Tag,
+    pub audio_properties: Mp4Properties,
+    path: Option<PathBuf>,
+    atoms: Mp4Atoms,
+}
+
+impl AudioFile for Mp4File {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut mp4_file = Self::read_from(&mut file, style)?;
+        mp4_file.path = Some(path);
+
+        Ok(mp4_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl Mp4File {
+    // Reads the atoms from any seekable stream.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        let atoms = Mp4Atoms::new(file)?;
+        if atoms.find(&["moov"]).is_none() {
+            return Err(Error::InvalidHeader);
+        }
+
+        let tag = match atoms.find(&["moov", "udta", "meta", "ilst"]) {
+            Some(ilst) => Mp4Tag::new(file, ilst)?,
+            None => Mp4Tag::empty(),
+        };
+        let audio_properties = Mp4Properties::new(file, &atoms)?;
+
+        Ok(Self {
+            tag,
+            audio_properties,
+            path: None,
+            atoms,
+        })
+    }
+
+    // Writes the ilst atom.  See `save_to`.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    //
+    // Free atoms next to the ilst atom are used as padding, so that the rest
+    // of the file is only moved when the tag outgrows them.  When it is, the
+    // sizes of the parent atoms are updated, as well as the chunk offsets of
+    // the tracks and fragments pointing past the tag.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        let path = self.atoms.path(&["moov", "udta", "meta", "ilst"]);
+        if path.len() < 4 && self.tag.is_empty() {
+            return Ok(());
+        }
+
+        let ilst = self.tag.render();
+        let (data, offset, length) = if path.len() == 4 {
+            // Replace the ilst atom and the free atoms around it.
+            let meta = &path[2].children;
+            let index = meta.iter().position(|atom| atom.name == "ilst").unwrap();
+            let first = meta[..index]
+                .iter()
+                .rposition(|atom| atom.name != "free")
+                .map_or(0, |i| i + 1);
+            let last = meta[index + 1..]
+                .iter()
+                .position(|atom| atom.name != "free")
+                .map_or(meta.len(), |i| index + 1 + i);
+
+            let offset = meta[first].offset;
+            let length = meta[last - 1].offset + meta[last - 1].length - offset;
+
+            let mut data = ilst;
+            let space = length as usize;
+            if data.len() + 8 <= space {
+                data.extend(render_padding(space - data.len()));
+            } else if data.len() != space {
+                data.extend(render_padding(padding_length(data.len())));
+            }
+
+            (data, offset, length)
+        } else {
+            // Add the missing atoms at the end of their parent.
+            let mut data = ilst;
+            data.extend(render_padding(padding_length(data.len())));
+
+            if path.len() < 3 {
+                let mut hdlr = vec![0; 8];
+                hdlr.extend(b"mdirappl");
+                hdlr.extend([0; 9]);
+
+                let mut meta = vec![0; 4];
+                meta.extend(render_atom("hdlr", &hdlr));
+                meta.extend(data);
+                data = render_atom("meta", &meta);
+            }
+            if path.len() < 2 {
+                data = render_atom("udta", &data);
+            }
+
+            let parent = path.last().ok_or(Error::InvalidHeader)?;
+            (data, parent.offset + parent.length, 0)
+        };
+
+        let delta = data.len() as i64 - length as i64;
+        let parents = &path[..path.len().min(3)];
+
+        // Check the new sizes and offsets before anything is written.
+        for atom in parents {
+            let new_length = (atom.length as i64 + delta) as u64;
+            if atom.header_size == 8 && new_length > u32::MAX as u64 {
+                return Err(Error::UnsupportedFormat);
+            }
+        }
+        let tables = if delta != 0 {
+            self.shift_offsets(file, delta, offset)?
+        } else {
+            vec![]
+        };
+
+        insert_block(file, &data, offset, length)?;
+
+        if delta != 0 {
+            for atom in parents {
+                let new_length = (atom.length as i64 + delta) as u64;
+                if atom.header_size == 8 {
+                    file.seek(SeekFrom::Start(atom.offset))?;
+                    file.write_all(&(new_length as u32).to_be_bytes())?;
+                } else {
+                    file.seek(SeekFrom::Start(atom.offset + 8))?;
+                    file.write_all(&new_length.to_be_bytes())?;
+                }
+            }
+
+            for (position, table) in tables {
+                file.seek(SeekFrom::Start(position))?;
+                file.write_all(&table)?;
+            }
+        }
+
+        self.atoms = Mp4Atoms::new(file)?;
+
+        Ok(())
+    }
+
+    // Returns the contents of the "stco", "co64" and "tfhd" atoms with `delta`
+    // added to the chunk offsets and base data offsets pointing past `offset`,
+    // together with the positions to write them to once the data at `offset`
+    // has been resized.  Fails if a 32-bit chunk offset would overflow.
+    fn shift_offsets<F: Read + Seek>(
+        &self,
+        file: &mut F,
+        delta: i64,
+        offset: u64,
+    ) -> Result<Vec<(u64, Vec<u8>)>> {
+        let shift = |value: u64| {
+            if value > offset {
+                (value as i64 + delta) as u64
+            } else {
+                value
+            }
+        };
+
+        let mut tables = vec![];
+        if let Some(moov) = self.atoms.find(&["moov"]) {
+            for trak in moov.find_all("trak") {
+                let stbl = trak.find(&["mdia", "minf", "stbl"]);
+                tables.extend(stbl.into_iter().flat_map(|stbl| {
+                    stbl.children
+                        .iter()
+                        .filter(|atom| atom.name == "stco" || atom.name == "co64")
+                }));
+            }
+        }
+        for moof in self.atoms.atoms.iter().filter(|atom| atom.name == "moof") {
+            for traf in moof.find_all("traf") {
+                tables.extend(traf.find_all("tfhd"));
+            }
+        }
+
+        let mut shifted = vec![];
+        for atom in tables {
+            file.seek(SeekFrom::Start(atom.offset + atom.header_size))?;
+            let mut data = read_block(file, (atom.length - atom.header_size) as usize)?;
+
+            match atom.name.as_str() {
+                "stco" => {
+                    for entry in data.get_mut(8..).unwrap_or_default().chunks_exact_mut(4) {
+                        let value = u32::from_be_bytes(entry.try_into().unwrap());
+                        let value = u32::try_from(shift(value as u64))
+                            .map_err(|_| Error::UnsupportedFormat)?;
+                        entry.copy_from_slice(&value.to_be_bytes());
+                    }
+                }
+                "co64" => {
+                    for entry in data.get_mut(8..).unwrap_or_default().chunks_exact_mut(8) {
+                        let value = u64::from_be_bytes(entry.try_into().unwrap());
+                        entry.copy_from_slice(&shift(value).to_be_bytes());
+                    }
+                }
+                _ => {
+                    // The base data offset follows the track ID when the
+                    // first flag is set.
+                    if data.len() < 16 || data[3] & 0x01 == 0 {
+                        continue;
+                    }
+                    let value = u64::from_be_bytes(data[8..16].try_into().unwrap());
+                    data[8..16].copy_from_slice(&shift(value).to_be_bytes());
+                }
+            }
+
+            shifted.push((shift(atom.offset) + atom.header_size, data));
+        }
+
+        Ok(shifted)
+    }
+
+    pub fn atoms(&self) -> &Mp4Atoms {
+        &self.atoms
+    }
+
+    // returns true if the file has an ilst atom
+    pub fn has_mp4_tag(&self) -> bool {
+        self.atoms.find(&["moov", "udta", "meta", "ilst"]).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn atom(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut atom = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend(name.chars().map(|c| c as u8));
+        atom.extend(data);
+        atom
+    }
+
+    fn data_atom(data_type: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = data_type.to_be_bytes().to_vec();
+        data.extend([0; 4]);
+        data.extend(payload);
+        atom("data", &data)
+    }
+
+    fn ilst() -> Vec<u8> {
+        let mut items = atom("\u{a9}nam", &data_atom(1, b"Title"));
+        items.extend(atom("\u{a9}ART", &data_atom(1, b"Artist")));
+        items.extend(atom("trkn", &data_atom(0, &[0, 0, 0, 3, 0, 12, 0, 0])));
+        items.extend(atom("cpil", &data_atom(21, &[1])));
+        items.extend(atom("tmpo", &data_atom(21, &[0, 120])));
+        items.extend(atom("gnre", &data_atom(0, &[0, 18])));
+        items.extend(atom("covr", &data_atom(14, b"\x89PNG")));
+
+        let mut freeform = atom("mean", b"\0\0\0\0com.apple.iTunes");
+        freeform.extend(atom("name", b"\0\0\0\0MusicBrainz Track Id"));
+        freeform.extend(data_atom(1, b"d5f4"));
+        items.extend(atom("----", &freeform));
+
+        atom("ilst", &items)
+    }
+
+    // an AAC track of 5 seconds at 44100 Hz, with an average bitrate of
+    // 128 kb/s in its decoder configuration, followed by the media data and a
+    // fragment pointing to it
+    //
+    // The udta atom comes before the track, so that its chunk offset table
+    // moves when the tag grows.  Without an ilst atom there is no udta atom.
+    fn mp4_file(ilst: &[u8], mdat_size: usize) -> Vec<u8> {
+        let mut mdhd = vec![0; 12];
+        mdhd.extend(44100u32.to_be_bytes());
+        mdhd.extend((44100u32 * 5).to_be_bytes());
+        mdhd.extend([0; 4]);
+
+        let mut hdlr = vec![0; 8];
+        hdlr.extend(b"soun");
+        hdlr.extend([0; 13]);
+
+        let mut esds = vec![0; 4];
+        esds.extend([0x03, 0x19, 0, 1, 0]);
+        esds.extend([0x04, 0x11, 0x40, 0x15, 0, 0, 0]);
+        esds.extend(128_000u32.to_be_bytes());
+        esds.extend(128_000u32.to_be_bytes());
+        let mut mp4a = vec![0; 6];
+        mp4a.extend([0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        mp4a.extend([0, 2, 0, 16, 0, 0, 0, 0]);
+        mp4a.extend([0xac, 0x44, 0, 0]);
+        mp4a.extend(atom("esds", &esds));
+
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(atom("mp4a", &mp4a));
+        let mut stbl = atom("stsd", &stsd);
+        stbl.extend(atom("stco", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]));
+        let minf = atom("minf", &atom("stbl", &stbl));
+
+        let mut mdia = atom("mdhd", &mdhd);
+        mdia.extend(atom("hdlr", &hdlr));
+        mdia.extend(minf);
+        let trak = atom("trak", &atom("mdia", &mdia));
+
+        let mut moov = vec![];
+        if !ilst.is_empty() {
+            let mut meta = vec![0; 4];
+            meta.extend(atom("hdlr", &[0; 25]));
+            meta.extend(ilst);
+            moov.extend(atom("udta", &atom("meta", &meta)));
+        }
+        moov.extend(trak);
+
+        let mut data = atom("ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        data.extend(atom("moov", &moov));
+        let media_offset = data.len() as u64 + 8;
+        data.extend(atom("mdat", &vec![0; mdat_size]));
+
+        let mut tfhd = vec![0, 0, 0, 1, 0, 0, 0, 1];
+        tfhd.extend(media_offset.to_be_bytes());
+        data.extend(atom("moof", &atom("traf", &atom("tfhd", &tfhd))));
+
+        let stco = data.windows(4).position(|w| w == b"stco").unwrap();
+        data[stco + 12..stco + 16].copy_from_slice(&(media_offset as u32).to_be_bytes());
+        data
+    }
+
+    // returns the offset of the media data, and the offsets pointing to it
+    // from the chunk offset table and the fragment
+    fn media_offsets(data: &[u8]) -> (u64, u64, u64) {
+        let mut cursor = Cursor::new(data.to_vec());
+        let atoms = Mp4Atoms::new(&mut cursor).unwrap();
+
+        let mdat = atoms.find(&["mdat"]).unwrap();
+        let stco = atoms
+            .find(&["moov", "trak", "mdia", "minf", "stbl", "stco"])
+            .unwrap()
+            .read_data(&mut cursor)
+            .unwrap();
+        let tfhd = atoms
+            .find(&["moof", "traf", "tfhd"])
+            .unwrap()
+            .read_data(&mut cursor)
+            .unwrap();
+
+        (
+            mdat.offset() + 8,
+            u32::from_be_bytes(stco[8..12].try_into().unwrap()) as u64,
+            u64::from_be_bytes(tfhd[8..16].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_atoms() {
+        let mut cursor = Cursor::new(mp4_file(&ilst(), 100));
+        let atoms = Mp4Atoms::new(&mut cursor).unwrap();
+
+        assert_eq!(atoms.atoms().len(), 4);
+        let ilst = atoms.find(&["moov", "udta", "meta", "ilst"]).unwrap();
+        assert_eq!(ilst.children().len(), 8);
+        assert_eq!(ilst.children()[0].name(), "\u{a9}nam");
+        assert_eq!(atoms.path(&["moov", "udta", "meta", "ilst"]).len(), 4);
+        assert_eq!(atoms.path(&["moov", "trak", "edts"]).len(), 2);
+
+        // 64-bit sizes
+        let mut data = vec![0, 0, 0, 1];
+        data.extend(b"free");
+        data.extend(24u64.to_be_bytes());
+        data.extend([0; 8]);
+        data.extend(atom("moov", &[]));
+        let atoms = Mp4Atoms::new(&mut Cursor::new(data)).unwrap();
+        assert_eq!(atoms.atoms()[0].length(), 24);
+        assert_eq!(atoms.atoms()[1].offset(), 24);
+
+        let result = Mp4Atoms::new(&mut Cursor::new(b"\0\0\0\x40moov".to_vec()));
+        assert!(matches!(result, Err(Error::InvalidHeader)));
+    }
+
+    #[test]
+    fn test_read() {
+        let mut cursor = Cursor::new(mp4_file(&ilst(), 100));
+        let file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        let properties = &file.audio_properties;
+        assert_eq!(properties.codec(), Mp4Codec::Aac);
+        assert_eq!(properties.length_in_milliseconds(), 5000);
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.bits_per_sample(), 16);
+        assert_eq!(properties.bitrate(), 128);
+
+        let tag = &file.tag;
+        assert!(file.has_mp4_tag());
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(*tag.track(), Some(3));
+        assert_eq!(tag.genre().as_deref(), Some("Rock"));
+        assert_eq!(tag.item("cpil"), Some(&Mp4Item::Bool(true)));
+        assert_eq!(tag.item("tmpo"), Some(&Mp4Item::Int(120)));
+        assert_eq!(
+            tag.item("covr"),
+            Some(&Mp4Item::CoverArt(vec![Mp4CoverArt {
+                format: Some(Mp4CoverArtFormat::Png),
+                data: b"\x89PNG".to_vec(),
+            }]))
+        );
+
+        let properties = tag.properties();
+        assert_eq!(properties["TRACKNUMBER"], ["3/12"]);
+        assert_eq!(properties["COMPILATION"], ["1"]);
+        assert_eq!(properties["BPM"], ["120"]);
+        assert_eq!(properties["MUSICBRAINZ_TRACKID"], ["d5f4"]);
+        assert!(!properties.contains_key("COVERART"));
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let mut cursor = Cursor::new(mp4_file(&ilst(), 100));
+        let mut tag = Mp4File::read_from(&mut cursor, ReadStyle::Average)
+            .unwrap()
+            .tag;
+
+        let mut properties = tag.properties();
+        properties.remove("COMPILATION");
+        properties.insert(String::from("DISCNUMBER"), vec![String::from("1/2")]);
+        properties.insert(String::from("MOOD"), vec![String::from("Calm")]);
+        assert!(tag.set_properties(properties).is_empty());
+
+        assert!(tag.item("cpil").is_none());
+        assert_eq!(tag.item("disk"), Some(&Mp4Item::IntPair(1, 2)));
+        assert_eq!(
+            tag.item("----:com.apple.iTunes:MOOD"),
+            Some(&Mp4Item::Text(vec![String::from("Calm")]))
+        );
+        assert!(tag.item("covr").is_some());
+
+        tag.set_track(Some(4));
+        assert_eq!(tag.item("trkn"), Some(&Mp4Item::IntPair(4, 12)));
+    }
+
+    #[test]
+    fn test_set_properties_lowercase_freeform() {
+        let mut freeform = atom("mean", b"\0\0\0\0com.apple.iTunes");
+        freeform.extend(atom("name", b"\0\0\0\0replaygain_track_gain"));
+        freeform.extend(data_atom(1, b"-6.5 dB"));
+        let ilst = atom("ilst", &atom("----", &freeform));
+
+        let mut cursor = Cursor::new(mp4_file(&ilst, 100));
+        let mut tag = Mp4File::read_from(&mut cursor, ReadStyle::Average)
+            .unwrap()
+            .tag;
+        let name = "----:com.apple.iTunes:replaygain_track_gain";
+        assert_eq!(tag.properties()["REPLAYGAIN_TRACK_GAIN"], ["-6.5 dB"]);
+
+        let mut properties = PropertyMap::new();
+        properties.insert(
+            String::from("REPLAYGAIN_TRACK_GAIN"),
+            vec![String::from("-3 dB")],
+        );
+        tag.set_properties(properties);
+        assert_eq!(tag.items().len(), 1);
+        assert_eq!(
+            tag.item(name),
+            Some(&Mp4Item::Text(vec![String::from("-3 dB")]))
+        );
+
+        tag.set_properties(PropertyMap::new());
+        assert!(tag.is_empty());
+    }
+
+    #[test]
+    fn test_save() {
+        let mut cursor = Cursor::new(mp4_file(&ilst(), 100));
+        let mut file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        // The tag outgrows its atom, so the media data moves.
+        let title = "x".repeat(3000);
+        file.tag.set_title(Some(title.clone()));
+        file.tag.set_item("cpil", Mp4Item::Bool(false));
+        file.save_to(&mut cursor).unwrap();
+
+        let (media, stco, tfhd) = media_offsets(cursor.get_ref());
+        assert_eq!(stco, media);
+        assert_eq!(tfhd, media);
+
+        let mut file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some(title.as_str()));
+        assert_eq!(file.tag.item("cpil"), Some(&Mp4Item::Bool(false)));
+        assert_eq!(file.tag.item("trkn"), Some(&Mp4Item::IntPair(3, 12)));
+        assert_eq!(file.tag.properties()["MUSICBRAINZ_TRACKID"], ["d5f4"]);
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 5000);
+
+        let meta = file.atoms().find(&["moov", "udta", "meta"]).unwrap();
+        let ilst = meta.find(&["ilst"]).unwrap();
+        let free = meta.find(&["free"]).unwrap();
+        assert_eq!((ilst.length() + free.length()) % PADDING_SIZE as u64, 0);
+
+        // The smaller tag fits in the padding, so nothing else moves.
+        let length = cursor.get_ref().len();
+        file.tag.set_title(Some(String::from("Title")));
+        file.tag.remove_item("covr");
+        file.save_to(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref().len(), length);
+        assert_eq!(media_offsets(cursor.get_ref()), (media, media, media));
+
+        let file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        assert!(file.tag.item("covr").is_none());
+    }
+
+    #[test]
+    fn test_save_chunk_offset_overflow() {
+        let mut data = mp4_file(&ilst(), 100);
+        let stco = data.windows(4).position(|w| w == b"stco").unwrap();
+        data[stco + 12..stco + 16].copy_from_slice(&(u32::MAX - 10).to_be_bytes());
+        let mut cursor = Cursor::new(data.clone());
+
+        let mut file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        file.tag.set_title(Some("x".repeat(3000)));
+        assert!(matches!(
+            file.save_to(&mut cursor),
+            Err(Error::UnsupportedFormat)
+        ));
+        assert_eq!(cursor.get_ref(), &data);
+    }
+
+    #[test]
+    fn test_save_new_tag() {
+        let mut cursor = Cursor::new(mp4_file(&[], 100));
+        let mut file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(!file.has_mp4_tag());
+
+        file.tag.set_artist(Some(String::from("Artist")));
+        file.tag.set_item(
+            "----:com.apple.iTunes:MOOD",
+            Mp4Item::Text(vec![String::from("Calm")]),
+        );
+        file.save_to(&mut cursor).unwrap();
+
+        let (media, stco, tfhd) = media_offsets(cursor.get_ref());
+        assert_eq!((stco, tfhd), (media, media));
+
+        let file = Mp4File::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.has_mp4_tag());
+        assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(file.tag.properties()["MOOD"], ["Calm"]);
+        assert!(file
+            .atoms()
+            .find(&["moov", "udta", "meta", "hdlr"])
+            .is_some());
+    }
+}
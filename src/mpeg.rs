@@ -1,9 +1,19 @@
 use std::{
-    fs::File,
-    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::BitOr,
+    path::{Path, PathBuf},
 };
 
-use crate::{utils::byte_vec_find, AudioFile};
+use crate::{
+    ape::{self, ApeTag},
+    error::{Error, Result},
+    id3v1::ID3v1Tag,
+    id3v2::ID3v2Tag,
+    lyrics3::{self, Lyrics3Tag},
+    utils::{byte_vec_find, insert_block, read_block, Truncate},
+    AudioFile,
+};
 
 use super::{
     audio_properties::{AudioProperties, ReadStyle},
@@ -12,13 +22,65 @@ use super::{
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+// the padding added when an ID3v2 tag has to grow
+const DEFAULT_ID3V2_PADDING_SIZE: usize = 1024;
+
+// A set of the tag types found in MPEG files, e.g. `TagTypes::ID3V1 |
+// TagTypes::ID3V2`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagTypes(u32);
+
+impl TagTypes {
+    pub const NONE: TagTypes = TagTypes(0);
+    pub const ID3V1: TagTypes = TagTypes(0x0001);
+    pub const ID3V2: TagTypes = TagTypes(0x0002);
+    pub const LYRICS3: TagTypes = TagTypes(0x0004);
+    pub const APE: TagTypes = TagTypes(0x0008);
+    pub const ALL: TagTypes = TagTypes(0xffff);
+
+    // returns true if all types of `other` are in the set
+    pub fn contains(self, other: TagTypes) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for TagTypes {
+    type Output = TagTypes;
+
+    fn bitor(self, rhs: TagTypes) -> TagTypes {
+        TagTypes(self.0 | rhs.0)
+    }
+}
+
 pub struct MpegFile {
     pub tag: MpegTag,
     pub audio_properties: MpegProperties,
+    path: Option<PathBuf>,
+    id3v2_padding_size: usize,
+    id3v2_location: Option<u64>,
+    id3v2_original_size: u64,
+    id3v1_location: Option<u64>,
+    id3v1_size: u64,
+    lyrics3_location: Option<u64>,
+    lyrics3_size: u64,
+    ape_location: Option<u64>,
+    ape_original_size: u64,
+    id3v2_tag: Option<ID3v2Tag>,
+    id3v1_tag: Option<ID3v1Tag>,
+    lyrics3_tag: Option<Lyrics3Tag>,
+    ape_tag: Option<ApeTag>,
 }
 
 impl AudioFile for MpegFile {
-    fn new() {}
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut mpeg_file = Self::read_from(&mut file, style)?;
+        mpeg_file.path = Some(path);
+
+        Ok(mpeg_file)
+    }
 
     fn tag(&self) -> Box<dyn Tag> {
         Box::from(self.tag.clone())
@@ -29,7 +91,487 @@ impl AudioFile for MpegFile {
     }
 }
 
-#[derive(Clone)]
+impl MpegFile {
+    // Reads the tags and audio properties from any seekable stream, e.g. a
+    // `Cursor` over data held in memory.  Files read this way have no path, so
+    // they are saved with `save_to`.
+    pub fn read_from<R: Read + Seek>(file: &mut R, style: ReadStyle) -> Result<Self> {
+        let file_length = file.seek(SeekFrom::End(0))?;
+
+        // Look for an ID3v2 tag at the beginning of the file.  A tag which
+        // cannot be read is an error, as saving would otherwise put a new tag in
+        // front of it.
+        let id3v2_tag = match ID3v2Tag::new(file, 0) {
+            Ok(tag) => Some(tag),
+            Err(Error::NoTagFound) => None,
+            Err(error) => return Err(error),
+        };
+
+        let id3v2_location = id3v2_tag.as_ref().map(|_| 0);
+        let id3v2_original_size = id3v2_tag
+            .as_ref()
+            .map_or(0, |tag| tag.header().complete_tag_size() as u64);
+
+        // Look for an ID3v1 tag in the last 128 bytes of the file.  Its
+        // location includes an extended "TAG+" block before it.
+        let id3v1_tag = match find_id3v1(file, file_length)? {
+            Some(location) => Some(ID3v1Tag::new(file, location as usize)?),
+            None => None,
+        };
+        let id3v1_location = id3v1_tag.as_ref().map(|tag| tag.offset());
+        let id3v1_size = id3v1_location.map_or(0, |location| file_length - location);
+
+        // Look for a Lyrics3 block right before the ID3v1 tag.  A block which
+        // cannot be parsed is kept in the file as it is.
+        let trailing_tags_start = id3v1_location.unwrap_or(file_length);
+        let (lyrics3_location, lyrics3_size, lyrics3_tag) =
+            match lyrics3::find(file, trailing_tags_start)? {
+                Some((location, size)) => (
+                    Some(location),
+                    size,
+                    Lyrics3Tag::new(file, location, size).ok(),
+                ),
+                None => (None, 0, None),
+            };
+
+        // Look for an APE tag before those, or at the end of the file if there
+        // are none.  A tag which cannot be read is treated as absent, but its
+        // location is kept so that saving replaces it.
+        let (ape_location, ape_original_size, ape_tag) =
+            match ape::find(file, lyrics3_location.unwrap_or(trailing_tags_start))? {
+                Some((location, size)) => {
+                    (Some(location), size, ApeTag::new(file, location, size).ok())
+                }
+                None => (None, 0, None),
+            };
+
+        // The audio stream starts with the first valid frame after the ID3v2 tag.
+        let first_frame_offset =
+            next_frame_offset(file, id3v2_original_size)?.ok_or(Error::NoFrameFound)?;
+
+        // The audio stream ends where the trailing tags begin.
+        let stream_end = ape_location
+            .or(lyrics3_location)
+            .unwrap_or(trailing_tags_start);
+
+        let audio_properties = MpegProperties::new(file, first_frame_offset, stream_end, style)?;
+
+        let mut file = Self {
+            tag: MpegTag::default(),
+            audio_properties,
+            path: None,
+            id3v2_padding_size: DEFAULT_ID3V2_PADDING_SIZE,
+            id3v2_location,
+            id3v2_original_size,
+            id3v1_location,
+            id3v1_size,
+            lyrics3_location,
+            lyrics3_size,
+            ape_location,
+            ape_original_size,
+            id3v2_tag,
+            id3v1_tag,
+            lyrics3_tag,
+            ape_tag,
+        };
+        file.tag = file.merged_tag();
+
+        Ok(file)
+    }
+
+    // Writes `tag` to the ID3v2 and ID3v1 tags of the file, creating them if
+    // necessary, and updates an existing APE tag.  Empty tags are removed from
+    // the file.
+    pub fn save(&mut self) -> Result<()> {
+        self.save_tags(self.default_tags())
+    }
+
+    // Like `save`, but only writes the given tag types.  Tags of other types
+    // are left as they are.
+    pub fn save_tags(&mut self, tags: TagTypes) -> Result<()> {
+        let mut file = self.open_for_writing()?;
+
+        self.save_tags_to(&mut file, tags)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        self.save_tags_to(file, self.default_tags())
+    }
+
+    // Like `save_tags`, but writes to `file`, which must contain the data this
+    // file was read from.
+    pub fn save_tags_to<F: Read + Write + Seek + Truncate>(
+        &mut self,
+        file: &mut F,
+        tags: TagTypes,
+    ) -> Result<()> {
+        // Only the properties changed since the file was read are written, so
+        // that each tag keeps the items the others cannot hold.
+        let changes = self.tag.property_changes();
+        let default_tags = self.default_tags();
+
+        if tags.contains(TagTypes::ID3V2) {
+            let mut id3v2_tag = self.id3v2_tag.take().unwrap_or_else(ID3v2Tag::empty);
+            self.tag.apply_to(&mut id3v2_tag, &changes);
+            self.write_id3v2(file, id3v2_tag)?;
+        }
+
+        if tags.contains(TagTypes::APE) {
+            let mut ape_tag = self.ape_tag.take().unwrap_or_else(ApeTag::empty);
+            self.tag.apply_to(&mut ape_tag, &changes);
+            self.write_ape(file, ape_tag)?;
+        }
+
+        if tags.contains(TagTypes::LYRICS3) {
+            let mut lyrics3_tag = self.lyrics3_tag.take().unwrap_or_else(Lyrics3Tag::empty);
+            self.tag.apply_to(&mut lyrics3_tag, &changes);
+            self.write_lyrics3(file, lyrics3_tag)?;
+        }
+
+        if tags.contains(TagTypes::ID3V1) {
+            let mut id3v1_tag = self.id3v1_tag.take().unwrap_or_else(ID3v1Tag::empty);
+            self.tag.apply_to(&mut id3v1_tag, &changes);
+            self.write_id3v1(file, id3v1_tag)?;
+        }
+
+        // The changes stay pending until all tags holding the properties have
+        // been written.
+        if tags.contains(default_tags) {
+            self.tag = self.merged_tag();
+        }
+
+        Ok(())
+    }
+
+    // Removes the given tag types from the file.  Tags of other types are
+    // kept.
+    pub fn strip(&mut self, tags: TagTypes) -> Result<()> {
+        let mut file = self.open_for_writing()?;
+
+        self.strip_from(&mut file, tags)
+    }
+
+    // Like `strip`, but removes the tags from `file`, which must contain the
+    // data this file was read from.
+    pub fn strip_from<F: Read + Write + Seek + Truncate>(
+        &mut self,
+        file: &mut F,
+        tags: TagTypes,
+    ) -> Result<()> {
+        if tags.contains(TagTypes::ID3V2) {
+            self.write_id3v2(file, ID3v2Tag::empty())?;
+        }
+
+        if tags.contains(TagTypes::APE) {
+            self.write_ape(file, ApeTag::empty())?;
+        }
+
+        if tags.contains(TagTypes::LYRICS3) {
+            self.write_lyrics3(file, Lyrics3Tag::empty())?;
+        }
+
+        if tags.contains(TagTypes::ID3V1) {
+            self.write_id3v1(file, ID3v1Tag::empty())?;
+        }
+
+        Ok(())
+    }
+
+    // returns the tags of the file combined in order of precedence
+    fn merged_tag(&self) -> MpegTag {
+        // The Lyrics3 fields hold the untruncated values of the ID3v1 ones.
+        MpegTag::merge(&[
+            self.id3v2_tag.as_ref().map(|t| t as &dyn Tag),
+            self.ape_tag.as_ref().map(|t| t as &dyn Tag),
+            self.lyrics3_tag.as_ref().map(|t| t as &dyn Tag),
+            self.id3v1_tag.as_ref().map(|t| t as &dyn Tag),
+        ])
+    }
+
+    // returns the tag types written by `save`
+    fn default_tags(&self) -> TagTypes {
+        if self.has_ape_tag() {
+            TagTypes::ID3V1 | TagTypes::ID3V2 | TagTypes::APE
+        } else {
+            TagTypes::ID3V1 | TagTypes::ID3V2
+        }
+    }
+
+    fn open_for_writing(&self) -> Result<File> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+
+        Ok(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+
+    // Writes the ID3v2 tag at the beginning of the file, or removes it if it is
+    // empty.
+    //
+    // If the rendered tag fits into the space of the existing tag, the padding
+    // is reused and the audio data is left untouched.  Otherwise the tag grows
+    // by the configured padding size, so that later edits fit again.
+    fn write_id3v2<F: Read + Write + Seek + Truncate>(
+        &mut self,
+        file: &mut F,
+        mut id3v2_tag: ID3v2Tag,
+    ) -> Result<()> {
+        let data = if id3v2_tag.is_empty() {
+            vec![]
+        } else {
+            let frames_size = id3v2_tag.render(0).len() as u64;
+            let padding_size =
+                if self.id3v2_location.is_some() && frames_size <= self.id3v2_original_size {
+                    self.id3v2_original_size - frames_size
+                } else {
+                    self.id3v2_padding_size as u64
+                };
+
+            id3v2_tag.render(padding_size as usize)
+        };
+
+        insert_block(file, &data, 0, self.id3v2_original_size)?;
+
+        // Everything after the ID3v2 tag has moved by the change in size.
+        let new_size = data.len() as u64;
+        let shift = |location: u64| location + new_size - self.id3v2_original_size;
+        self.id3v1_location = self.id3v1_location.map(shift);
+        self.lyrics3_location = self.lyrics3_location.map(shift);
+        self.ape_location = self.ape_location.map(shift);
+
+        self.id3v2_original_size = new_size;
+        if data.is_empty() {
+            self.id3v2_location = None;
+            self.id3v2_tag = None;
+        } else {
+            self.id3v2_location = Some(0);
+            self.id3v2_tag = Some(id3v2_tag);
+        }
+
+        Ok(())
+    }
+
+    // Writes the APE tag over the existing one, or inserts it before the
+    // Lyrics3 block and the ID3v1 tag.  An empty tag is removed.
+    fn write_ape<F: Read + Write + Seek + Truncate>(
+        &mut self,
+        file: &mut F,
+        mut ape_tag: ApeTag,
+    ) -> Result<()> {
+        let (location, replace) = match self.ape_location {
+            Some(location) => (location, self.ape_original_size),
+            None => match self.lyrics3_location.or(self.id3v1_location) {
+                Some(location) => (location, 0),
+                None => (file.seek(SeekFrom::End(0))?, 0),
+            },
+        };
+
+        let data = if ape_tag.is_empty() {
+            vec![]
+        } else {
+            ape_tag.render()
+        };
+
+        insert_block(file, &data, location, replace)?;
+
+        // The tags after the APE tag have moved by the change in size.
+        let new_size = data.len() as u64;
+        let shift = |location: u64| location + new_size - replace;
+        self.lyrics3_location = self.lyrics3_location.map(shift);
+        self.id3v1_location = self.id3v1_location.map(shift);
+
+        if data.is_empty() {
+            self.ape_location = None;
+            self.ape_original_size = 0;
+            self.ape_tag = None;
+        } else {
+            self.ape_location = Some(location);
+            self.ape_original_size = new_size;
+            self.ape_tag = Some(ape_tag);
+        }
+
+        Ok(())
+    }
+
+    // Writes the Lyrics3 block over the existing one, or inserts it before the
+    // ID3v1 tag.  An empty block is removed.
+    fn write_lyrics3<F: Read + Write + Seek + Truncate>(
+        &mut self,
+        file: &mut F,
+        lyrics3_tag: Lyrics3Tag,
+    ) -> Result<()> {
+        let (location, replace) = match (self.lyrics3_location, self.id3v1_location) {
+            (Some(location), _) => (location, self.lyrics3_size),
+            (None, Some(location)) => (location, 0),
+            (None, None) => (file.seek(SeekFrom::End(0))?, 0),
+        };
+
+        let data = if lyrics3_tag.is_empty() {
+            vec![]
+        } else {
+            lyrics3_tag.render()
+        };
+
+        insert_block(file, &data, location, replace)?;
+
+        // The ID3v1 tag after the block has moved by the change in size.
+        let new_size = data.len() as u64;
+        self.id3v1_location = self
+            .id3v1_location
+            .map(|id3v1_location| id3v1_location + new_size - replace);
+
+        if data.is_empty() {
+            self.lyrics3_location = None;
+            self.lyrics3_size = 0;
+            self.lyrics3_tag = None;
+        } else {
+            self.lyrics3_location = Some(location);
+            self.lyrics3_size = new_size;
+            self.lyrics3_tag = Some(lyrics3_tag);
+        }
+
+        Ok(())
+    }
+
+    // Writes the ID3v1 tag over the existing one, and appends it otherwise.  An
+    // extended "TAG+" block is written before it if the tag needs one.  An
+    // empty tag is removed by truncating the file.
+    fn write_id3v1<F: Read + Write + Seek + Truncate>(
+        &mut self,
+        file: &mut F,
+        id3v1_tag: ID3v1Tag,
+    ) -> Result<()> {
+        let (location, replace) = match self.id3v1_location {
+            Some(location) => (location, self.id3v1_size),
+            None => (file.seek(SeekFrom::End(0))?, 0),
+        };
+
+        if id3v1_tag.is_empty() {
+            insert_block(file, &[], location, replace)?;
+            self.id3v1_location = None;
+            self.id3v1_size = 0;
+            self.id3v1_tag = None;
+        } else {
+            let mut data = id3v1_tag.render_extended().unwrap_or_default();
+            data.extend(id3v1_tag.render());
+
+            insert_block(file, &data, location, replace)?;
+            self.id3v1_location = Some(location);
+            self.id3v1_size = data.len() as u64;
+            self.id3v1_tag = Some(id3v1_tag);
+        }
+
+        Ok(())
+    }
+
+    // sets the padding added after the ID3v2 frames when the tag has to grow
+    pub fn set_id3v2_padding_size(&mut self, size: usize) {
+        self.id3v2_padding_size = size;
+    }
+
+    // returns true if the file has an ID3v2 tag at its beginning
+    pub fn has_id3v2_tag(&self) -> bool {
+        self.id3v2_location.is_some()
+    }
+
+    // returns true if the file has an ID3v1 tag in its last 128 bytes
+    pub fn has_id3v1_tag(&self) -> bool {
+        self.id3v1_location.is_some()
+    }
+
+    // returns true if the file has a Lyrics3 block before the ID3v1 tag
+    pub fn has_lyrics3_tag(&self) -> bool {
+        self.lyrics3_location.is_some()
+    }
+
+    // returns true if the file has an APE tag before the ID3v1 tag or at its end
+    pub fn has_ape_tag(&self) -> bool {
+        self.ape_location.is_some()
+    }
+
+    // returns the ID3v2 tag of the file, if there is one
+    pub fn id3v2_tag(&self) -> Option<&ID3v2Tag> {
+        self.id3v2_tag.as_ref()
+    }
+
+    // returns the ID3v1 tag of the file, if there is one
+    pub fn id3v1_tag(&self) -> Option<&ID3v1Tag> {
+        self.id3v1_tag.as_ref()
+    }
+
+    // returns the Lyrics3 block of the file, if there is one
+    pub fn lyrics3_tag(&self) -> Option<&Lyrics3Tag> {
+        self.lyrics3_tag.as_ref()
+    }
+
+    // returns the APE tag of the file, if there is one
+    pub fn ape_tag(&self) -> Option<&ApeTag> {
+        self.ape_tag.as_ref()
+    }
+
+    // returns the size of the ID3v2 tag, including header, padding and footer
+    pub fn id3v2_size(&self) -> u64 {
+        self.id3v2_original_size
+    }
+
+    // returns the size of the APE tag, including header and footer
+    pub fn ape_size(&self) -> u64 {
+        self.ape_original_size
+    }
+}
+
+// Returns the offset of the ID3v1 tag if the last 128 bytes of the file start
+// with "TAG".
+fn find_id3v1<R: Read + Seek>(file: &mut R, file_length: u64) -> Result<Option<u64>> {
+    if file_length < 128 {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(file_length - 128))?;
+    let data = read_block(file, 3)?;
+
+    if data == b"TAG" {
+        Ok(Some(file_length - 128))
+    } else {
+        Ok(None)
+    }
+}
+
+// Returns the offset of the first valid MPEG frame at or after `position`.
+fn next_frame_offset<R: Read + Seek>(file: &mut R, mut position: u64) -> Result<Option<u64>> {
+    const BUFFER_SIZE: usize = 1024;
+
+    // The sync bytes may span two buffers, so the last byte is carried over.
+    let mut frame_sync_bytes = [0u8; 2];
+
+    loop {
+        file.seek(SeekFrom::Start(position))?;
+        let buffer = read_block(file, BUFFER_SIZE)?;
+
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+
+        for (i, &b) in buffer.iter().enumerate() {
+            frame_sync_bytes[0] = frame_sync_bytes[1];
+            frame_sync_bytes[1] = b;
+
+            if is_frame_sync(&frame_sync_bytes, 0) && position + i as u64 >= 1 {
+                let offset = position + i as u64 - 1;
+
+                if let Ok(header) = MpegHeader::new(file, offset, true) {
+                    if header.is_valid() {
+                        return Ok(Some(offset));
+                    }
+                }
+            }
+        }
+
+        position += buffer.len() as u64;
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct MpegTag {
     title: Option<String>,
     artist: Option<String>,
@@ -39,21 +581,144 @@ pub struct MpegTag {
     year: Option<u32>,
     track: Option<u32>,
     property_map: PropertyMap,
+    // the properties as they were last read or written
+    saved_properties: PropertyMap,
+}
+
+impl MpegTag {
+    // Combines the tags of a file.  Each value and property is taken from the
+    // first tag that has it, so `tags` should be ordered by precedence.
+    pub(crate) fn merge(tags: &[Option<&dyn Tag>]) -> Self {
+        let mut tag = Self::default();
+
+        for t in tags.iter().flatten() {
+            for (key, values) in t.properties() {
+                tag.property_map.entry(key).or_insert(values);
+            }
+
+            tag.title = tag.title.or_else(|| t.title().clone());
+            tag.artist = tag.artist.or_else(|| t.artist().clone());
+            tag.album = tag.album.or_else(|| t.album().clone());
+            tag.comment = tag.comment.or_else(|| t.comment().clone());
+            tag.genre = tag.genre.or_else(|| t.genre().clone());
+            tag.year = tag.year.or(*t.year());
+            tag.track = tag.track.or(*t.track());
+        }
+        tag.saved_properties = tag.property_map.clone();
+
+        tag
+    }
+}
+
+impl MpegTag {
+    // Refreshes the values returned by the Tag accessors from the properties.
+    fn update_fields(&mut self) {
+        let text = |key: &str| {
+            self.property_map
+                .get(key)
+                .and_then(|values| values.first())
+                .filter(|value| !value.is_empty())
+                .cloned()
+        };
+        let leading_number = |text: String| {
+            let end = text
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(text.len());
+            text[..end].parse::<u32>().ok().filter(|&n| n > 0)
+        };
+
+        self.title = text("TITLE");
+        self.artist = text("ARTIST");
+        self.album = text("ALBUM");
+        self.comment = text("COMMENT");
+        self.genre = text("GENRE");
+        self.year = text("DATE").and_then(leading_number);
+        self.track = text("TRACKNUMBER").and_then(leading_number);
+    }
+
+    // Keeps the properties in step with the Tag setters, so that the values
+    // set either way are saved.
+    fn set_property(&mut self, key: &str, value: Option<String>) {
+        match value.filter(|value| !value.is_empty()) {
+            Some(value) => self.property_map.insert(String::from(key), vec![value]),
+            None => self.property_map.remove(key),
+        };
+    }
+
+    // returns the properties which were set or removed since the tag was last
+    // read or written, with None for removed ones
+    pub(crate) fn property_changes(&self) -> Vec<(String, Option<Vec<String>>)> {
+        let removed = self
+            .saved_properties
+            .keys()
+            .filter(|key| !self.property_map.contains_key(*key))
+            .map(|key| (key.clone(), None));
+        let set = self
+            .property_map
+            .iter()
+            .filter(|(key, values)| self.saved_properties.get(*key) != Some(values))
+            .map(|(key, values)| (key.clone(), Some(values.clone())));
+
+        removed.chain(set).collect()
+    }
+
+    // Writes the values of this tag to `tag`, leaving unchanged values alone so
+    // that their original representation is kept.  Of the properties, only
+    // `changes` are written, so that the other items of `tag` are kept.
+    pub(crate) fn apply_to(&self, tag: &mut dyn Tag, changes: &[(String, Option<Vec<String>>)]) {
+        let current = tag.properties();
+        let mut properties = current.clone();
+        for (key, values) in changes {
+            match values {
+                Some(values) => properties.insert(key.clone(), values.clone()),
+                None => properties.remove(key),
+            };
+        }
+        if properties != current {
+            tag.set_properties(properties);
+        }
+
+        if tag.title() != self.title() {
+            tag.set_title(self.title.clone());
+        }
+        if tag.artist() != self.artist() {
+            tag.set_artist(self.artist.clone());
+        }
+        if tag.album() != self.album() {
+            tag.set_album(self.album.clone());
+        }
+        if tag.comment() != self.comment() {
+            tag.set_comment(self.comment.clone());
+        }
+        if tag.genre() != self.genre() {
+            tag.set_genre(self.genre.clone());
+        }
+        if tag.year() != self.year() {
+            tag.set_year(self.year);
+        }
+        if tag.track() != self.track() {
+            tag.set_track(self.track);
+        }
+    }
 }
 
 impl Tag for MpegTag {
-    fn properties(&self) -> &PropertyMap {
-        &self.property_map
+    fn properties(&self) -> PropertyMap {
+        self.property_map.clone()
     }
 
     fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
         for s in properties {
             self.property_map.remove(&s);
         }
+        self.update_fields();
     }
 
-    fn set_properties(&mut self, properties: PropertyMap) {
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
         self.property_map = properties;
+        self.update_fields();
+
+        PropertyMap::new()
     }
 
     fn title(&self) -> &Option<String> {
@@ -61,6 +726,7 @@ impl Tag for MpegTag {
     }
 
     fn set_title(&mut self, title: Option<String>) {
+        self.set_property("TITLE", title.clone());
         self.title = title;
     }
 
@@ -69,6 +735,7 @@ impl Tag for MpegTag {
     }
 
     fn set_artist(&mut self, artist: Option<String>) {
+        self.set_property("ARTIST", artist.clone());
         self.artist = artist;
     }
 
@@ -77,6 +744,7 @@ impl Tag for MpegTag {
     }
 
     fn set_album(&mut self, album: Option<String>) {
+        self.set_property("ALBUM", album.clone());
         self.album = album;
     }
 
@@ -85,6 +753,7 @@ impl Tag for MpegTag {
     }
 
     fn set_comment(&mut self, comment: Option<String>) {
+        self.set_property("COMMENT", comment.clone());
         self.comment = comment;
     }
 
@@ -93,6 +762,7 @@ impl Tag for MpegTag {
     }
 
     fn set_genre(&mut self, genre: Option<String>) {
+        self.set_property("GENRE", genre.clone());
         self.genre = genre;
     }
 
@@ -101,6 +771,7 @@ impl Tag for MpegTag {
     }
 
     fn set_year(&mut self, year: Option<u32>) {
+        self.set_property("DATE", year.map(|year| year.to_string()));
         self.year = year
     }
 
@@ -108,18 +779,31 @@ impl Tag for MpegTag {
         &self.track
     }
 
+    // keeps the total number of tracks
     fn set_track(&mut self, track: Option<u32>) {
+        let total = self
+            .property_map
+            .get("TRACKNUMBER")
+            .and_then(|values| values.first())
+            .and_then(|value| value.split_once('/'))
+            .map(|(_, total)| String::from(total));
+        let value = track.map(|track| match total {
+            Some(total) => format!("{}/{}", track, total),
+            None => track.to_string(),
+        });
+
+        self.set_property("TRACKNUMBER", value);
         self.track = track;
     }
 
     fn is_empty(&self) -> bool {
-        return self.title.is_none()
+        self.title.is_none()
             && self.artist.is_none()
             && self.album.is_none()
             && self.comment.is_none()
             && self.genre.is_none()
             && self.year.is_none()
-            && self.track.is_none();
+            && self.track.is_none()
     }
 }
 
@@ -130,7 +814,7 @@ pub struct MpegProperties {
 
 #[derive(Clone)]
 pub(crate) struct MpegPropertiesPrivate {
-    xing_header: XingHeader,
+    xing_header: Option<XingHeader>,
     length: u32,
     bitrate: u32,
     sample_rate: u32,
@@ -141,12 +825,11 @@ pub(crate) struct MpegPropertiesPrivate {
     protection_enabled: bool,
     is_copyrighted: bool,
     is_original: bool,
-    read_style: ReadStyle,
 }
 
 impl AudioProperties for MpegProperties {
     fn length(&self) -> u32 {
-        self.d.length
+        self.length_in_seconds()
     }
 
     fn length_in_seconds(&self) -> u32 {
@@ -170,15 +853,112 @@ impl AudioProperties for MpegProperties {
     }
 }
 
-/* impl MpegProperties {
-    pub(crate) fn new(file: File, style: ReadStyle) -> Result<Self> {
+impl MpegProperties {
+    // `stream_end` is the offset right after the last audio frame, i.e. the
+    // beginning of any trailing APE or ID3v1 tag.
+    pub(crate) fn new<R: Read + Seek>(
+        file: &mut R,
+        first_frame_offset: u64,
+        stream_end: u64,
+        _style: ReadStyle,
+    ) -> Result<Self> {
         // Only the first valid frame is required if we have a VBR header.
+        let first_header = MpegHeader::new(file, first_frame_offset, false)?;
+
+        // Check for a VBR header that will help us in gathering information about a
+        // VBR stream.
+        file.seek(SeekFrom::Start(first_frame_offset))?;
+        let data = read_block(file, first_header.frame_length() as usize)?;
+        let xing_header = XingHeader::new(data)
+            .ok()
+            .filter(|xing_header| xing_header.is_valid());
+
+        let mut length = 0;
+        let mut bitrate = 0;
+
+        match &xing_header {
+            Some(xing_header)
+                if first_header.samples_per_frame() > 0 && first_header.sample_rate() > 0 =>
+            {
+                // Read the length and the bitrate from the VBR header.
+                let time_per_frame = first_header.samples_per_frame() as f64 * 1000.0
+                    / first_header.sample_rate() as f64;
+                let length_ms = time_per_frame * xing_header.total_frames() as f64;
+
+                length = (length_ms + 0.5) as u32;
+                bitrate = (xing_header.total_size() as f64 * 8.0 / length_ms + 0.5) as u32;
+            }
+            _ if first_header.bitrate() > 0 => {
+                // Since there was no valid VBR header found, we hope that we're in a
+                // constant bitrate file.
+                bitrate = first_header.bitrate();
+
+                let stream_length = stream_end.saturating_sub(first_frame_offset);
+                length = (stream_length as f64 * 8.0 / bitrate as f64 + 0.5) as u32;
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            d: MpegPropertiesPrivate {
+                xing_header,
+                length,
+                bitrate,
+                sample_rate: first_header.sample_rate(),
+                channels: match first_header.channel_mode() {
+                    ChannelMode::SingleChannel => 1,
+                    _ => 2,
+                },
+                layer: first_header.layer(),
+                version: first_header.version(),
+                channel_mode: first_header.channel_mode(),
+                protection_enabled: first_header.protection_enabled(),
+                is_copyrighted: first_header.is_copyrighted(),
+                is_original: first_header.is_original(),
+            },
+        })
+    }
+
+    // returns the MPEG version of the file
+    pub fn version(&self) -> Version {
+        self.d.version
+    }
+
+    // returns the layer version, one of 1, 2 or 3
+    pub fn layer(&self) -> u32 {
+        self.d.layer
+    }
+
+    // returns true if the MPEG protection bit is enabled
+    pub fn protection_enabled(&self) -> bool {
+        self.d.protection_enabled
     }
-} */
 
-#[derive(Clone, Copy, TryFromPrimitive, IntoPrimitive)]
+    // returns the channel mode of the file
+    pub fn channel_mode(&self) -> ChannelMode {
+        self.d.channel_mode
+    }
+
+    // returns true if the copyrighted bit is set
+    pub fn is_copyrighted(&self) -> bool {
+        self.d.is_copyrighted
+    }
+
+    // returns true if the "original" bit is set
+    pub fn is_original(&self) -> bool {
+        self.d.is_original
+    }
+
+    // returns the Xing/VBRI header if one was found in the first frame
+    pub fn xing_header(&self) -> Option<&XingHeader> {
+        self.d.xing_header.as_ref()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
-pub(crate) enum Version {
+#[allow(clippy::enum_variant_names)]
+pub enum Version {
     // MPEG Version 1
     Version1 = 0,
     // MPEG Version 2
@@ -187,9 +967,9 @@ pub(crate) enum Version {
     Version2_5 = 2,
 }
 
-#[derive(Clone, Copy, TryFromPrimitive, IntoPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
-pub(crate) enum ChannelMode {
+pub enum ChannelMode {
     // Stereo
     Stereo = 0,
     // Stereo
@@ -238,21 +1018,19 @@ pub struct MpegHeader {
 }
 
 impl MpegHeader {
-    pub(crate) fn new(mut file: File, offset: u64, check_length: bool) -> Result<Self> {
+    pub fn new<R: Read + Seek>(file: &mut R, offset: u64, check_length: bool) -> Result<Self> {
         let mut header = MpegHeader {
             d: MpegHeaderPrivate::default(),
         };
 
         file.seek(SeekFrom::Start(offset))?;
-        let mut data = [0 as u8; 4];
-        file.read_exact(&mut data)?;
+        let data: [u8; 4] = read_block(file, 4)?
+            .try_into()
+            .map_err(|_| Error::TruncatedHeader)?;
 
         // Check for the MPEG synch bytes.
-        if !is_frame_sync(data.to_vec(), 0) {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "MPEG::Header::parse() -- MPEG header did not match MPEG synch.",
-            ));
+        if !is_frame_sync(&data, 0) {
+            return Err(Error::InvalidFrameSync);
         }
 
         // set the MPEG version
@@ -386,8 +1164,10 @@ impl MpegHeader {
 
             file.seek(SeekFrom::Start(offset + header.d.frame_length as u64))?;
 
-            let mut next_data = [0u8; 4];
-            file.read_exact(&mut next_data)?;
+            // A frame without a following frame cannot be verified.
+            let Ok(next_data) = <[u8; 4]>::try_from(read_block(file, 4)?) else {
+                return Ok(header);
+            };
 
             let header_mask: u32 = 0xfffe0c00;
             let header_n: u32 = u32::from_be_bytes(data) & header_mask;
@@ -404,75 +1184,75 @@ impl MpegHeader {
         Ok(header)
     }
 
-    pub(crate) fn is_valid(&self) -> bool {
+    pub fn is_valid(&self) -> bool {
         self.d.is_valid
     }
 
-    pub(crate) fn version(&self) -> Version {
+    pub fn version(&self) -> Version {
         self.d.version
     }
 
-    pub(crate) fn layer(&self) -> u32 {
+    pub fn layer(&self) -> u32 {
         self.d.layer
     }
 
-    pub(crate) fn protection_enabled(&self) -> bool {
+    pub fn protection_enabled(&self) -> bool {
         self.d.protection_enabled
     }
 
-    pub(crate) fn bitrate(&self) -> u32 {
+    pub fn bitrate(&self) -> u32 {
         self.d.bitrate
     }
 
-    pub(crate) fn sample_rate(&self) -> u32 {
+    pub fn sample_rate(&self) -> u32 {
         self.d.sample_rate
     }
 
-    pub(crate) fn is_padded(&self) -> bool {
+    pub fn is_padded(&self) -> bool {
         self.d.is_padded
     }
 
-    pub(crate) fn channel_mode(&self) -> ChannelMode {
+    pub fn channel_mode(&self) -> ChannelMode {
         self.d.channel_mode
     }
 
-    pub(crate) fn is_copyrighted(&self) -> bool {
+    pub fn is_copyrighted(&self) -> bool {
         self.d.is_copyrighted
     }
 
-    pub(crate) fn is_original(&self) -> bool {
+    pub fn is_original(&self) -> bool {
         self.d.is_original
     }
 
-    pub(crate) fn frame_length(&self) -> u32 {
+    pub fn frame_length(&self) -> u32 {
         self.d.frame_length
     }
 
-    pub(crate) fn samples_per_frame(&self) -> u32 {
+    pub fn samples_per_frame(&self) -> u32 {
         self.d.samples_per_frame
     }
 }
 
-fn is_frame_sync(bytes: Vec<u8>, offset: usize) -> bool {
-    let b1 = bytes[offset + 0];
+fn is_frame_sync(bytes: &[u8], offset: usize) -> bool {
+    let b1 = bytes[offset];
     let b2 = bytes[offset + 1];
 
     b1 == 0xff && b2 != 0xff && (b2 & 0xe0) == 0xe0
 }
 
-#[derive(Clone, Copy, TryFromPrimitive, IntoPrimitive, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, TryFromPrimitive, IntoPrimitive, PartialEq, Eq)]
 #[repr(u8)]
-pub(crate) enum XingHeaderType {
+pub enum XingHeaderType {
     // Invalid header or no VBR header found.
     Invalid = 0,
     // Xing header.
     Xing = 1,
     // VBRI header.
-    VBRI = 2,
+    Vbri = 2,
 }
 
 #[derive(Clone)]
-pub(crate) struct XingHeader {
+pub struct XingHeader {
     d: XingHeaderPrivate,
 }
 
@@ -494,32 +1274,26 @@ impl Default for XingHeaderPrivate {
 }
 
 impl XingHeader {
-    pub(crate) fn new(data: Vec<u8>) -> Result<Self> {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
         // look for a Xing header
         let mut xing_header = Self {
             d: XingHeaderPrivate::default(),
         };
 
-        let mut offset = byte_vec_find(&data, &vec![b'X', b'i', b'n', b'g'], 0, 1);
+        let mut offset = byte_vec_find(&data, b"Xing", 0, 1);
 
         if offset.is_none() {
-            offset = byte_vec_find(&data, &vec![b'I', b'n', b'f', b'o'], 0, 1);
+            offset = byte_vec_find(&data, b"Info", 0, 1);
         }
 
         match offset {
             Some(offset) => {
                 if data.len() < offset + 16 {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "MPEG::XingHeader::parse() -- Xing header found but too short.",
-                    ));
+                    return Err(Error::TruncatedHeader);
                 }
 
                 if (data[offset + 7] & 0x03) != 0x03 {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "MPEG::XingHeader::parse() -- Xing header doesn't contain the required information.",
-                    ));
+                    return Err(Error::InvalidHeader);
                 }
 
                 xing_header.d.frames =
@@ -531,25 +1305,20 @@ impl XingHeader {
                 xing_header.d.header_type = XingHeaderType::Xing;
             }
             None => {
-                let offset = byte_vec_find(&data, &vec![b'V', b'B', b'R', b'I'], 0, 1);
-
-                if offset.is_some() {
-                    let offset = offset.unwrap();
+                let offset = byte_vec_find(&data, b"VBRI", 0, 1);
 
+                if let Some(offset) = offset {
                     // VBRI header found
 
                     if data.len() < offset + 32 {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            "MPEG::XingHeader::parse() -- VBRI header found but too short.",
-                        ));
+                        return Err(Error::TruncatedHeader);
                     }
 
                     xing_header.d.frames =
                         u32::from_be_bytes(data[offset + 14..offset + 18].try_into().unwrap());
                     xing_header.d.size =
                         u32::from_be_bytes(data[offset + 10..offset + 14].try_into().unwrap());
-                    xing_header.d.header_type = XingHeaderType::VBRI;
+                    xing_header.d.header_type = XingHeaderType::Vbri;
                 }
             }
         }
@@ -557,19 +1326,561 @@ impl XingHeader {
         Ok(xing_header)
     }
 
-    pub(crate) fn is_valid(&self) -> bool {
+    pub fn is_valid(&self) -> bool {
         self.d.header_type != XingHeaderType::Invalid && self.d.frames > 0 && self.d.size > 0
     }
 
-    pub(crate) fn total_frames(&self) -> u32 {
+    pub fn total_frames(&self) -> u32 {
         self.d.frames
     }
 
-    pub(crate) fn total_size(&self) -> u32 {
+    pub fn total_size(&self) -> u32 {
         self.d.size
     }
 
-    pub(crate) fn header_type(&self) -> XingHeaderType {
+    pub fn header_type(&self) -> XingHeaderType {
         self.d.header_type
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ape::ApeItem;
+    use std::{
+        io::{Cursor, Write},
+        path::PathBuf,
+    };
+
+    // MPEG-1 Layer III, 128 kb/s, 44100 Hz, joint stereo, original, 417 bytes per frame
+    fn mpeg_frames(count: usize) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x44];
+        frame.resize(417, 0);
+        frame.repeat(count)
+    }
+
+    fn id3v1_block() -> Vec<u8> {
+        let mut data = b"TAG".to_vec();
+        for field in [&b"Title"[..], b"Artist", b"Album"] {
+            let mut f = field.to_vec();
+            f.resize(30, b' ');
+            data.extend(f);
+        }
+        data.extend(b"2001");
+        data.extend([0u8; 28]);
+        data.extend([0, 7, 17]);
+        data
+    }
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustaglib-{}-{}", std::process::id(), name));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_open_with_tags() {
+        let mut data = vec![b'I', b'D', b'3', 4, 0, 0, 0, 0, 0, 20];
+        data.extend([0u8; 20]);
+        data.extend(mpeg_frames(10));
+        data.extend(id3v1_block());
+        let path = write_temp("open-with-tags.mp3", &data);
+
+        let file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(file.has_id3v2_tag());
+        assert_eq!(file.id3v2_size(), 30);
+        assert!(file.has_id3v1_tag());
+        assert!(!file.has_ape_tag());
+
+        let tag = file.tag();
+        assert_eq!(tag.title().as_deref(), Some("Title"));
+        assert_eq!(tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(*tag.year(), Some(2001));
+        assert_eq!(*tag.track(), Some(7));
+        assert_eq!(tag.genre().as_deref(), Some("Rock"));
+
+        let properties = file.audio_properties;
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.layer(), 3);
+        assert_eq!(properties.version(), Version::Version1);
+        assert_eq!(properties.channel_mode(), ChannelMode::JointStereo);
+    }
+
+    #[test]
+    fn test_cbr_properties() {
+        let mut data = mpeg_frames(100);
+        data.extend(id3v1_block());
+        let path = write_temp("cbr-properties.mp3", &data);
+
+        let file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let properties = file.audio_properties;
+        assert!(properties.xing_header().is_none());
+        assert_eq!(properties.bitrate(), 128);
+        assert_eq!(properties.length_in_milliseconds(), 2606);
+        assert_eq!(properties.length_in_seconds(), 2);
+        assert!(properties.is_original());
+        assert!(!properties.is_copyrighted());
+    }
+
+    #[test]
+    fn test_xing_properties() {
+        let mut data = mpeg_frames(10);
+        // Xing header with the frames and bytes fields: 1000 frames, 200000 bytes
+        data[36..40].copy_from_slice(b"Xing");
+        data[40..44].copy_from_slice(&3u32.to_be_bytes());
+        data[44..48].copy_from_slice(&1000u32.to_be_bytes());
+        data[48..52].copy_from_slice(&200000u32.to_be_bytes());
+        let path = write_temp("xing-properties.mp3", &data);
+
+        let file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let properties = file.audio_properties;
+        let xing_header = properties.xing_header().unwrap();
+        assert_eq!(xing_header.header_type(), XingHeaderType::Xing);
+        assert_eq!(xing_header.total_frames(), 1000);
+        assert_eq!(xing_header.total_size(), 200000);
+        assert_eq!(properties.length_in_milliseconds(), 26122);
+        assert_eq!(properties.bitrate(), 61);
+    }
+
+    #[test]
+    fn test_save_reuses_padding() {
+        let mut data = vec![b'I', b'D', b'3', 3, 0, 0, 0, 0, 0x08, 0];
+        data.extend(b"TIT2\x00\x00\x00\x06\x00\x00\x00Title");
+        data.resize(10 + 1024, 0);
+        let audio = mpeg_frames(10);
+        data.extend(&audio);
+        data.extend(id3v1_block());
+        let path = write_temp("save-reuses-padding.mp3", &data);
+
+        let mut file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        file.tag.set_title(Some(String::from("A longer title")));
+        file.save().unwrap();
+
+        let saved = std::fs::read(&path).unwrap();
+        let file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.len(), data.len());
+        assert_eq!(saved[1034..1034 + audio.len()], audio[..]);
+        assert_eq!(file.id3v2_tag().unwrap().header().major_version(), 4);
+        assert_eq!(file.tag.title().as_deref(), Some("A longer title"));
+        assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+    }
+
+    #[test]
+    fn test_save_grows_tag() {
+        let mut data = mpeg_frames(10);
+        data.extend(id3v1_block());
+        let path = write_temp("save-grows-tag.mp3", &data);
+
+        let mut file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        assert!(!file.has_id3v2_tag());
+        file.set_id3v2_padding_size(100);
+        file.tag.set_album(Some(String::from("New album")));
+        file.save().unwrap();
+
+        let size = file.id3v2_size() as usize;
+        let saved = std::fs::read(&path).unwrap();
+        let file = MpegFile::open(&path, ReadStyle::Average).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let audio_size = data.len() - 128;
+        assert_eq!(saved.len(), data.len() + size);
+        assert_eq!(saved[size..size + audio_size], data[..audio_size]);
+        assert_eq!(
+            file.id3v2_tag().unwrap().album().as_deref(),
+            Some("New album")
+        );
+        assert_eq!(
+            file.id3v1_tag().unwrap().album().as_deref(),
+            Some("New album")
+        );
+        assert_eq!(file.id3v2_tag().unwrap().title().as_deref(), Some("Title"));
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+    }
+
+    fn ape_block() -> Vec<u8> {
+        let mut data = b"APETAGEX".to_vec();
+        for value in [2000u32, 32, 0, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([0u8; 8]);
+        data
+    }
+
+    #[test]
+    fn test_save_id3v1() {
+        let audio = mpeg_frames(10);
+        let mut cursor = Cursor::new(audio.clone());
+
+        // a file without tags gets an ID3v1 tag appended
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        file.tag.set_title(Some(String::from("Title")));
+        file.tag.set_track(Some(3));
+        file.save_tags_to(&mut cursor, TagTypes::ID3V1).unwrap();
+
+        assert!(!file.has_id3v2_tag());
+        assert_eq!(cursor.get_ref().len(), audio.len() + 128);
+        assert_eq!(cursor.get_ref()[..audio.len()], audio[..]);
+
+        // saving again overwrites it in place
+        file.tag.set_title(Some(String::from("Other title")));
+        file.save_tags_to(&mut cursor, TagTypes::ID3V1).unwrap();
+        assert_eq!(cursor.get_ref().len(), audio.len() + 128);
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let id3v1_tag = file.id3v1_tag().unwrap();
+        assert_eq!(id3v1_tag.title().as_deref(), Some("Other title"));
+        assert_eq!(*id3v1_tag.track(), Some(3));
+    }
+
+    #[test]
+    fn test_save_extended_id3v1() {
+        let audio = mpeg_frames(10);
+        let mut data = audio.clone();
+        data.extend(b"TAG+");
+        data.resize(audio.len() + 227, 0);
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.id3v1_tag().unwrap().is_extended());
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+
+        // the extended block is written while a value needs it
+        let title = "A title which is longer than thirty characters";
+        file.tag.set_title(Some(String::from(title)));
+        file.save_tags_to(&mut cursor, TagTypes::ID3V1).unwrap();
+        assert_eq!(cursor.get_ref().len(), audio.len() + 227 + 128);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some(title));
+
+        // and dropped afterwards
+        file.tag.set_title(Some(String::from("Title")));
+        file.save_tags_to(&mut cursor, TagTypes::ID3V1).unwrap();
+        assert_eq!(cursor.get_ref().len(), audio.len() + 128);
+        assert_eq!(cursor.get_ref()[..audio.len()], audio[..]);
+    }
+
+    #[test]
+    fn test_lyrics3() {
+        let audio = mpeg_frames(10);
+        let title = "A title which is longer than thirty characters";
+        let mut lyrics3 = Lyrics3Tag::empty();
+        lyrics3.set_lyrics(Some(String::from("Lyrics")));
+        lyrics3.set_title(Some(String::from(title)));
+        let block = lyrics3.render();
+
+        let mut data = audio.clone();
+        data.extend(&block);
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.has_lyrics3_tag());
+        assert_eq!(
+            file.lyrics3_tag().unwrap().lyrics().as_deref(),
+            Some("Lyrics")
+        );
+        assert_eq!(file.tag.title().as_deref(), Some(title));
+        assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+
+        // saving the other tags keeps the block
+        file.tag.set_artist(Some(String::from("New artist")));
+        file.save_to(&mut cursor).unwrap();
+        let start = file.id3v2_size() as usize + audio.len();
+        assert_eq!(cursor.get_ref()[start..start + block.len()], block[..]);
+
+        file.strip_from(&mut cursor, TagTypes::LYRICS3).unwrap();
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(!file.has_lyrics3_tag());
+        assert_eq!(
+            file.id3v1_tag().unwrap().artist().as_deref(),
+            Some("New artist")
+        );
+        assert_eq!(cursor.get_ref().len(), start + 128);
+    }
+
+    #[test]
+    fn test_damaged_lyrics3() {
+        let audio = mpeg_frames(10);
+        let mut block = b"LYRICSBEGININD00002".to_vec();
+        block.extend(b"LYR99999Lyrics");
+        block.extend(format!("{:06}LYRICS200", block.len()).as_bytes());
+
+        let mut data = audio.clone();
+        data.extend(&block);
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.has_lyrics3_tag());
+        assert!(file.lyrics3_tag().is_none());
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+
+        file.tag.set_artist(Some(String::from("New artist")));
+        file.save_to(&mut cursor).unwrap();
+        let start = file.id3v2_size() as usize + audio.len();
+        assert_eq!(cursor.get_ref()[start..start + block.len()], block[..]);
+    }
+
+    #[test]
+    fn test_strip() {
+        let audio = mpeg_frames(10);
+        let mut data = audio.clone();
+        data.extend(ape_block());
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.has_ape_tag());
+        assert!(file.has_id3v1_tag());
+
+        file.save_tags_to(&mut cursor, TagTypes::ID3V2).unwrap();
+        assert!(file.has_id3v2_tag());
+
+        file.strip_from(&mut cursor, TagTypes::ID3V1 | TagTypes::ID3V2)
+            .unwrap();
+        assert!(!file.has_id3v1_tag());
+        assert!(!file.has_id3v2_tag());
+
+        // the APE tag before the ID3v1 tag is kept
+        let mut expected = audio;
+        expected.extend(ape_block());
+        assert_eq!(cursor.get_ref(), &expected);
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.has_ape_tag());
+        assert!(file.tag.title().is_none());
+    }
+
+    #[test]
+    fn test_ape() {
+        let audio = mpeg_frames(10);
+        let mut ape_tag = ApeTag::empty();
+        ape_tag.set_title(Some(String::from("APE title")));
+        ape_tag.set_album(Some(String::from("APE album")));
+
+        let mut data = audio.clone();
+        data.extend(ape_tag.render());
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(file.has_ape_tag());
+        assert_eq!(file.tag.title().as_deref(), Some("APE title"));
+        assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+
+        // saving updates the existing APE tag along with the ID3v1 tag
+        file.tag.set_artist(Some(String::from("New artist")));
+        file.save_to(&mut cursor).unwrap();
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let ape_tag = file.ape_tag().unwrap();
+        assert_eq!(ape_tag.artist().as_deref(), Some("New artist"));
+        assert_eq!(ape_tag.album().as_deref(), Some("APE album"));
+        assert_eq!(
+            file.id3v1_tag().unwrap().artist().as_deref(),
+            Some("New artist")
+        );
+
+        file.strip_from(&mut cursor, TagTypes::APE).unwrap();
+        assert!(!file.has_ape_tag());
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert!(!file.has_ape_tag());
+        assert!(file.has_id3v1_tag());
+        let start = file.id3v2_size() as usize;
+        assert_eq!(cursor.get_ref()[start..start + audio.len()], audio[..]);
+        assert_eq!(cursor.get_ref().len(), start + audio.len() + 128);
+    }
+
+    #[test]
+    fn test_save_keeps_ape_items() {
+        let mut ape_tag = ApeTag::empty();
+        ape_tag.set_title(Some(String::from("Title")));
+        for (key, value) in [
+            ("MP3GAIN_MINMAX", "102,183"),
+            ("REPLAYGAIN_TRACK_GAIN", "-6.5 dB"),
+        ] {
+            ape_tag.set_item(ApeItem::text(key, vec![String::from(value)]));
+        }
+
+        let mut data = mpeg_frames(10);
+        data.extend(ape_tag.render());
+        let mut cursor = Cursor::new(data);
+
+        // The ID3v2 tag written first cannot hold the APE-only items.
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.properties()["MP3GAIN_MINMAX"], ["102,183"]);
+        file.tag.set_artist(Some(String::from("Artist")));
+        file.save_to(&mut cursor).unwrap();
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let mut properties = file.tag.properties();
+        properties.insert(String::from("MOOD"), vec![String::from("Calm")]);
+        file.tag.set_properties(properties);
+        file.save_to(&mut cursor).unwrap();
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let ape_tag = file.ape_tag().unwrap();
+        assert_eq!(ape_tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(
+            ape_tag.item("MP3GAIN_MINMAX").unwrap().values(),
+            ["102,183"]
+        );
+        assert_eq!(
+            ape_tag.item("REPLAYGAIN_TRACK_GAIN").unwrap().values(),
+            ["-6.5 dB"]
+        );
+        assert_eq!(ape_tag.item("MOOD").unwrap().values(), ["Calm"]);
+        assert_eq!(ape_tag.items().len(), 5);
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let mut data = vec![b'I', b'D', b'3', 4, 0, 0, 0, 0, 0, 0x1d];
+        data.extend(b"TIT2\x00\x00\x00\x04\x00\x00\x00Old");
+        data.extend(b"TRCK\x00\x00\x00\x05\x00\x00\x003/12");
+        data.extend(mpeg_frames(10));
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let mut properties = file.tag.properties();
+        properties.insert(String::from("TITLE"), vec![String::from("New")]);
+        file.tag.set_properties(properties);
+        assert_eq!(file.tag.title().as_deref(), Some("New"));
+        file.save_to(&mut cursor).unwrap();
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("New"));
+        assert_eq!(*file.tag.track(), Some(3));
+
+        // values set through the accessors show up in the properties
+        file.tag.set_track(Some(4));
+        let properties = file.tag.properties();
+        assert_eq!(properties["TRACKNUMBER"], ["4/12"]);
+        file.tag.set_properties(properties);
+        assert_eq!(*file.tag.track(), Some(4));
+        file.save_to(&mut cursor).unwrap();
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.properties()["TRACKNUMBER"], ["4/12"]);
+    }
+
+    #[test]
+    fn test_save_tags_keeps_pending_changes() {
+        let mut data = mpeg_frames(10);
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let mut properties = file.tag.properties();
+        properties.insert(String::from("TITLE"), vec![String::from("New")]);
+        properties.insert(String::from("MOOD"), vec![String::from("Calm")]);
+        file.tag.set_properties(properties);
+
+        file.save_tags_to(&mut cursor, TagTypes::ID3V1).unwrap();
+        assert!(!file.has_id3v2_tag());
+        file.save_tags_to(&mut cursor, TagTypes::ID3V2).unwrap();
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.id3v1_tag().unwrap().title().as_deref(), Some("New"));
+        assert_eq!(file.id3v2_tag().unwrap().title().as_deref(), Some("New"));
+        assert_eq!(file.id3v2_tag().unwrap().properties()["MOOD"], ["Calm"]);
+    }
+
+    #[test]
+    fn test_damaged_ape_tag() {
+        // an APEv1 tag announcing two items, the second of which is damaged
+        let mut items = 5u32.to_le_bytes().to_vec();
+        items.extend([0; 4]);
+        items.extend(b"Title\0Title");
+        items.extend(u32::MAX.to_le_bytes());
+        items.extend([0; 4]);
+        items.extend(b"Artist\0Artist");
+
+        let mut data = mpeg_frames(10);
+        data.extend(&items);
+        data.extend(b"APETAGEX");
+        for value in [1000u32, items.len() as u32 + 32, 2, 0] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([0u8; 8]);
+        let mut cursor = Cursor::new(data);
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        assert_eq!(file.ape_tag().unwrap().items().len(), 1);
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+
+        file.tag.set_artist(Some(String::from("New artist")));
+        file.save_to(&mut cursor).unwrap();
+        assert!(!cursor.get_ref().windows(7).any(|w| w == b"Artist\0"));
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let ape_tag = file.ape_tag().unwrap();
+        assert_eq!(ape_tag.artist().as_deref(), Some("New artist"));
+        assert_eq!(ape_tag.items().len(), 2);
+    }
+
+    #[test]
+    fn test_read_and_save_cursor() {
+        let mut data = mpeg_frames(10);
+        data.extend(id3v1_block());
+        let mut cursor = Cursor::new(data.clone());
+
+        let mut file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+        assert_eq!(file.tag.properties()["TITLE"], ["Title"]);
+        assert!(matches!(file.save(), Err(Error::NoPath)));
+
+        file.tag.set_artist(Some(String::from("New artist")));
+        file.save_to(&mut cursor).unwrap();
+        let size = file.id3v2_size() as usize;
+        let audio_size = data.len() - 128;
+        assert_eq!(cursor.get_ref().len(), size + data.len());
+        assert_eq!(
+            cursor.get_ref()[size..size + audio_size],
+            data[..audio_size]
+        );
+
+        let file = MpegFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.artist().as_deref(), Some("New artist"));
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 261);
+    }
+
+    #[test]
+    fn test_open_without_frames() {
+        let path = write_temp("open-without-frames.mp3", &[0u8; 4096]);
+        let result = MpegFile::open(&path, ReadStyle::Average);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(Error::NoFrameFound)));
+    }
+
+    #[test]
+    fn test_unreadable_id3v2_tag() {
+        let mut data = b"ID3\x05\x00\x00\x00\x00\x00\x10".to_vec();
+        data.extend([0u8; 16]);
+        data.extend(mpeg_frames(10));
+        let result = MpegFile::read_from(&mut Cursor::new(data.clone()), ReadStyle::Average);
+        assert!(matches!(result, Err(Error::UnsupportedVersion)));
+
+        data[3] = 4;
+        data.truncate(20);
+        let result = MpegFile::read_from(&mut Cursor::new(data), ReadStyle::Average);
+        assert!(matches!(result, Err(Error::TruncatedTag)));
+    }
+}
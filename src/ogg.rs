@@ -0,0 +1,690 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{
+    error::{Error, Result},
+    utils::{insert_block, read_block, Truncate},
+};
+
+// the most lacing values a page can have
+const MAX_SEGMENTS: usize = 255;
+
+// the size of a page header without the segment table
+const HEADER_SIZE: usize = 27;
+
+// the CRC-32 table for the polynomial 0x04c11db7, without reflection
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// returns the checksum of a page, whose checksum field must be zeroed
+pub fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[(((crc >> 24) as u8) ^ b) as usize]
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OggPageHeader {
+    first_packet_continued: bool,
+    first_page_of_stream: bool,
+    last_page_of_stream: bool,
+    granule_position: i64,
+    stream_serial_number: u32,
+    page_sequence_number: u32,
+    checksum: u32,
+    // the sizes of the packet fragments on the page
+    packet_sizes: Vec<usize>,
+    last_packet_completed: bool,
+}
+
+impl OggPageHeader {
+    // Parses a page header, including its segment table.  `data` may be
+    // followed by the page data.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::TruncatedHeader);
+        }
+        if &data[0..4] != b"OggS" {
+            return Err(Error::InvalidHeader);
+        }
+        if data[4] != 0 {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        let flags = data[5];
+        let page_segments = data[26] as usize;
+        let lacing_values = data
+            .get(HEADER_SIZE..HEADER_SIZE + page_segments)
+            .ok_or(Error::TruncatedHeader)?;
+
+        // A packet fragment ends with the first lacing value below 255.
+        let mut packet_sizes = vec![];
+        let mut size = 0;
+        for &value in lacing_values {
+            size += value as usize;
+            if value < 255 {
+                packet_sizes.push(size);
+                size = 0;
+            }
+        }
+
+        let last_packet_completed = size == 0 && lacing_values.last() != Some(&255);
+        if !last_packet_completed {
+            packet_sizes.push(size);
+        }
+
+        Ok(Self {
+            first_packet_continued: flags & 0x01 != 0,
+            first_page_of_stream: flags & 0x02 != 0,
+            last_page_of_stream: flags & 0x04 != 0,
+            granule_position: i64::from_le_bytes(data[6..14].try_into().unwrap()),
+            stream_serial_number: u32::from_le_bytes(data[14..18].try_into().unwrap()),
+            page_sequence_number: u32::from_le_bytes(data[18..22].try_into().unwrap()),
+            checksum: u32::from_le_bytes(data[22..26].try_into().unwrap()),
+            packet_sizes,
+            last_packet_completed,
+        })
+    }
+
+    // Renders the header with a zero checksum, which `OggPage::render` fills
+    // in.
+    pub fn render(&self) -> Vec<u8> {
+        let lacing_values = self.lacing_values();
+
+        let mut flags = 0;
+        if self.first_packet_continued {
+            flags |= 0x01;
+        }
+        if self.first_page_of_stream {
+            flags |= 0x02;
+        }
+        if self.last_page_of_stream {
+            flags |= 0x04;
+        }
+
+        let mut data = b"OggS\x00".to_vec();
+        data.push(flags);
+        data.extend(self.granule_position.to_le_bytes());
+        data.extend(self.stream_serial_number.to_le_bytes());
+        data.extend(self.page_sequence_number.to_le_bytes());
+        data.extend([0; 4]);
+        data.push(lacing_values.len() as u8);
+        data.extend(lacing_values);
+
+        data
+    }
+
+    fn lacing_values(&self) -> Vec<u8> {
+        let mut values = vec![];
+
+        for (i, &size) in self.packet_sizes.iter().enumerate() {
+            values.resize(values.len() + size / 255, 255);
+
+            // An incomplete last fragment has no terminating value.
+            if i + 1 < self.packet_sizes.len() || self.last_packet_completed {
+                values.push((size % 255) as u8);
+            }
+        }
+
+        values
+    }
+
+    // returns true if the first fragment continues a packet of the previous
+    // page
+    pub fn first_packet_continued(&self) -> bool {
+        self.first_packet_continued
+    }
+
+    // returns true if the last fragment ends a packet, false if the packet
+    // continues on the next page
+    pub fn last_packet_completed(&self) -> bool {
+        self.last_packet_completed
+    }
+
+    pub fn first_page_of_stream(&self) -> bool {
+        self.first_page_of_stream
+    }
+
+    pub fn last_page_of_stream(&self) -> bool {
+        self.last_page_of_stream
+    }
+
+    // returns the codec specific position of the last packet which ends on
+    // the page, or -1 if no packet ends on it
+    pub fn granule_position(&self) -> i64 {
+        self.granule_position
+    }
+
+    pub fn stream_serial_number(&self) -> u32 {
+        self.stream_serial_number
+    }
+
+    pub fn page_sequence_number(&self) -> u32 {
+        self.page_sequence_number
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    pub fn packet_sizes(&self) -> &[usize] {
+        &self.packet_sizes
+    }
+
+    // returns the size of the header, including the segment table
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.lacing_values().len()
+    }
+
+    // returns the size of the page data
+    pub fn data_size(&self) -> usize {
+        self.packet_sizes.iter().sum()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OggPage {
+    header: OggPageHeader,
+    // the packet fragments, as described by the header
+    packets: Vec<Vec<u8>>,
+}
+
+impl OggPage {
+    // reads the page at `offset` and verifies its checksum
+    pub fn read<R: Read + Seek>(file: &mut R, offset: u64) -> Result<Self> {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = read_block(file, HEADER_SIZE)?;
+        if data.len() < HEADER_SIZE {
+            return Err(Error::TruncatedHeader);
+        }
+        data.extend(read_block(file, data[26] as usize)?);
+
+        let header = OggPageHeader::parse(&data)?;
+        let header_size = data.len();
+        data.extend(read_block(file, header.data_size())?);
+        if data.len() < header_size + header.data_size() {
+            return Err(Error::TruncatedTag);
+        }
+
+        data[22..26].fill(0);
+        if crc32(&data) != header.checksum {
+            return Err(Error::ChecksumMismatch);
+        }
+
+        let mut packets = vec![];
+        let mut pos = header_size;
+        for &size in &header.packet_sizes {
+            packets.push(data[pos..pos + size].to_vec());
+            pos += size;
+        }
+
+        Ok(Self { header, packets })
+    }
+
+    // Renders the page and stores its checksum in the header.
+    pub fn render(&mut self) -> Vec<u8> {
+        let mut data = self.header.render();
+        for packet in &self.packets {
+            data.extend(packet);
+        }
+
+        self.header.checksum = crc32(&data);
+        data[22..26].copy_from_slice(&self.header.checksum.to_le_bytes());
+
+        data
+    }
+
+    pub fn header(&self) -> &OggPageHeader {
+        &self.header
+    }
+
+    // returns the packet fragments of the page
+    pub fn packets(&self) -> &[Vec<u8>] {
+        &self.packets
+    }
+
+    // returns the size of the page, including the header
+    pub fn size(&self) -> usize {
+        self.header.size() + self.header.data_size()
+    }
+
+    // Splits `packets` into pages, numbered from `first_sequence`.  The last
+    // packet continues on a following page if `last_packet_completed` is
+    // false, in which case its size must be a multiple of 255.  Every page
+    // which ends a packet gets `granule_position`; the others get -1.
+    pub fn paginate(
+        packets: &[Vec<u8>],
+        stream_serial_number: u32,
+        first_sequence: u32,
+        first_page_of_stream: bool,
+        last_packet_completed: bool,
+        granule_position: i64,
+    ) -> Vec<OggPage> {
+        let mut pages = vec![];
+        let mut header = OggPageHeader {
+            first_packet_continued: false,
+            first_page_of_stream,
+            last_page_of_stream: false,
+            granule_position: -1,
+            stream_serial_number,
+            page_sequence_number: first_sequence,
+            checksum: 0,
+            packet_sizes: vec![],
+            last_packet_completed: true,
+        };
+        let mut page_packets: Vec<Vec<u8>> = vec![];
+        let mut segments = 0;
+
+        for (i, packet) in packets.iter().enumerate() {
+            let is_last = i + 1 == packets.len();
+            let completed = !is_last || last_packet_completed;
+            let mut rest = &packet[..];
+
+            loop {
+                // The fragment which fits into the page, and whether it ends
+                // the packet.
+                let free = (MAX_SEGMENTS - segments) * 255;
+                let ends = completed && rest.len() < free;
+                let size = if ends {
+                    rest.len()
+                } else {
+                    rest.len().min(free)
+                };
+
+                page_packets.push(rest[..size].to_vec());
+                header.packet_sizes.push(size);
+                segments += size / 255 + ends as usize;
+                rest = &rest[size..];
+
+                if ends {
+                    header.granule_position = granule_position;
+                }
+
+                // Start a new page once this one is full.
+                let done = rest.is_empty() && (ends || !completed);
+                if segments == MAX_SEGMENTS || (done && is_last) {
+                    header.last_packet_completed = ends;
+                    let next_header = OggPageHeader {
+                        first_packet_continued: !ends,
+                        first_page_of_stream: false,
+                        granule_position: -1,
+                        page_sequence_number: header.page_sequence_number + 1,
+                        packet_sizes: vec![],
+                        last_packet_completed: true,
+                        ..header.clone()
+                    };
+                    pages.push(OggPage {
+                        header: std::mem::replace(&mut header, next_header),
+                        packets: std::mem::take(&mut page_packets),
+                    });
+                    segments = 0;
+                }
+
+                if done {
+                    break;
+                }
+            }
+        }
+
+        pages
+    }
+}
+
+// The header packets of a logical Ogg stream, like the identification and
+// comment headers of Vorbis.  They can be replaced, and the pages holding them
+// are rewritten on saving.
+pub struct OggStream {
+    d: OggStreamPrivate,
+}
+
+struct OggStreamPrivate {
+    stream_serial_number: u32,
+    packets: Vec<Vec<u8>>,
+    // whether each header packet has been replaced
+    dirty: Vec<bool>,
+    // packets after the header packets on the last header page, with the
+    // last one continuing on the next page unless `extra_completed`
+    extra_packets: Vec<Vec<u8>>,
+    extra_completed: bool,
+    // the offset of the first page and the number of pages holding all header
+    // packets but the first one, which stays on its own page where possible
+    rewrite_offset: u64,
+    rewrite_index: u32,
+    rewrite_page_count: u32,
+    header_end: u64,
+    last_header_granule: i64,
+}
+
+impl OggStream {
+    // reads the first `packet_count` packets of the stream, reassembling
+    // packets which span several pages
+    pub fn read<R: Read + Seek>(file: &mut R, packet_count: usize) -> Result<Self> {
+        let mut packets: Vec<Vec<u8>> = vec![];
+        let mut extra_packets = vec![];
+        let mut pending: Option<Vec<u8>> = None;
+        let mut stream_serial_number = None;
+
+        // the page index and offset after the page ending the first packet
+        let mut first_packet_end = None;
+        let mut first_packet_page_shared = false;
+
+        let mut offset = 0;
+        let mut index = 0;
+        let mut last_header_granule = 0;
+
+        while packets.len() < packet_count {
+            let OggPage {
+                header,
+                packets: fragments,
+            } = OggPage::read(file, offset)?;
+
+            match stream_serial_number {
+                None => stream_serial_number = Some(header.stream_serial_number),
+                Some(serial) if serial != header.stream_serial_number => {
+                    return Err(Error::UnsupportedFormat);
+                }
+                _ => (),
+            }
+
+            let fragment_count = fragments.len();
+            for (i, fragment) in fragments.into_iter().enumerate() {
+                let mut packet = pending.take().unwrap_or_default();
+                packet.extend(fragment);
+
+                if i + 1 < fragment_count || header.last_packet_completed {
+                    if packets.len() < packet_count {
+                        packets.push(packet);
+                    } else {
+                        extra_packets.push(packet);
+                    }
+
+                    if packets.len() == 1 && first_packet_end.is_none() {
+                        first_packet_end = Some((index + 1, offset + page_size(&header)));
+                        first_packet_page_shared = i + 1 < fragment_count;
+                    }
+                } else {
+                    pending = Some(packet);
+                }
+            }
+
+            offset += page_size(&header);
+            index += 1;
+            last_header_granule = header.granule_position;
+        }
+
+        // Packets ending on the same page as the last header packet are kept
+        // as they are.
+        let extra_completed = pending.is_none();
+        extra_packets.extend(pending);
+
+        // The first packet usually fills the first page, so it can be left
+        // alone.  Otherwise all header pages are rewritten.
+        let (rewrite_index, rewrite_offset) = match first_packet_end {
+            Some(end) if !first_packet_page_shared => end,
+            _ => (0, 0),
+        };
+
+        let packet_count = packets.len();
+        Ok(Self {
+            d: OggStreamPrivate {
+                stream_serial_number: stream_serial_number.unwrap_or_default(),
+                packets,
+                dirty: vec![false; packet_count],
+                extra_packets,
+                extra_completed,
+                rewrite_offset,
+                rewrite_index,
+                rewrite_page_count: index - rewrite_index,
+                header_end: offset,
+                last_header_granule,
+            },
+        })
+    }
+
+    pub fn stream_serial_number(&self) -> u32 {
+        self.d.stream_serial_number
+    }
+
+    // returns the header packet `index`
+    pub fn packet(&self, index: usize) -> Option<&[u8]> {
+        self.d.packets.get(index).map(|packet| &packet[..])
+    }
+
+    // replaces the header packet `index`
+    pub fn set_packet(&mut self, index: usize, data: Vec<u8>) {
+        if let Some(packet) = self.d.packets.get_mut(index) {
+            *packet = data;
+            self.d.dirty[index] = true;
+        }
+    }
+
+    // returns the offset right after the last page holding a header packet
+    pub fn header_end(&self) -> u64 {
+        self.d.header_end
+    }
+
+    // Writes the pages holding the header packets again.  If their number
+    // changes, the sequence numbers of all following pages of the stream are
+    // updated.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        if !self.d.dirty.iter().any(|&dirty| dirty) {
+            return Ok(());
+        }
+
+        let first_packet = if self.d.rewrite_index == 0 { 0 } else { 1 };
+        let mut packets = self.d.packets[first_packet..].to_vec();
+        packets.extend(self.d.extra_packets.iter().cloned());
+
+        let completed = self.d.extra_packets.is_empty() || self.d.extra_completed;
+        let mut pages = OggPage::paginate(
+            &packets,
+            self.d.stream_serial_number,
+            self.d.rewrite_index,
+            self.d.rewrite_index == 0,
+            completed,
+            self.d.last_header_granule,
+        );
+
+        let data: Vec<u8> = pages.iter_mut().flat_map(|page| page.render()).collect();
+        let old_size = self.d.header_end - self.d.rewrite_offset;
+        insert_block(file, &data, self.d.rewrite_offset, old_size)?;
+
+        let page_count = pages.len() as u32;
+        let header_end = self.d.rewrite_offset + data.len() as u64;
+        if page_count != self.d.rewrite_page_count {
+            let delta = page_count as i64 - self.d.rewrite_page_count as i64;
+            renumber_pages(file, header_end, self.d.stream_serial_number, delta)?;
+        }
+
+        self.d.rewrite_page_count = page_count;
+        self.d.header_end = header_end;
+        self.d.dirty.fill(false);
+
+        Ok(())
+    }
+}
+
+// returns the size of the page with the given header
+fn page_size(header: &OggPageHeader) -> u64 {
+    (header.size() + header.data_size()) as u64
+}
+
+// Adds `delta` to the sequence numbers of the pages of the stream from
+// `offset` to the end of the file.
+fn renumber_pages<F: Read + Write + Seek>(
+    file: &mut F,
+    mut offset: u64,
+    stream_serial_number: u32,
+    delta: i64,
+) -> Result<()> {
+    let file_length = file.seek(SeekFrom::End(0))?;
+
+    while offset < file_length {
+        let mut page = match OggPage::read(file, offset) {
+            Ok(page) => page,
+            // Trailing garbage or a damaged page ends the stream.
+            Err(Error::Io(e)) => return Err(Error::Io(e)),
+            Err(_) => break,
+        };
+
+        let size = page.size() as u64;
+        if page.header.stream_serial_number == stream_serial_number {
+            let sequence = page.header.page_sequence_number as i64 + delta;
+            page.header.page_sequence_number = sequence as u32;
+
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&page.render())?;
+        }
+
+        offset += size;
+    }
+
+    Ok(())
+}
+
+// Returns the header of the last page of the stream, searching backwards
+// from the end of the file.
+pub fn last_page_header<R: Read + Seek>(
+    file: &mut R,
+    stream_serial_number: u32,
+) -> Result<Option<OggPageHeader>> {
+    const MAX_PAGE_SIZE: u64 = (HEADER_SIZE + MAX_SEGMENTS + MAX_SEGMENTS * 255) as u64;
+
+    let file_length = file.seek(SeekFrom::End(0))?;
+    let start = file_length.saturating_sub(MAX_PAGE_SIZE * 2);
+    file.seek(SeekFrom::Start(start))?;
+    let data = read_block(file, (file_length - start) as usize)?;
+
+    let mut end = data.len();
+    while let Some(pos) = data[..end].windows(4).rposition(|w| w == b"OggS") {
+        if let Ok(header) = OggPageHeader::parse(&data[pos..]) {
+            if header.stream_serial_number == stream_serial_number {
+                return Ok(Some(header));
+            }
+        }
+        end = pos + 3;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn stream(header_packets: &[Vec<u8>], audio_pages: u32) -> Vec<u8> {
+        let mut data = vec![];
+        let mut pages = OggPage::paginate(&header_packets[..1], 7, 0, true, true, 0);
+        pages.extend(OggPage::paginate(
+            &header_packets[1..],
+            7,
+            pages.len() as u32,
+            false,
+            true,
+            0,
+        ));
+        for i in 0..audio_pages {
+            let sequence = pages.len() as u32;
+            let packet = vec![i as u8; 100];
+            pages.extend(OggPage::paginate(
+                &[packet],
+                7,
+                sequence,
+                false,
+                true,
+                1000 * (i as i64 + 1),
+            ));
+        }
+        for page in &mut pages {
+            data.extend(page.render());
+        }
+        data
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn test_page() {
+        let packets = vec![vec![1; 10], vec![2; 70_000], vec![3; 255]];
+        let mut pages = OggPage::paginate(&packets, 1, 5, true, true, 42);
+        assert_eq!(pages.len(), 2);
+
+        let first = pages[0].header().clone();
+        assert!(first.first_page_of_stream());
+        assert!(!first.last_packet_completed());
+        assert_eq!(first.granule_position(), 42);
+        assert_eq!(first.packet_sizes(), [10, 254 * 255]);
+
+        let second = pages[1].header().clone();
+        assert!(second.first_packet_continued());
+        assert!(second.last_packet_completed());
+        assert_eq!(second.page_sequence_number(), 6);
+        assert_eq!(second.packet_sizes(), [70_000 - 254 * 255, 255]);
+
+        let data = pages[1].render();
+        let page = OggPage::read(&mut Cursor::new(data.clone()), 0).unwrap();
+        assert_eq!(page, pages[1]);
+        assert_eq!(page.size(), data.len());
+
+        let mut corrupt = data;
+        corrupt[40] ^= 1;
+        let result = OggPage::read(&mut Cursor::new(corrupt), 0);
+        assert!(matches!(result, Err(Error::ChecksumMismatch)));
+    }
+
+    #[test]
+    fn test_stream() {
+        let header_packets = vec![vec![1; 30], vec![2; 300], vec![3; 3000]];
+        let data = stream(&header_packets, 3);
+        let mut cursor = Cursor::new(data);
+
+        let mut ogg = OggStream::read(&mut cursor, 3).unwrap();
+        assert_eq!(ogg.stream_serial_number(), 7);
+        assert_eq!(ogg.packet(1), Some(&header_packets[1][..]));
+        assert_eq!(ogg.packet(2), Some(&header_packets[2][..]));
+        assert_eq!(ogg.packet(3), None);
+
+        // growing the second packet adds a page
+        ogg.set_packet(1, vec![4; 100_000]);
+        ogg.save_to(&mut cursor).unwrap();
+
+        let ogg = OggStream::read(&mut cursor, 3).unwrap();
+        assert_eq!(ogg.packet(1).unwrap().len(), 100_000);
+        assert_eq!(ogg.packet(2), Some(&header_packets[2][..]));
+
+        // all pages are numbered in order and have valid checksums
+        let mut offset = 0;
+        let mut sequence = 0;
+        while offset < cursor.get_ref().len() as u64 {
+            let page = OggPage::read(&mut cursor, offset).unwrap();
+            assert_eq!(page.header().page_sequence_number(), sequence);
+            offset += page.size() as u64;
+            sequence += 1;
+        }
+        assert_eq!(sequence, 6);
+
+        let header = last_page_header(&mut cursor, 7).unwrap().unwrap();
+        assert_eq!(header.granule_position(), 3000);
+        assert_eq!(header.page_sequence_number(), 5);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    flac::{BlockType, FlacProperties},
+    ogg::OggStream,
+    tag::Tag,
+    utils::Truncate,
+    xiph_comment::XiphComment,
+    AudioFile,
+};
+
+// the first packet starts with this signature, followed by the mapping
+// version, the number of header packets and the native FLAC signature
+const MAPPING_HEADER: &[u8] = b"\x7fFLAC";
+
+// the size of the first packet, which ends with the STREAMINFO block
+const MAPPING_HEADER_SIZE: usize = 51;
+
+// the most metadata blocks read when the number of header packets is unknown
+const MAX_HEADER_PACKETS: usize = 1024;
+
+// FLAC streams in an Ogg container.  Each metadata block after STREAMINFO is
+// a packet of its own.
+pub struct OggFlacFile {
+    pub tag: XiphComment,
+    pub audio_properties: FlacProperties,
+    path: Option<PathBuf>,
+    stream: OggStream,
+    // the index of the VORBIS_COMMENT packet, if there is one
+    comment_packet: Option<usize>,
+}
+
+impl AudioFile for OggFlacFile {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut ogg_flac_file = Self::read_from(&mut file, style)?;
+        ogg_flac_file.path = Some(path);
+
+        Ok(ogg_flac_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl OggFlacFile {
+    // Reads the header packets from any seekable stream.  Files read this way
+    // have no path, so they are saved with `save_to`.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        let stream = OggStream::read(file, 1)?;
+        let mapping = stream.packet(0).ok_or(Error::TruncatedHeader)?;
+        if mapping.len() < MAPPING_HEADER_SIZE {
+            return Err(Error::TruncatedHeader);
+        }
+        if !mapping.starts_with(MAPPING_HEADER) || &mapping[9..13] != b"fLaC" {
+            return Err(Error::InvalidHeader);
+        }
+        if mapping[5] != 1 {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        // The mapping header may leave the number of header packets open, in
+        // which case they end with the block flagged as the last one.
+        let header_packets = u16::from_be_bytes([mapping[7], mapping[8]]) as usize;
+        let stream = if header_packets > 0 {
+            OggStream::read(file, 1 + header_packets)?
+        } else if mapping[13] & 0x80 != 0 {
+            stream
+        } else {
+            let mut count = 2;
+            loop {
+                let stream = OggStream::read(file, count)?;
+                let is_last = stream.packet(count - 1).and_then(|p| p.first()).copied();
+                if is_last.unwrap_or(0x80) & 0x80 != 0 || count == MAX_HEADER_PACKETS {
+                    break stream;
+                }
+                count += 1;
+            }
+        };
+
+        let mut tag = None;
+        let mut comment_packet = None;
+        for index in 1.. {
+            let Some(packet) = stream.packet(index) else {
+                break;
+            };
+            let code = packet.first().map(|code| code & 0x7f);
+            if code == Some(BlockType::VorbisComment.into()) && packet.len() >= 4 {
+                tag = Some(XiphComment::parse(&packet[4..])?);
+                comment_packet = Some(index);
+                break;
+            }
+        }
+
+        let mapping = stream.packet(0).ok_or(Error::TruncatedHeader)?;
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let stream_length = file_length.saturating_sub(stream.header_end());
+        let audio_properties = FlacProperties::new(&mapping[17..], stream_length)?;
+
+        Ok(Self {
+            tag: tag.unwrap_or_default(),
+            audio_properties,
+            path: None,
+            stream,
+            comment_packet,
+        })
+    }
+
+    // Writes the VORBIS_COMMENT packet.  The pages after it are only moved if
+    // it needs a different number of pages.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.  Streams without a VORBIS_COMMENT block cannot be saved.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        let index = self.comment_packet.ok_or(Error::NoTagFound)?;
+        let old_packet = self.stream.packet(index).ok_or(Error::NoTagFound)?;
+
+        let comment = self.tag.render(false);
+        let length = (comment.len() as u32).to_be_bytes();
+
+        // Keep the flag marking the last metadata block.
+        let mut packet = vec![old_packet[0], length[1], length[2], length[3]];
+        packet.extend(comment);
+
+        self.stream.set_packet(index, packet);
+        self.stream.save_to(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::OggPage;
+    use std::io::Cursor;
+
+    // a stream with two header packets, the comment flagged as last block,
+    // and two seconds of audio
+    fn ogg_flac_file(header_packets: u16) -> Vec<u8> {
+        let mut mapping = MAPPING_HEADER.to_vec();
+        mapping.extend([1, 0]);
+        mapping.extend(header_packets.to_be_bytes());
+        mapping.extend(b"fLaC\x00\x00\x00\x22");
+        mapping.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        let flags: u64 = (48000 << 44) | (1 << 41) | (23 << 36) | 96_000;
+        mapping.extend(flags.to_be_bytes());
+        mapping.extend([0; 16]);
+
+        let mut tag = XiphComment::empty();
+        tag.set_artist(Some(String::from("Artist")));
+        let comment = tag.render(false);
+        let mut comment_block = vec![0x84, 0, 0, comment.len() as u8];
+        comment_block.extend(comment);
+
+        let seek_table = vec![0x03, 0, 0, 0];
+
+        let mut pages = OggPage::paginate(&[mapping], 4, 0, true, true, 0);
+        pages.extend(OggPage::paginate(
+            &[seek_table, comment_block],
+            4,
+            1,
+            false,
+            true,
+            0,
+        ));
+        pages.extend(OggPage::paginate(
+            &[vec![0; 4000]],
+            4,
+            2,
+            false,
+            true,
+            96_000,
+        ));
+
+        pages.iter_mut().flat_map(|page| page.render()).collect()
+    }
+
+    #[test]
+    fn test_read_and_save() {
+        for header_packets in [2, 0] {
+            let mut cursor = Cursor::new(ogg_flac_file(header_packets));
+            let mut file = OggFlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+            let properties = &file.audio_properties;
+            assert_eq!(properties.length_in_milliseconds(), 2000);
+            assert_eq!(properties.sample_rate(), 48000);
+            assert_eq!(properties.channels(), 2);
+            assert_eq!(properties.bits_per_sample(), 24);
+            assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+
+            file.tag.set_album(Some(String::from("Album")));
+            file.save_to(&mut cursor).unwrap();
+
+            let file = OggFlacFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+            assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+            assert_eq!(file.tag.album().as_deref(), Some("Album"));
+            assert_eq!(file.stream.packet(2).unwrap()[0], 0x84);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    ogg::{self, OggStream},
+    tag::Tag,
+    utils::Truncate,
+    xiph_comment::XiphComment,
+    AudioFile,
+};
+
+const IDENTIFICATION_HEADER: &[u8] = b"OpusHead";
+const COMMENT_HEADER: &[u8] = b"OpusTags";
+
+// Opus always decodes at 48 kHz, whatever the rate of the input was.
+const OUTPUT_SAMPLE_RATE: u32 = 48000;
+
+const R128_TRACK_GAIN: &str = "R128_TRACK_GAIN";
+const R128_ALBUM_GAIN: &str = "R128_ALBUM_GAIN";
+
+#[derive(Clone)]
+pub(crate) struct OpusPropertiesPrivate {
+    length: u32,
+    bitrate: u32,
+    channels: u32,
+    opus_version: u32,
+    input_sample_rate: u32,
+    pre_skip: u32,
+    output_gain: i16,
+    channel_mapping_family: u8,
+}
+
+#[derive(Clone)]
+pub struct OpusProperties {
+    d: OpusPropertiesPrivate,
+}
+
+impl AudioProperties for OpusProperties {
+    fn length(&self) -> u32 {
+        self.length_in_seconds()
+    }
+
+    fn length_in_seconds(&self) -> u32 {
+        self.d.length / 1000
+    }
+
+    fn length_in_milliseconds(&self) -> u32 {
+        self.d.length
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.d.bitrate
+    }
+
+    // returns 48000, the rate Opus is always decoded at; see
+    // `input_sample_rate` for the rate of the original audio
+    fn sample_rate(&self) -> u32 {
+        OUTPUT_SAMPLE_RATE
+    }
+
+    fn channels(&self) -> u32 {
+        self.d.channels
+    }
+}
+
+impl OpusProperties {
+    // Reads the OpusHead packet.  The length is computed from the granule
+    // position of the last page, less the samples skipped at the start.
+    pub(crate) fn new<R: Read + Seek>(file: &mut R, stream: &OggStream) -> Result<Self> {
+        let data = stream.packet(0).ok_or(Error::TruncatedHeader)?;
+        if data.len() < 19 {
+            return Err(Error::TruncatedHeader);
+        }
+        if !data.starts_with(IDENTIFICATION_HEADER) {
+            return Err(Error::InvalidHeader);
+        }
+
+        let opus_version = data[8] as u32;
+        let channels = data[9] as u32;
+        let pre_skip = u16::from_le_bytes([data[10], data[11]]) as u32;
+        let input_sample_rate = u32::from_le_bytes(data[12..16].try_into().unwrap());
+        let output_gain = i16::from_le_bytes([data[16], data[17]]);
+        let channel_mapping_family = data[18];
+
+        // Only the major version in the upper four bits is incompatible.
+        if opus_version >> 4 != 0 {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        let granule_position = ogg::last_page_header(file, stream.stream_serial_number())?
+            .map_or(0, |header| header.granule_position().max(0) as u64);
+        let samples = granule_position.saturating_sub(pre_skip as u64);
+        let length = samples
+            .checked_mul(1000)
+            .map_or(0, |ms| ms / OUTPUT_SAMPLE_RATE as u64) as u32;
+
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let stream_length = file_length.saturating_sub(stream.header_end());
+        let bitrate = if length > 0 {
+            (stream_length * 8 / length as u64) as u32
+        } else {
+            0
+        };
+
+        Ok(Self {
+            d: OpusPropertiesPrivate {
+                length,
+                bitrate,
+                channels,
+                opus_version,
+                input_sample_rate,
+                pre_skip,
+                output_gain,
+                channel_mapping_family,
+            },
+        })
+    }
+
+    pub fn opus_version(&self) -> u32 {
+        self.d.opus_version
+    }
+
+    // returns the sample rate of the audio before it was encoded, 0 if unknown
+    pub fn input_sample_rate(&self) -> u32 {
+        self.d.input_sample_rate
+    }
+
+    // returns the number of samples at 48 kHz to discard from the start
+    pub fn pre_skip(&self) -> u32 {
+        self.d.pre_skip
+    }
+
+    // returns the gain to apply on decoding in dB, as a Q7.8 fixed point
+    // number
+    pub fn output_gain(&self) -> i16 {
+        self.d.output_gain
+    }
+
+    // returns 0 for mono and stereo streams, and the channel mapping family
+    // of multichannel ones
+    pub fn channel_mapping_family(&self) -> u8 {
+        self.d.channel_mapping_family
+    }
+}
+
+pub struct OpusFile {
+    pub tag: XiphComment,
+    pub audio_properties: OpusProperties,
+    path: Option<PathBuf>,
+    stream: OggStream,
+}
+
+impl AudioFile for OpusFile {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut opus_file = Self::read_from(&mut file, style)?;
+        opus_file.path = Some(path);
+
+        Ok(opus_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl OpusFile {
+    // Reads the header packets from any seekable stream.  Files read this way
+    // have no path, so they are saved with `save_to`.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        // The OpusHead and OpusTags packets.
+        let stream = OggStream::read(file, 2)?;
+
+        let comment = stream.packet(1).ok_or(Error::NoTagFound)?;
+        if !comment.starts_with(COMMENT_HEADER) {
+            return Err(Error::InvalidHeader);
+        }
+        let tag = XiphComment::parse(&comment[COMMENT_HEADER.len()..])?;
+
+        let audio_properties = OpusProperties::new(file, &stream)?;
+
+        Ok(Self {
+            tag,
+            audio_properties,
+            path: None,
+            stream,
+        })
+    }
+
+    // Writes the OpusTags packet.  The pages after it are only moved if it
+    // needs a different number of pages.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        let mut packet = COMMENT_HEADER.to_vec();
+        packet.extend(self.tag.render(false));
+
+        self.stream.set_packet(1, packet);
+        self.stream.save_to(file)
+    }
+
+    // returns the R128_TRACK_GAIN field, the gain in dB on top of the output
+    // gain which normalizes the track, as a Q7.8 fixed point number
+    pub fn r128_track_gain(&self) -> Option<i16> {
+        self.r128_gain(R128_TRACK_GAIN)
+    }
+
+    pub fn set_r128_track_gain(&mut self, gain: Option<i16>) {
+        self.set_r128_gain(R128_TRACK_GAIN, gain);
+    }
+
+    // returns the R128_ALBUM_GAIN field, the gain in dB on top of the output
+    // gain which normalizes the album, as a Q7.8 fixed point number
+    pub fn r128_album_gain(&self) -> Option<i16> {
+        self.r128_gain(R128_ALBUM_GAIN)
+    }
+
+    pub fn set_r128_album_gain(&mut self, gain: Option<i16>) {
+        self.set_r128_gain(R128_ALBUM_GAIN, gain);
+    }
+
+    // Gains are stored as decimal integers.  Values which are not are ignored.
+    fn r128_gain(&self, name: &str) -> Option<i16> {
+        self.tag.field(name)?.first()?.trim().parse().ok()
+    }
+
+    fn set_r128_gain(&mut self, name: &str, gain: Option<i16>) {
+        let values = gain.map(|gain| gain.to_string()).into_iter().collect();
+        self.tag.set_field(name, values);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::OggPage;
+    use std::io::Cursor;
+
+    // a stereo stream with 312 samples of pre-skip, followed by five seconds
+    // of audio pages
+    fn opus_file() -> Vec<u8> {
+        let mut head = IDENTIFICATION_HEADER.to_vec();
+        head.extend([1, 2]);
+        head.extend(312u16.to_le_bytes());
+        head.extend(44100u32.to_le_bytes());
+        head.extend((-256i16).to_le_bytes());
+        head.push(0);
+
+        let mut tag = XiphComment::empty();
+        tag.set_vendor("libopus 1.4");
+        tag.add_field("R128_TRACK_GAIN", "-1280");
+        let mut tags = COMMENT_HEADER.to_vec();
+        tags.extend(tag.render(false));
+
+        let mut pages = OggPage::paginate(&[head], 2, 0, true, true, 0);
+        pages.extend(OggPage::paginate(&[tags], 2, 1, false, true, 0));
+        for i in 0..5 {
+            let granule_position = 312 + 48000 * (i as i64 + 1);
+            let packets = vec![vec![0; 500]; 2];
+            pages.extend(OggPage::paginate(
+                &packets,
+                2,
+                i + 2,
+                false,
+                true,
+                granule_position,
+            ));
+        }
+
+        pages.iter_mut().flat_map(|page| page.render()).collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let mut cursor = Cursor::new(opus_file());
+        let file = OpusFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        let properties = &file.audio_properties;
+        assert_eq!(properties.length_in_milliseconds(), 5000);
+        assert_eq!(properties.sample_rate(), 48000);
+        assert_eq!(properties.input_sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.pre_skip(), 312);
+        assert_eq!(properties.output_gain(), -256);
+        assert_eq!(properties.channel_mapping_family(), 0);
+        assert_eq!(properties.opus_version(), 1);
+
+        assert_eq!(file.tag.vendor(), "libopus 1.4");
+        assert_eq!(file.r128_track_gain(), Some(-1280));
+        assert_eq!(file.r128_album_gain(), None);
+    }
+
+    #[test]
+    fn test_save() {
+        let mut cursor = Cursor::new(opus_file());
+
+        let mut file = OpusFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        file.tag.set_title(Some(String::from("Episode 1")));
+        file.set_r128_track_gain(None);
+        file.set_r128_album_gain(Some(384));
+        file.save_to(&mut cursor).unwrap();
+
+        let file = OpusFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("Episode 1"));
+        assert_eq!(file.r128_track_gain(), None);
+        assert_eq!(file.r128_album_gain(), Some(384));
+        assert_eq!(file.tag.field("r128_album_gain").unwrap(), ["384"]);
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 5000);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    ogg::{self, OggStream},
+    tag::Tag,
+    utils::Truncate,
+    xiph_comment::XiphComment,
+    AudioFile,
+};
+
+const SPEEX_HEADER: &[u8] = b"Speex   ";
+
+// the size of the Speex header packet
+const SPEEX_HEADER_SIZE: usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum SpeexMode {
+    // 8 kHz
+    Narrowband = 0,
+    // 16 kHz
+    Wideband = 1,
+    // 32 kHz
+    UltraWideband = 2,
+}
+
+#[derive(Clone)]
+pub(crate) struct SpeexPropertiesPrivate {
+    length: u32,
+    bitrate: u32,
+    bitrate_nominal: i32,
+    sample_rate: u32,
+    channels: u32,
+    speex_version: u32,
+    mode: Option<SpeexMode>,
+    vbr: bool,
+}
+
+#[derive(Clone)]
+pub struct SpeexProperties {
+    d: SpeexPropertiesPrivate,
+}
+
+impl AudioProperties for SpeexProperties {
+    fn length(&self) -> u32 {
+        self.length_in_seconds()
+    }
+
+    fn length_in_seconds(&self) -> u32 {
+        self.d.length / 1000
+    }
+
+    fn length_in_milliseconds(&self) -> u32 {
+        self.d.length
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.d.bitrate
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.d.sample_rate
+    }
+
+    fn channels(&self) -> u32 {
+        self.d.channels
+    }
+}
+
+impl SpeexProperties {
+    // Reads the Speex header packet.  The length is computed from the granule
+    // position of the last page.
+    pub(crate) fn new<R: Read + Seek>(file: &mut R, stream: &OggStream) -> Result<Self> {
+        let data = stream.packet(0).ok_or(Error::TruncatedHeader)?;
+        if data.len() < SPEEX_HEADER_SIZE {
+            return Err(Error::TruncatedHeader);
+        }
+        if !data.starts_with(SPEEX_HEADER) {
+            return Err(Error::InvalidHeader);
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let speex_version = u32_at(28);
+        let sample_rate = u32_at(36);
+        let mode = SpeexMode::try_from(u32_at(40)).ok();
+        let channels = u32_at(48);
+        let bitrate_nominal = u32_at(52) as i32;
+        let vbr = u32_at(60) == 1;
+
+        if sample_rate == 0 {
+            return Err(Error::InvalidHeader);
+        }
+
+        let granule_position = ogg::last_page_header(file, stream.stream_serial_number())?
+            .map_or(0, |header| header.granule_position().max(0) as u64);
+        let length = granule_position
+            .checked_mul(1000)
+            .map_or(0, |ms| ms / sample_rate as u64) as u32;
+
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let stream_length = file_length.saturating_sub(stream.header_end());
+        let bitrate = if length > 0 {
+            (stream_length * 8 / length as u64) as u32
+        } else {
+            bitrate_nominal.max(0) as u32 / 1000
+        };
+
+        Ok(Self {
+            d: SpeexPropertiesPrivate {
+                length,
+                bitrate,
+                bitrate_nominal,
+                sample_rate,
+                channels,
+                speex_version,
+                mode,
+                vbr,
+            },
+        })
+    }
+
+    pub fn speex_version(&self) -> u32 {
+        self.d.speex_version
+    }
+
+    // returns the mode of the stream, or None for unknown modes
+    pub fn mode(&self) -> Option<SpeexMode> {
+        self.d.mode
+    }
+
+    // returns true if the stream uses variable bitrate
+    pub fn is_vbr(&self) -> bool {
+        self.d.vbr
+    }
+
+    // returns the bitrate in b/s given by the header, or -1 if unknown
+    pub fn bitrate_nominal(&self) -> i32 {
+        self.d.bitrate_nominal
+    }
+}
+
+pub struct SpeexFile {
+    pub tag: XiphComment,
+    pub audio_properties: SpeexProperties,
+    path: Option<PathBuf>,
+    stream: OggStream,
+}
+
+impl AudioFile for SpeexFile {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut speex_file = Self::read_from(&mut file, style)?;
+        speex_file.path = Some(path);
+
+        Ok(speex_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl SpeexFile {
+    // Reads the header packets from any seekable stream.  Files read this way
+    // have no path, so they are saved with `save_to`.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        // The Speex header and the comment, which has no packet type.
+        let stream = OggStream::read(file, 2)?;
+
+        let tag = XiphComment::parse(stream.packet(1).ok_or(Error::NoTagFound)?)?;
+        let audio_properties = SpeexProperties::new(file, &stream)?;
+
+        Ok(Self {
+            tag,
+            audio_properties,
+            path: None,
+            stream,
+        })
+    }
+
+    // Writes the comment packet.  The pages after it are only moved if it
+    // needs a different number of pages.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        self.stream.set_packet(1, self.tag.render(true));
+        self.stream.save_to(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::OggPage;
+    use std::io::Cursor;
+
+    // a wideband mono VBR stream, followed by three seconds of audio pages
+    fn speex_file() -> Vec<u8> {
+        let mut header = SPEEX_HEADER.to_vec();
+        header.extend(b"1.2.1");
+        header.resize(28, 0);
+        for value in [1u32, 80, 16000, 1, 4, 1, u32::MAX, 320, 1, 1, 0, 0, 0] {
+            header.extend(value.to_le_bytes());
+        }
+
+        let mut tag = XiphComment::empty();
+        tag.set_vendor("Encoded with Speex 1.2.1");
+        tag.set_title(Some(String::from("Title")));
+
+        let mut pages = OggPage::paginate(&[header], 3, 0, true, true, 0);
+        pages.extend(OggPage::paginate(
+            &[tag.render(false)],
+            3,
+            1,
+            false,
+            true,
+            0,
+        ));
+        for i in 0..3 {
+            let packets = vec![vec![0; 300]; 2];
+            pages.extend(OggPage::paginate(
+                &packets,
+                3,
+                i + 2,
+                false,
+                true,
+                16000 * (i as i64 + 1),
+            ));
+        }
+
+        pages.iter_mut().flat_map(|page| page.render()).collect()
+    }
+
+    #[test]
+    fn test_read_and_save() {
+        let mut cursor = Cursor::new(speex_file());
+        let mut file = SpeexFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+
+        let properties = &file.audio_properties;
+        assert_eq!(properties.length_in_milliseconds(), 3000);
+        assert_eq!(properties.sample_rate(), 16000);
+        assert_eq!(properties.channels(), 1);
+        assert_eq!(properties.mode(), Some(SpeexMode::Wideband));
+        assert!(properties.is_vbr());
+        assert_eq!(properties.bitrate_nominal(), -1);
+        assert_eq!(properties.speex_version(), 1);
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+
+        file.tag.set_title(Some(String::from("New title")));
+        file.save_to(&mut cursor).unwrap();
+
+        let file = SpeexFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.title().as_deref(), Some("New title"));
+        assert_eq!(file.tag.vendor(), "Encoded with Speex 1.2.1");
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 3000);
+    }
+}
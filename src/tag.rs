@@ -1,11 +1,13 @@
 use std::collections::HashMap;
 
 pub trait Tag {
-    fn properties(&self) -> &PropertyMap;
+    fn properties(&self) -> PropertyMap;
 
     fn remove_unsupported_properties(&mut self, properties: Vec<String>);
 
-    fn set_properties(&mut self, properties: PropertyMap);
+    // replaces the tag contents with `properties`, returning the properties
+    // which the tag format cannot store
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap;
 
     fn title(&self) -> &Option<String>;
     fn artist(&self) -> &Option<String>;
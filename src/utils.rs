@@ -1,11 +1,49 @@
+use std::{
+    fs::File,
+    io::{Cursor, Read, Result, Seek, SeekFrom, Write},
+};
+
+/// Streams which can be shortened.  Saving needs this when a tag shrinks or
+/// is removed.
+pub trait Truncate {
+    /// Truncates the stream to `size` bytes.
+    fn truncate(&mut self, size: u64) -> Result<()>;
+}
+
+impl Truncate for File {
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.set_len(size)
+    }
+}
+
+impl Truncate for Cursor<Vec<u8>> {
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.get_mut().truncate(size as usize);
+        Ok(())
+    }
+}
+
+impl Truncate for Cursor<&mut Vec<u8>> {
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.get_mut().truncate(size as usize);
+        Ok(())
+    }
+}
+
+impl<T: Truncate + ?Sized> Truncate for &mut T {
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        (**self).truncate(size)
+    }
+}
+
 /// Searches the ByteVector for `pattern` starting at `offset` and returns
 /// the offset.  Returns None if the pattern was not found.  If `byteAlign` is
 /// specified the pattern will only be matched if it starts on `byte` divisible
 /// by `byteAlign` (starting from `offset`).
 /// `offset` by default is 0, and `byte_align` by default is 1
 pub(crate) fn byte_vec_find(
-    bytes: &Vec<u8>,
-    pattern: &Vec<u8>,
+    bytes: &[u8],
+    pattern: &[u8],
     offset: usize,
     byte_align: usize,
 ) -> Option<usize> {
@@ -41,6 +79,128 @@ pub(crate) fn byte_vec_find(
     None
 }
 
+/// Reads up to `length` bytes from the current position of `reader`.  The
+/// returned vector is shorter than `length` only if the end of the stream was
+/// reached.  `length` usually comes from the file itself, so the buffer grows
+/// as data is read instead of being allocated up front.
+pub(crate) fn read_block<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(length as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Returns the `length` bytes of `data` at `pos` and moves `pos` past them.
+pub(crate) fn take<'a>(data: &'a [u8], pos: &mut usize, length: usize) -> crate::Result<&'a [u8]> {
+    let value = data
+        .get(*pos..pos.saturating_add(length))
+        .ok_or(crate::Error::TruncatedTag)?;
+    *pos += length;
+
+    Ok(value)
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes `data` as padded standard base64.
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Decodes standard base64, with or without padding.  Returns None if `text`
+/// contains other characters.
+pub(crate) fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let index = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+            bits |= index << (18 - 6 * i);
+        }
+
+        decoded.extend(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+
+    Some(decoded)
+}
+
+/// Writes `data` at `start`, replacing `replace` bytes of the file.  The data
+/// following the replaced block is moved when the sizes differ, and the file
+/// is truncated if it shrinks.
+pub(crate) fn insert_block<F: Read + Write + Seek + Truncate>(
+    file: &mut F,
+    data: &[u8],
+    start: u64,
+    replace: u64,
+) -> Result<()> {
+    const BUFFER_SIZE: u64 = 64 * 1024;
+
+    let length = file.seek(SeekFrom::End(0))?;
+    let old_tail = start + replace;
+    let new_tail = start + data.len() as u64;
+
+    if new_tail > old_tail {
+        // Move the tail towards the end, starting with its last block so that
+        // nothing is overwritten before it is read.
+        let shift = new_tail - old_tail;
+        let mut end = length;
+
+        while end > old_tail {
+            let from = end - BUFFER_SIZE.min(end - old_tail);
+            file.seek(SeekFrom::Start(from))?;
+            let buffer = read_block(file, (end - from) as usize)?;
+            file.seek(SeekFrom::Start(from + shift))?;
+            file.write_all(&buffer)?;
+            end = from;
+        }
+    } else if new_tail < old_tail {
+        let shift = old_tail - new_tail;
+        let mut from = old_tail;
+
+        while from < length {
+            file.seek(SeekFrom::Start(from))?;
+            let buffer = read_block(file, BUFFER_SIZE.min(length - from) as usize)?;
+            if buffer.is_empty() {
+                break;
+            }
+            file.seek(SeekFrom::Start(from - shift))?;
+            file.write_all(&buffer)?;
+            from += buffer.len() as u64;
+        }
+
+        file.truncate(length - shift)?;
+    }
+
+    file.seek(SeekFrom::Start(start))?;
+    file.write_all(data)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,4 +238,58 @@ mod tests {
         assert_eq!(byte_vec_find(&data, &pattern_1, 0, 2).unwrap(), 6);
         assert!(byte_vec_find(&data, &pattern_1, 0, 4).is_none());
     }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+
+        assert_eq!(base64_decode("Zg==").unwrap(), b"f");
+        assert_eq!(base64_decode("Zm8").unwrap(), b"fo");
+        assert_eq!(base64_decode("Zm9vYmFy").unwrap(), b"foobar");
+        assert!(base64_decode("Zm9v!").is_none());
+        assert!(base64_decode("Z").is_none());
+    }
+
+    #[test]
+    fn test_read_block() {
+        let mut cursor = Cursor::new(b"0123456789".to_vec());
+
+        assert_eq!(read_block(&mut cursor, 4).unwrap(), b"0123");
+        assert_eq!(read_block(&mut cursor, usize::MAX).unwrap(), b"456789");
+    }
+
+    #[test]
+    fn test_insert_block_cursor() {
+        let mut cursor = Cursor::new(b"0123456789".to_vec());
+
+        insert_block(&mut cursor, b"abc", 0, 4).unwrap();
+        assert_eq!(cursor.get_ref(), b"abc456789");
+
+        insert_block(&mut cursor, b"xyz", 9, 0).unwrap();
+        assert_eq!(cursor.get_ref(), b"abc456789xyz");
+    }
+
+    #[test]
+    fn test_insert_block() {
+        let path = std::env::temp_dir().join(format!("rustaglib-{}-insert", std::process::id()));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"0123456789").unwrap();
+
+        insert_block(&mut file, b"abcd", 2, 1).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"01abcd3456789");
+
+        insert_block(&mut file, b"x", 1, 5).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"0x3456789");
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,307 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio_properties::{AudioProperties, ReadStyle},
+    error::{Error, Result},
+    ogg::{self, OggStream},
+    tag::Tag,
+    utils::Truncate,
+    xiph_comment::XiphComment,
+    AudioFile,
+};
+
+// the packet types of the identification and comment headers, followed by
+// "vorbis"
+const IDENTIFICATION_HEADER: &[u8] = b"\x01vorbis";
+const COMMENT_HEADER: &[u8] = b"\x03vorbis";
+
+#[derive(Clone)]
+pub(crate) struct VorbisPropertiesPrivate {
+    length: u32,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u32,
+    vorbis_version: u32,
+    bitrate_maximum: i32,
+    bitrate_nominal: i32,
+    bitrate_minimum: i32,
+}
+
+#[derive(Clone)]
+pub struct VorbisProperties {
+    d: VorbisPropertiesPrivate,
+}
+
+impl AudioProperties for VorbisProperties {
+    fn length(&self) -> u32 {
+        self.length_in_seconds()
+    }
+
+    fn length_in_seconds(&self) -> u32 {
+        self.d.length / 1000
+    }
+
+    fn length_in_milliseconds(&self) -> u32 {
+        self.d.length
+    }
+
+    fn bitrate(&self) -> u32 {
+        self.d.bitrate
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.d.sample_rate
+    }
+
+    fn channels(&self) -> u32 {
+        self.d.channels
+    }
+}
+
+impl VorbisProperties {
+    // Reads the identification header.  The length is computed from the
+    // granule position of the last page, and the average bitrate from the
+    // size of the audio pages.
+    pub(crate) fn new<R: Read + Seek>(file: &mut R, stream: &OggStream) -> Result<Self> {
+        let data = stream.packet(0).ok_or(Error::TruncatedHeader)?;
+        if data.len() < 28 {
+            return Err(Error::TruncatedHeader);
+        }
+        if !data.starts_with(IDENTIFICATION_HEADER) {
+            return Err(Error::InvalidHeader);
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let vorbis_version = u32_at(7);
+        let channels = data[11] as u32;
+        let sample_rate = u32_at(12);
+        let bitrate_maximum = u32_at(16) as i32;
+        let bitrate_nominal = u32_at(20) as i32;
+        let bitrate_minimum = u32_at(24) as i32;
+
+        if sample_rate == 0 || channels == 0 {
+            return Err(Error::InvalidHeader);
+        }
+
+        let granule_position = ogg::last_page_header(file, stream.stream_serial_number())?
+            .map_or(0, |header| header.granule_position().max(0) as u64);
+        // A crafted granule position must not overflow.
+        let length = granule_position
+            .checked_mul(1000)
+            .map_or(0, |ms| ms / sample_rate as u64) as u32;
+
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let stream_length = file_length.saturating_sub(stream.header_end());
+        let bitrate = if length > 0 {
+            (stream_length * 8 / length as u64) as u32
+        } else {
+            bitrate_nominal.max(0) as u32 / 1000
+        };
+
+        Ok(Self {
+            d: VorbisPropertiesPrivate {
+                length,
+                bitrate,
+                sample_rate,
+                channels,
+                vorbis_version,
+                bitrate_maximum,
+                bitrate_nominal,
+                bitrate_minimum,
+            },
+        })
+    }
+
+    pub fn vorbis_version(&self) -> u32 {
+        self.d.vorbis_version
+    }
+
+    // returns the maximum bitrate in b/s, or 0 if it is not set
+    pub fn bitrate_maximum(&self) -> i32 {
+        self.d.bitrate_maximum
+    }
+
+    // returns the nominal bitrate in b/s, or 0 if it is not set
+    pub fn bitrate_nominal(&self) -> i32 {
+        self.d.bitrate_nominal
+    }
+
+    // returns the minimum bitrate in b/s, or 0 if it is not set
+    pub fn bitrate_minimum(&self) -> i32 {
+        self.d.bitrate_minimum
+    }
+}
+
+pub struct VorbisFile {
+    pub tag: XiphComment,
+    pub audio_properties: VorbisProperties,
+    path: Option<PathBuf>,
+    stream: OggStream,
+}
+
+impl AudioFile for VorbisFile {
+    fn open<P: AsRef<Path>>(path: P, style: ReadStyle) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        let mut vorbis_file = Self::read_from(&mut file, style)?;
+        vorbis_file.path = Some(path);
+
+        Ok(vorbis_file)
+    }
+
+    fn tag(&self) -> Box<dyn Tag> {
+        Box::from(self.tag.clone())
+    }
+
+    fn audio_properties(&self) -> Box<dyn AudioProperties> {
+        Box::from(self.audio_properties.clone())
+    }
+}
+
+impl VorbisFile {
+    // Reads the header packets from any seekable stream.  Files read this way
+    // have no path, so they are saved with `save_to`.
+    pub fn read_from<R: Read + Seek>(file: &mut R, _style: ReadStyle) -> Result<Self> {
+        // The identification, comment and setup headers.
+        let stream = OggStream::read(file, 3)?;
+
+        let comment = stream.packet(1).ok_or(Error::NoTagFound)?;
+        if !comment.starts_with(COMMENT_HEADER) {
+            return Err(Error::InvalidHeader);
+        }
+        let tag = XiphComment::parse(&comment[COMMENT_HEADER.len()..])?;
+
+        let audio_properties = VorbisProperties::new(file, &stream)?;
+
+        Ok(Self {
+            tag,
+            audio_properties,
+            path: None,
+            stream,
+        })
+    }
+
+    // Writes the comment header.  The pages after it are only moved if it
+    // needs a different number of pages.
+    pub fn save(&mut self) -> Result<()> {
+        let path = self.path.as_ref().ok_or(Error::NoPath)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        self.save_to(&mut file)
+    }
+
+    // Like `save`, but writes to `file`, which must contain the data this file
+    // was read from.
+    pub fn save_to<F: Read + Write + Seek + Truncate>(&mut self, file: &mut F) -> Result<()> {
+        let mut packet = COMMENT_HEADER.to_vec();
+        packet.extend(self.tag.render(true));
+
+        self.stream.set_packet(1, packet);
+        self.stream.save_to(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::OggPage;
+    use std::io::Cursor;
+
+    // 44100 Hz stereo at a nominal 128 kb/s, followed by ten seconds of audio
+    // pages
+    fn vorbis_file(tag: &XiphComment) -> Vec<u8> {
+        let mut identification = IDENTIFICATION_HEADER.to_vec();
+        identification.extend(0u32.to_le_bytes());
+        identification.push(2);
+        identification.extend(44100u32.to_le_bytes());
+        identification.extend(0u32.to_le_bytes());
+        identification.extend(128_000u32.to_le_bytes());
+        identification.extend(0u32.to_le_bytes());
+        identification.extend([0xb8, 0x01]);
+
+        let mut comment = COMMENT_HEADER.to_vec();
+        comment.extend(tag.render(true));
+        let setup = b"\x05vorbis".to_vec();
+
+        let mut pages = OggPage::paginate(&[identification], 1, 0, true, true, 0);
+        pages.extend(OggPage::paginate(&[comment, setup], 1, 1, false, true, 0));
+        for i in 0..10 {
+            let packets = vec![vec![0; 1000]; 2];
+            pages.extend(OggPage::paginate(
+                &packets,
+                1,
+                i + 2,
+                false,
+                true,
+                44100 * (i as i64 + 1),
+            ));
+        }
+
+        pages.iter_mut().flat_map(|page| page.render()).collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let mut tag = XiphComment::empty();
+        tag.set_vendor("Xiph.Org libVorbis I 20200704");
+        tag.set_title(Some(String::from("Title")));
+        let mut cursor = Cursor::new(vorbis_file(&tag));
+
+        let file = VorbisFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        let properties = &file.audio_properties;
+        assert_eq!(properties.length_in_milliseconds(), 10_000);
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.bitrate_nominal(), 128_000);
+        assert_eq!(properties.bitrate(), 16);
+        assert_eq!(properties.vorbis_version(), 0);
+
+        assert_eq!(file.tag.vendor(), "Xiph.Org libVorbis I 20200704");
+        assert_eq!(file.tag.title().as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn test_huge_granule_position() {
+        let mut data = vorbis_file(&XiphComment::empty());
+        let mut pages = OggPage::paginate(&[vec![0; 10]], 1, 12, false, true, i64::MAX);
+        data.extend(pages.iter_mut().flat_map(|page| page.render()));
+
+        let file = VorbisFile::read_from(&mut Cursor::new(data), ReadStyle::Average).unwrap();
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 0);
+        assert_eq!(file.audio_properties.bitrate(), 128);
+    }
+
+    #[test]
+    fn test_save() {
+        let mut cursor = Cursor::new(vorbis_file(&XiphComment::empty()));
+
+        let mut file = VorbisFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        file.tag.set_artist(Some(String::from("Artist")));
+        file.tag
+            .set_field("LYRICS", vec![String::from("x").repeat(70_000)]);
+        file.save_to(&mut cursor).unwrap();
+
+        let file = VorbisFile::read_from(&mut cursor, ReadStyle::Average).unwrap();
+        assert_eq!(file.tag.artist().as_deref(), Some("Artist"));
+        assert_eq!(file.tag.field("lyrics").unwrap()[0].len(), 70_000);
+        assert_eq!(file.audio_properties.length_in_milliseconds(), 10_000);
+
+        // the audio pages were moved and renumbered
+        let mut offset = file.stream.header_end();
+        let mut sequence = None;
+        while offset < cursor.get_ref().len() as u64 {
+            let page = OggPage::read(&mut cursor, offset).unwrap();
+            let number = page.header().page_sequence_number();
+            assert_eq!(sequence.map_or(number, |s: u32| s + 1), number);
+            sequence = Some(number);
+            offset += page.size() as u64;
+        }
+        assert_eq!(sequence, Some(12));
+    }
+}
@@ -0,0 +1,452 @@
+use crate::{
+    error::Result,
+    flac::FlacPicture,
+    tag::{PropertyMap, Tag},
+    utils::{base64_decode, base64_encode, take},
+};
+
+// the field holding base64 encoded FLAC picture blocks
+const PICTURE_FIELD: &str = "METADATA_BLOCK_PICTURE";
+
+#[derive(Clone, Default)]
+pub(crate) struct XiphCommentPrivate {
+    vendor: String,
+    // field names are kept in upper case, in the order they were added
+    fields: Vec<(String, Vec<String>)>,
+    pictures: Vec<FlacPicture>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    comment: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+    track: Option<u32>,
+}
+
+// A Vorbis comment, the list of "NAME=value" fields used as tag by FLAC, Ogg
+// Vorbis, Opus and Speex.  Field names are case-insensitive and each may hold
+// several values.
+#[derive(Clone, Default)]
+pub struct XiphComment {
+    d: XiphCommentPrivate,
+}
+
+impl XiphComment {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    // Parses a comment.  A framing bit after the fields, as used by Ogg
+    // Vorbis, is ignored.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let le_u32 = |pos: &mut usize| -> Result<usize> {
+            let value = take(data, pos, 4)?;
+            Ok(u32::from_le_bytes(value.try_into().unwrap()) as usize)
+        };
+
+        let mut comment = Self::empty();
+
+        let vendor_length = le_u32(&mut pos)?;
+        comment.d.vendor =
+            String::from_utf8_lossy(take(data, &mut pos, vendor_length)?).into_owned();
+
+        let count = le_u32(&mut pos)?;
+        for _ in 0..count {
+            let length = le_u32(&mut pos)?;
+            let field = String::from_utf8_lossy(take(data, &mut pos, length)?).into_owned();
+
+            // Fields without a separator are invalid and dropped.
+            let Some((name, value)) = field.split_once('=') else {
+                continue;
+            };
+
+            // Pictures which cannot be decoded are kept as ordinary fields, so
+            // that they are written back unchanged.
+            let picture = Some(value)
+                .filter(|_| name.eq_ignore_ascii_case(PICTURE_FIELD))
+                .and_then(base64_decode)
+                .and_then(|d| FlacPicture::parse(&d).ok());
+            match picture {
+                Some(picture) => comment.d.pictures.push(picture),
+                None => comment.push_field(name, value),
+            }
+        }
+
+        Ok(comment)
+    }
+
+    // Renders the comment, followed by the framing bit if `framing_bit` is
+    // set.  Ogg Vorbis requires the bit, FLAC and Opus do not.
+    pub fn render(&self, framing_bit: bool) -> Vec<u8> {
+        let mut data = (self.d.vendor.len() as u32).to_le_bytes().to_vec();
+        data.extend(self.d.vendor.as_bytes());
+
+        let fields = self.d.fields.iter().flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |value| format!("{}={}", name, value))
+        });
+        let pictures = self
+            .d
+            .pictures
+            .iter()
+            .map(|picture| format!("{}={}", PICTURE_FIELD, base64_encode(&picture.render())));
+        let fields: Vec<String> = fields.chain(pictures).collect();
+
+        data.extend((fields.len() as u32).to_le_bytes());
+        for field in fields {
+            data.extend((field.len() as u32).to_le_bytes());
+            data.extend(field.as_bytes());
+        }
+
+        if framing_bit {
+            data.push(1);
+        }
+
+        data
+    }
+
+    // returns the name of the encoder
+    pub fn vendor(&self) -> &str {
+        &self.d.vendor
+    }
+
+    pub fn set_vendor(&mut self, vendor: &str) {
+        self.d.vendor = String::from(vendor);
+    }
+
+    // returns the values of the field `name`, ignoring case
+    pub fn field(&self, name: &str) -> Option<&[String]> {
+        let name = name.to_uppercase();
+
+        self.d
+            .fields
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, values)| &values[..])
+    }
+
+    // returns true if the comment has the field `name`, ignoring case
+    pub fn contains(&self, name: &str) -> bool {
+        self.field(name).is_some()
+    }
+
+    // Adds a value to the field `name`.  Returns false, and leaves the
+    // comment unchanged, if the name is invalid.
+    pub fn add_field(&mut self, name: &str, value: &str) -> bool {
+        if !is_valid_name(name) {
+            return false;
+        }
+
+        self.push_field(name, value);
+
+        true
+    }
+
+    // adds a value to the field `name` without checking the name
+    fn push_field(&mut self, name: &str, value: &str) {
+        let name = name.to_uppercase();
+        match self.d.fields.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => values.push(String::from(value)),
+            None => self.d.fields.push((name, vec![String::from(value)])),
+        }
+
+        self.update_fields();
+    }
+
+    // Replaces the values of the field `name`, removing it if there are none.
+    // Returns false, and leaves the comment unchanged, if the name is invalid.
+    pub fn set_field(&mut self, name: &str, values: Vec<String>) -> bool {
+        if !is_valid_name(name) {
+            return false;
+        }
+
+        let name = name.to_uppercase();
+        if values.is_empty() {
+            self.d.fields.retain(|(n, _)| *n != name);
+        } else {
+            match self.d.fields.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, v)) => *v = values,
+                None => self.d.fields.push((name, values)),
+            }
+        }
+
+        self.update_fields();
+
+        true
+    }
+
+    // removes the field `name`, ignoring case
+    pub fn remove_field(&mut self, name: &str) {
+        self.set_field(name, vec![]);
+    }
+
+    // returns the pictures stored in METADATA_BLOCK_PICTURE fields
+    pub fn pictures(&self) -> &[FlacPicture] {
+        &self.d.pictures
+    }
+
+    pub fn add_picture(&mut self, picture: FlacPicture) {
+        self.d.pictures.push(picture);
+    }
+
+    // removes the pictures, including picture fields which cannot be decoded
+    pub fn remove_pictures(&mut self) {
+        self.d.pictures.clear();
+        self.d.fields.retain(|(name, _)| name != PICTURE_FIELD);
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.field(name)
+            .and_then(|values| values.first())
+            .filter(|value| !value.is_empty())
+            .cloned()
+    }
+
+    fn set_text(&mut self, name: &str, value: Option<String>) {
+        self.set_field(name, value.into_iter().filter(|v| !v.is_empty()).collect());
+    }
+
+    // returns the name of the field holding the comment
+    fn comment_field(&self) -> &'static str {
+        if self.contains("DESCRIPTION") {
+            "DESCRIPTION"
+        } else {
+            "COMMENT"
+        }
+    }
+
+    fn update_fields(&mut self) {
+        let number = |s: Option<String>| {
+            let s = s?;
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            s[..end].parse::<u32>().ok().filter(|&n| n > 0)
+        };
+
+        self.d.title = self.text("TITLE");
+        self.d.artist = self.text("ARTIST");
+        self.d.album = self.text("ALBUM");
+        self.d.comment = self.text(self.comment_field());
+        self.d.genre = self.text("GENRE");
+        self.d.year = number(self.text("DATE"));
+        self.d.track = number(self.text("TRACKNUMBER"));
+    }
+}
+
+impl Tag for XiphComment {
+    // Every field is a property of the same name.  Pictures are not included.
+    fn properties(&self) -> PropertyMap {
+        self.d
+            .fields
+            .iter()
+            .filter(|(name, _)| name != PICTURE_FIELD)
+            .cloned()
+            .collect()
+    }
+
+    fn remove_unsupported_properties(&mut self, properties: Vec<String>) {
+        for name in properties {
+            self.remove_field(&name);
+        }
+    }
+
+    // Only properties with invalid field names are unsupported.
+    fn set_properties(&mut self, properties: PropertyMap) -> PropertyMap {
+        let mut unsupported = PropertyMap::new();
+
+        self.d
+            .fields
+            .retain(|(name, _)| name == PICTURE_FIELD || properties.contains_key(name));
+        self.update_fields();
+
+        let mut names: Vec<&String> = properties.keys().collect();
+        names.sort();
+        for name in names {
+            let values = properties[name].clone();
+            if !self.set_field(name, values.clone()) {
+                unsupported.insert(name.clone(), values);
+            }
+        }
+
+        unsupported
+    }
+
+    fn title(&self) -> &Option<String> {
+        &self.d.title
+    }
+
+    fn artist(&self) -> &Option<String> {
+        &self.d.artist
+    }
+
+    fn album(&self) -> &Option<String> {
+        &self.d.album
+    }
+
+    fn comment(&self) -> &Option<String> {
+        &self.d.comment
+    }
+
+    fn genre(&self) -> &Option<String> {
+        &self.d.genre
+    }
+
+    fn year(&self) -> &Option<u32> {
+        &self.d.year
+    }
+
+    fn track(&self) -> &Option<u32> {
+        &self.d.track
+    }
+
+    fn set_title(&mut self, title: Option<String>) {
+        self.set_text("TITLE", title);
+    }
+
+    fn set_artist(&mut self, artist: Option<String>) {
+        self.set_text("ARTIST", artist);
+    }
+
+    fn set_album(&mut self, album: Option<String>) {
+        self.set_text("ALBUM", album);
+    }
+
+    fn set_comment(&mut self, comment: Option<String>) {
+        self.set_text(self.comment_field(), comment);
+    }
+
+    fn set_genre(&mut self, genre: Option<String>) {
+        self.set_text("GENRE", genre);
+    }
+
+    fn set_year(&mut self, year: Option<u32>) {
+        self.set_text("DATE", year.map(|year| year.to_string()));
+    }
+
+    fn set_track(&mut self, track: Option<u32>) {
+        self.set_text("TRACKNUMBER", track.map(|track| track.to_string()));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.d.fields.is_empty() && self.d.pictures.is_empty()
+    }
+}
+
+// Field names are non-empty printable ASCII without '='.  The picture field is
+// reserved for the pictures.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| (0x20..=0x7d).contains(&b) && b != b'=')
+        && !name.eq_ignore_ascii_case(PICTURE_FIELD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn picture() -> FlacPicture {
+        FlacPicture {
+            picture_type: 3,
+            mime_type: String::from("image/jpeg"),
+            description: String::new(),
+            width: 2,
+            height: 2,
+            color_depth: 24,
+            num_colors: 0,
+            data: vec![0xff, 0xd8, 0xff, 0xe0],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut comment = XiphComment::empty();
+        comment.set_vendor("Xiph.Org libVorbis I 20200704");
+        comment.add_field("Title", "Title");
+        comment.add_field("ARTIST", "One");
+        comment.add_field("artist", "Two");
+        comment.add_picture(picture());
+        assert!(!comment.add_field("A=B", "value"));
+        assert!(!comment.add_field("", "value"));
+
+        let data = comment.render(true);
+        assert_eq!(data.last(), Some(&1));
+
+        let comment = XiphComment::parse(&data).unwrap();
+        assert_eq!(comment.vendor(), "Xiph.Org libVorbis I 20200704");
+        assert_eq!(comment.title().as_deref(), Some("Title"));
+        assert_eq!(comment.field("Artist").unwrap(), ["One", "Two"]);
+        assert_eq!(comment.pictures(), [picture()]);
+
+        let properties = comment.properties();
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties["ARTIST"], ["One", "Two"]);
+        assert_eq!(comment.render(true), data);
+
+        assert!(matches!(
+            XiphComment::parse(&data[..10]),
+            Err(Error::TruncatedTag)
+        ));
+    }
+
+    #[test]
+    fn test_undecodable_picture() {
+        let field = b"METADATA_BLOCK_PICTURE=not base64!";
+        let mut data = 0u32.to_le_bytes().to_vec();
+        data.extend(1u32.to_le_bytes());
+        data.extend((field.len() as u32).to_le_bytes());
+        data.extend(field);
+
+        let mut comment = XiphComment::parse(&data).unwrap();
+        assert!(comment.pictures().is_empty());
+        assert!(comment.properties().is_empty());
+        assert_eq!(comment.render(false), data);
+
+        comment.set_properties(PropertyMap::new());
+        assert_eq!(comment.render(false), data);
+
+        comment.remove_pictures();
+        assert!(!comment.contains(PICTURE_FIELD));
+    }
+
+    #[test]
+    fn test_set_properties() {
+        let mut comment = XiphComment::empty();
+        comment.set_comment(Some(String::from("Comment")));
+        comment.set_year(Some(2001));
+        comment.add_picture(picture());
+
+        let mut properties = comment.properties();
+        assert_eq!(properties["COMMENT"], ["Comment"]);
+        assert_eq!(properties["DATE"], ["2001"]);
+
+        properties.remove("DATE");
+        properties.insert(
+            String::from("PERFORMER"),
+            vec![String::from("One"), String::from("Two")],
+        );
+        properties.insert(String::from("A=B"), vec![String::from("value")]);
+        let unsupported = comment.set_properties(properties);
+
+        assert_eq!(unsupported.len(), 1);
+        assert!(unsupported.contains_key("A=B"));
+        assert!(comment.year().is_none());
+        assert_eq!(comment.field("performer").unwrap(), ["One", "Two"]);
+        assert_eq!(comment.pictures().len(), 1);
+
+        // an existing DESCRIPTION field holds the comment
+        comment.add_field("DESCRIPTION", "Description");
+        assert_eq!(comment.comment().as_deref(), Some("Description"));
+        comment.set_comment(None);
+        assert!(!comment.contains("description"));
+        assert_eq!(comment.field("COMMENT").unwrap(), ["Comment"]);
+
+        comment.set_title(Some(String::from("Title")));
+        assert!(comment.set_properties(PropertyMap::new()).is_empty());
+        assert!(comment.title().is_none());
+        assert!(comment.comment().is_none());
+    }
+}